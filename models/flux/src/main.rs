@@ -1,32 +1,59 @@
 use clap::*;
-use ipc_channel::ipc::{IpcReceiver, IpcSender};
 use pyo3::prelude::*;
-use moonweb::ipc::accept;
+use moonweb::data::Usage;
+use moonweb::ipc::{OutputStream, WorkerChannel};
 
 
+/// The channel to the master, replies go to the request received last.
 #[pyclass]
 struct IpcChannel {
-    sender: IpcSender<String>,
-    receiver: IpcReceiver<String>,
+    channel: WorkerChannel,
 }
+
+fn runtime_error(e: impl std::fmt::Display) -> PyErr {
+    pyo3::exceptions::PyRuntimeError::new_err(e.to_string())
+}
+
 #[pymethods]
 impl IpcChannel {
     #[new]
     fn new(ipc_name: String) -> Self {
-        let (receiver, sender) = accept(ipc_name);
-        IpcChannel { sender, receiver }
+        IpcChannel {
+            channel: WorkerChannel::accept(ipc_name),
+        }
     }
 
-    fn send(&self, msg: &str) -> PyResult<()> {
-        self.sender
-            .send(msg.to_string())
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    /// Tells the master the model is loaded.
+    fn ready(&self, model_id: &str) -> PyResult<()> {
+        self.channel.ready(model_id).map_err(runtime_error)
     }
 
+    /// The next request, as JSON.
     fn recv(&self) -> PyResult<String> {
-        self.receiver
-            .recv()
-            .map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+        let request = self.channel.recv().map_err(runtime_error)?;
+        Ok(serde_json::json!(request).to_string())
+    }
+
+    fn send(&self, text: &str) -> PyResult<()> {
+        self.channel.write(text.to_string()).map_err(runtime_error)
+    }
+
+    fn send_artifact(&self, content_type: &str, alt: &str, data: &[u8]) -> PyResult<()> {
+        self.channel.artifact(content_type, alt, data).map_err(runtime_error)
+    }
+
+    /// `usage` is the JSON of a `Usage`.
+    fn send_usage(&self, usage: &str) -> PyResult<()> {
+        let usage: Usage = serde_json::from_str(usage).map_err(runtime_error)?;
+        self.channel.usage(&usage).map_err(runtime_error)
+    }
+
+    fn send_error(&self, message: &str) -> PyResult<()> {
+        self.channel.error(message.to_string()).map_err(runtime_error)
+    }
+
+    fn end(&self) -> PyResult<()> {
+        self.channel.end().map_err(runtime_error)
     }
 }
 
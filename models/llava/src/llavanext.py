@@ -10,6 +10,7 @@ import requests
 import copy
 import torch
 import json
+import time
 from transformers import TextStreamer,AutoTokenizer
 from moonipc import IpcChannel;
 
@@ -20,11 +21,52 @@ class IpcStreamer(TextStreamer):
     ):
         super().__init__(tokenizer, skip_prompt, **decode_kwargs)
         self.ipc = ipc
+        self.reset()
+
+    def reset(self):
+        self.start = time.time()
+        self.first_token = None
+        self.completion_tokens = 0
+
+    def put(self, value):
+        if not (self.skip_prompt and self.next_tokens_are_prompt):
+            if self.first_token is None:
+                self.first_token = time.time()
+            self.completion_tokens += value.numel()
+        super().put(value)
 
     def on_finalized_text(self, text: str, stream_end: bool = False):
         self.ipc.send(text)
-        if stream_end:
-           self.ipc.send("<|endoftext|>")
+
+    def send_usage(self, prompt_tokens, max_new_tokens):
+        first_token = self.first_token or self.start
+        decode_time = time.time() - first_token
+        usage = {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": self.completion_tokens,
+            "time_to_first_token": (first_token - self.start) * 1000,
+            "tokens_per_second": (self.completion_tokens - 1) / decode_time if self.completion_tokens > 1 and decode_time > 0 else 0,
+            "finish_reason": "length" if self.completion_tokens >= max_new_tokens else "eos",
+        }
+        self.ipc.send_usage(json.dumps(usage))
+        self.ipc.end()
+
+def sampling_kwargs(request, **defaults):
+    kwargs = dict(defaults)
+    if request.get('max_tokens') is not None:
+        kwargs['max_new_tokens'] = request['max_tokens']
+    if request.get('temperature') is not None:
+        kwargs['temperature'] = request['temperature']
+        kwargs['do_sample'] = request['temperature'] > 0
+    if request.get('top_p') is not None:
+        kwargs['top_p'] = request['top_p']
+    if request.get('top_k') is not None:
+        kwargs['top_k'] = request['top_k']
+    if request.get('repeat_penalty') is not None:
+        kwargs['repetition_penalty'] = request['repeat_penalty']
+    if request.get('seed') is not None:
+        torch.manual_seed(request['seed'])
+    return kwargs
 
 def run(ipc_name,model_id = "lmms-lab/llama3-llava-next-8b"):
 
@@ -38,6 +80,7 @@ def run(ipc_name,model_id = "lmms-lab/llama3-llava-next-8b"):
 
     conv = copy.deepcopy(conv_templates[conv_template])
     
+    ipc.ready(model_id)
     print(f"{model_id} server start!")
     while True:
         request = json.loads(ipc.recv())
@@ -70,20 +113,20 @@ def run(ipc_name,model_id = "lmms-lab/llama3-llava-next-8b"):
         conv.append_message(conv.roles[1], None)
         prompt_question = conv.get_prompt()
         print(prompt_question)
+        streamer.reset()
         input_ids = tokenizer_image_token(prompt_question, tokenizer, IMAGE_TOKEN_INDEX, return_tensors="pt").unsqueeze(0).to(device)
         
         image_tensor = process_images(image_list, image_processor, model.config)
         image_tensor = [_image.to(dtype=torch.float16, device=device) for _image in image_tensor]
         image_sizes = [image.size for image in image_list]
 
+        kwargs = sampling_kwargs(request, do_sample=True, top_p=0.95, temperature=0.5, max_new_tokens=256)
         cont = model.generate(
             input_ids,
             images=image_tensor,
             image_sizes=image_sizes,
-            do_sample=True,
-            top_p=0.95,
-            temperature=0.5,
             pad_token_id=tokenizer.eos_token_id,
-            max_new_tokens=256,
             streamer = streamer,
+            **kwargs,
         )
+        streamer.send_usage(input_ids.shape[1], kwargs['max_new_tokens'])
@@ -1,5 +1,4 @@
-use moonweb::ipc::{accept,OutputStream};
-use moonweb::data::Request;
+use moonweb::ipc::{OutputStream, WorkerChannel};
 use clap::*;
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -25,20 +24,19 @@ fn main() {
     let args = Args::parse();
     let ipc_name = args.ipc_name.unwrap();
     let model_id = args.model_id.unwrap();
-    let (receiver,sender) = accept(ipc_name);
+    let channel = WorkerChannel::accept(ipc_name);
+    channel.ready(&model_id).unwrap();
     println!("{} server start!",model_id);
     loop {
-        let msg = receiver.recv().unwrap();
-            if let Ok(req) = serde_json::from_str::<Request>(msg.as_str()) {
-                if req.cmd.eq("QUIT") {
-                    break;
-                }
-            let response = format!("{} recv {:?}",model_id,req.msg_list);
-            for char in response.chars() {
-                sender.write(format!("{}",char)).unwrap();
-            }
-            sender.end().unwrap();
+        let req = channel.recv().unwrap();
+        if req.cmd.eq("QUIT") {
+            break;
         }
+        let response = format!("{} recv {:?}",model_id,req.msg_list);
+        for char in response.chars() {
+            channel.write(format!("{}",char)).unwrap();
+        }
+        channel.end().unwrap();
     }
     
 }
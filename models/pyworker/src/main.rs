@@ -1,40 +1,54 @@
-
 use pyo3::prelude::*;
 use clap::*;
+use moonweb::data::Usage;
+use moonweb::ipc::{OutputStream, WorkerChannel};
 
-use ipc_channel::ipc::{self,IpcSender, IpcReceiver};
-
-fn accept(ipc_name: String) -> (IpcReceiver<String>, IpcSender<String>) {
-    let (client_sender, receiver): (IpcSender<String>, IpcReceiver<String>) = ipc::channel().unwrap();
-    let connector = IpcSender::connect(ipc_name.clone()).expect(format!("Failed to connect {}",ipc_name).as_str());
-    connector.send(client_sender).expect("Failed to send client sender");
-    let (sender, client_receiver): (IpcSender<String>, IpcReceiver<String>) = ipc::channel().unwrap();
-    let client_name = receiver.recv().expect("Failed to recv!");
-    let connector = IpcSender::connect(client_name.clone()).expect(format!("Failed to connect client: {}",client_name).as_str());
-    connector.send(client_receiver).expect("Failed to send client receive");
-    (receiver, sender)
-}
-
+/// The channel to the master, replies go to the request received last.
 #[pyclass]
 struct IpcChannel {
-    sender: IpcSender<String>,
-    receiver: IpcReceiver<String>,
+    channel: WorkerChannel,
+}
+
+fn runtime_error(e: impl std::fmt::Display) -> PyErr {
+    pyo3::exceptions::PyRuntimeError::new_err(e.to_string())
 }
 
 #[pymethods]
 impl IpcChannel {
     #[new]
     fn new(ipc_name: String) -> Self {
-        let (receiver, sender) = accept(ipc_name);
-        IpcChannel { sender, receiver }
+        IpcChannel {
+            channel: WorkerChannel::accept(ipc_name),
+        }
     }
 
-    fn send(&self, msg: &str) -> PyResult<()> {
-        self.sender.send(msg.to_string()).map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+    /// Tells the master the model is loaded.
+    fn ready(&self, model_id: &str) -> PyResult<()> {
+        self.channel.ready(model_id).map_err(runtime_error)
     }
 
+    /// The next request, as JSON.
     fn recv(&self) -> PyResult<String> {
-        self.receiver.recv().map_err(|e| pyo3::exceptions::PyRuntimeError::new_err(e.to_string()))
+        let request = self.channel.recv().map_err(runtime_error)?;
+        Ok(serde_json::json!(request).to_string())
+    }
+
+    fn send(&self, text: &str) -> PyResult<()> {
+        self.channel.write(text.to_string()).map_err(runtime_error)
+    }
+
+    /// `usage` is the JSON of a `Usage`.
+    fn send_usage(&self, usage: &str) -> PyResult<()> {
+        let usage: Usage = serde_json::from_str(usage).map_err(runtime_error)?;
+        self.channel.usage(&usage).map_err(runtime_error)
+    }
+
+    fn send_error(&self, message: &str) -> PyResult<()> {
+        self.channel.error(message.to_string()).map_err(runtime_error)
+    }
+
+    fn end(&self) -> PyResult<()> {
+        self.channel.end().map_err(runtime_error)
     }
 }
 
@@ -62,6 +76,8 @@ struct Args {
     #[clap(short='t', long)]
     top_p: Option<f64>,
 
+    #[clap(long)]
+    chat_template: Option<String>,
 }
 
 fn main() {
@@ -72,7 +88,7 @@ fn main() {
     pyo3::append_to_inittab!(moonipc);
     pyo3::prepare_freethreaded_python();
     
-    let args=(ipc_name.as_str(),model_id.as_str());
+    let args=(ipc_name.as_str(),model_id.as_str(),args.chat_template);
     Python::with_gil(|py| {
         let activators = PyModule::from_code_bound(py,code,"qwen.py","qwen").unwrap();
         activators.getattr("run").unwrap().call1(args).unwrap();
@@ -3,6 +3,7 @@ from transformers import TextStreamer
 from transformers import AutoModelForCausalLM, AutoTokenizer
 import json
 import torch
+import time
 
 class IpcStreamer(TextStreamer):
     def __init__(
@@ -10,13 +11,86 @@ class IpcStreamer(TextStreamer):
     ):
         super().__init__(tokenizer, skip_prompt, **decode_kwargs)
         self.ipc = ipc
+        self.reset()
+
+    def reset(self):
+        self.start = time.time()
+        self.first_token = None
+        self.completion_tokens = 0
+
+    def put(self, value):
+        if not (self.skip_prompt and self.next_tokens_are_prompt):
+            if self.first_token is None:
+                self.first_token = time.time()
+            self.completion_tokens += value.numel()
+        super().put(value)
 
     def on_finalized_text(self, text: str, stream_end: bool = False):
         self.ipc.send(text)
-        if stream_end:
-           self.ipc.send("<|endoftext|>");
 
-def run(ipc_name,model_id):
+    def send_usage(self, prompt_tokens, max_new_tokens):
+        first_token = self.first_token or self.start
+        decode_time = time.time() - first_token
+        usage = {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": self.completion_tokens,
+            "time_to_first_token": (first_token - self.start) * 1000,
+            "tokens_per_second": (self.completion_tokens - 1) / decode_time if self.completion_tokens > 1 and decode_time > 0 else 0,
+            "finish_reason": "length" if self.completion_tokens >= max_new_tokens else "eos",
+        }
+        self.ipc.send_usage(json.dumps(usage))
+        self.ipc.end()
+
+def sampling_kwargs(request, **defaults):
+    kwargs = dict(defaults)
+    if request.get('max_tokens') is not None:
+        kwargs['max_new_tokens'] = request['max_tokens']
+    if request.get('temperature') is not None:
+        kwargs['temperature'] = request['temperature']
+        kwargs['do_sample'] = request['temperature'] > 0
+    if request.get('top_p') is not None:
+        kwargs['top_p'] = request['top_p']
+    if request.get('top_k') is not None:
+        kwargs['top_k'] = request['top_k']
+    if request.get('repeat_penalty') is not None:
+        kwargs['repetition_penalty'] = request['repeat_penalty']
+    if request.get('seed') is not None:
+        torch.manual_seed(request['seed'])
+    return kwargs
+
+def chat_template(tokenizer, system_prompt, msg_list):
+    messages = [{"role": "system", "content": system_prompt}]
+    for msg in msg_list :
+        if msg['role']=='User':
+           messages.append({"role":"user","content":msg['content']})
+        else:
+           messages.append({"role":"assistant","content":msg['content']})
+    return tokenizer.apply_chat_template(
+            messages,
+            tokenize=False,
+            add_generation_prompt=True
+    )
+
+# same policies as moonweb::model::fit_context.
+def fit_context(tokenizer, request, max_context, max_new_tokens):
+    msg_list = [msg for msg in request['msg_list'] if msg['role'] != 'Administrator']
+    truncation = request.get('truncation') or {"policy": "drop-oldest"}
+    budget = max_context - min(max_new_tokens, max_context // 2)
+    start = 0
+    if truncation['policy'] == 'keep-last':
+        start = max(len(msg_list) - truncation['n'], 0)
+    while True:
+        text = chat_template(tokenizer, request['system_prompt'], msg_list[start:])
+        tokens = len(tokenizer(text).input_ids)
+        if tokens <= budget:
+            return text
+        if truncation['policy'] != 'drop-oldest' or start + 1 >= len(msg_list):
+            raise ValueError(f"The conversation has {tokens} tokens, but only {budget} of the {max_context} token context are left for it.")
+        start += 1
+        while start + 1 < len(msg_list) and msg_list[start]['role'] != 'User':
+            start += 1
+
+def run(ipc_name,model_id,chat_template_file=None):
    
     ipc = IpcChannel(ipc_name);
     
@@ -28,31 +102,32 @@ def run(ipc_name,model_id):
           attn_implementation="flash_attention_2",
     )
     tokenizer = AutoTokenizer.from_pretrained(model_id)
+    if chat_template_file is not None:
+        with open(chat_template_file) as f:
+            tokenizer.chat_template = f.read()
     streamer = IpcStreamer(tokenizer, skip_prompt=True, skip_special_tokens=True,ipc=ipc)
+    ipc.ready(model_id)
     print(f"{model_id} server start!")
     while True:
         request = json.loads(ipc.recv())
         
         if request['cmd'] == "QUIT":
             break
-        messages = [{"role": "system", "content": request['system_prompt']}]
-        for msg in request['msg_list'] :
-            if msg['role']=='User':
-               messages.append({"role":"user","content":msg['content']})
-            else:
-               messages.append({"role":"assistant","content":msg['content']})
-        text = tokenizer.apply_chat_template(
-                messages,
-                tokenize=False,
-                add_generation_prompt=True
-        )
+        kwargs = sampling_kwargs(request, max_new_tokens=512)
+        try:
+            text = fit_context(tokenizer, request, model.config.max_position_embeddings, kwargs['max_new_tokens'])
+        except ValueError as e:
+            ipc.send_error(f"Failed to generate: {e}")
+            ipc.end()
+            continue
         model_inputs = tokenizer([text], return_tensors="pt").to(device)
         
         print("model.generate!!")
+        streamer.reset()
         model.generate(
             model_inputs.input_ids,
-            
-            max_new_tokens=512,
             streamer=streamer,
+            **kwargs,
         )
+        streamer.send_usage(model_inputs.input_ids.shape[1], kwargs['max_new_tokens'])
             
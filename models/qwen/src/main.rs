@@ -1,114 +1,40 @@
 use anyhow::{Error as E, Result};
-use candle_core::utils::cuda_is_available;
 use clap::Parser;
 
-use candle_transformers::models::qwen2::{Config as ConfigBase, ModelForCausalLM as ModelBase};
+use candle_transformers::models::qwen2::Config as ConfigBase;
 
-use candle_core::{DType, Device, Tensor};
-use moonweb::token_output_stream::TokenOutputStream;
 use candle_nn::VarBuilder;
-use candle_transformers::generation::LogitsProcessor;
 use hf_hub::{api::sync::Api, Repo, RepoType};
 use tokenizers::Tokenizer;
-use moonweb::ipc::{accept,OutputStream};
-use moonweb::data::{Request,Message,Role};
+use moonweb::ipc::{OutputStream, WorkerChannel};
+use moonweb::data::{Message,Role,SamplingParams,Tool};
+use moonweb::chat_template::ChatTemplate;
+use moonweb::generation::Generator;
+use moonweb::model::{fit_context, Sequence, TextGenModel};
+use moonweb::registry::{select_device, LoadOptions};
+use moonweb::transformer::{Transformer, TransformerDecoder};
+use moonweb::tools::{ToolCallOutput, ToolCallParser};
+use std::cell::RefCell;
 
 struct TextGeneration {
-    model: ModelBase,
-    device: Device,
-    tokenizer: TokenOutputStream,
-    logits_processor: LogitsProcessor,
-    repeat_penalty: f32,
-    repeat_last_n: usize,
+    generator: Generator<TransformerDecoder>,
 }
 
-impl TextGeneration {
-    #[allow(clippy::too_many_arguments)]
-    fn new(
-        model: ModelBase,
-        tokenizer: Tokenizer,
-        seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
-        repeat_penalty: f32,
-        repeat_last_n: usize,
-        device: &Device,
-    ) -> Self {
-        let logits_processor = LogitsProcessor::new(seed, temp, top_p);
-        Self {
-            model,
-            tokenizer: TokenOutputStream::new(tokenizer),
-            logits_processor,
-            repeat_penalty,
-            repeat_last_n,
-            device: device.clone(),
-        }
+impl TextGenModel for TextGeneration {
+    fn start(&mut self, prompt: &str, params: &SamplingParams, session_id: Option<&str>) -> Result<Box<dyn Sequence>> {
+        self.generator.start(prompt, params, session_id)
     }
 
-    fn run(&mut self,output: &impl OutputStream,prompt: &str, sample_len: usize) -> Result<()> {
-        self.model.clear_kv_cache();
-        self.tokenizer.clear();
-        let mut tokens = self
-            .tokenizer
-            .tokenizer()
-            .encode(prompt, true)
-            .map_err(E::msg)?
-            .get_ids()
-            .to_vec();
-        for &t in tokens.iter() {
-            if let Some(t) = self.tokenizer.next_token(t)? {
-                print!("{t}")
-            }
-        }
-        
+    fn messages_chat_template(&self, msg_list: &[Message], system_prompt: &str, tools: &[Tool]) -> Result<String> {
+        self.generator.apply_chat_template(msg_list, system_prompt, tools, messages_chat_template)
+    }
 
-        let mut generated_tokens = 0usize;
-        let eos_token = match self.tokenizer.get_token("<|endoftext|>") {
-            Some(token) => token,
-            None => anyhow::bail!("cannot find the <|endoftext|> token"),
-        };
-        let start_gen = std::time::Instant::now();
-        for index in 0..sample_len {
-            let context_size = if index > 0 { 1 } else { tokens.len() };
-            let start_pos = tokens.len().saturating_sub(context_size);
-            let ctxt = &tokens[start_pos..];
-            let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-            let logits = self.model.forward(&input, start_pos)?;
-            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-            let logits = if self.repeat_penalty == 1. {
-                logits
-            } else {
-                let start_at = tokens.len().saturating_sub(self.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    self.repeat_penalty,
-                    &tokens[start_at..],
-                )?
-            };
-
-            let next_token = self.logits_processor.sample(&logits)?;
-            tokens.push(next_token);
-            generated_tokens += 1;
-            if next_token == eos_token {
-                break;
-            }
-            if let Some(t) = self.tokenizer.next_token(next_token)? {
-                //print!("{t}");
-                //std::io::stdout().flush()?;
-                output.write(format!("{t}")).unwrap();
-            }
-        }
-        output.end().unwrap();
-        let dt = start_gen.elapsed();
-        if let Some(rest) = self.tokenizer.decode_rest().map_err(E::msg)? {
-            print!("{rest}");
-        }
-        
-        println!(
-            "\n{generated_tokens} tokens generated ({:.2} token/s)",
-            generated_tokens as f64 / dt.as_secs_f64(),
-        );
-        Ok(())
+    fn max_context(&self) -> usize {
+        self.generator.max_context()
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        self.generator.count_tokens(text)
     }
 }
 
@@ -157,11 +83,31 @@ struct Args {
     /// The context size to consider for the repeat penalty.
     #[arg(long, default_value_t = 64)]
     repeat_last_n: usize,
+
+    /// A Jinja file used instead of the chat template in tokenizer_config.json.
+    #[arg(long)]
+    chat_template: Option<String>,
+
+    /// sampling defaults as JSON, e.g. `{"top_k":40,"repeat_penalty":1.1}`.
+    #[arg(long)]
+    sampling: Option<String>,
+
+    /// cpu, cuda:N or metal.
+    #[arg(long)]
+    device: Option<String>,
+
+    /// f32, f16 or bf16.
+    #[arg(long)]
+    dtype: Option<String>,
+
+    /// Threads to compute on the cpu with.
+    #[arg(long)]
+    threads: Option<usize>,
 }
 
 
 
-fn messages_chat_template(msg_list: &Vec<Message>,system_prompt:&str)->String {
+fn messages_chat_template(msg_list: &[Message],system_prompt:&str)->String {
     let mut history = String::new();
     history.push_str("<|im_start|>system\n");
     history.push_str(system_prompt);
@@ -183,6 +129,14 @@ fn messages_chat_template(msg_list: &Vec<Message>,system_prompt:&str)->String {
 fn main() -> Result<()> {
     
     let args = Args::parse();
+    let sampling: SamplingParams = match args.sampling.as_deref() {
+        Some(sampling) => serde_json::from_str(sampling)
+            .map_err(|e| E::msg(format!("invalid --sampling: {}", e)))?,
+        None => Default::default(),
+    };
+    if let Some(threads) = args.threads {
+        std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+    }
     let start = std::time::Instant::now();
     let api = Api::new()?;
     let model_id = "Qwen/Qwen2-1.5B-Instruct".to_string();
@@ -192,56 +146,77 @@ fn main() -> Result<()> {
         "main".to_string(),
     ));
     let tokenizer_filename = repo.get("tokenizer.json")?;
+    let tokenizer_config = repo.get("tokenizer_config.json").ok();
     let filenames = vec![repo.get("model.safetensors")?];
     println!("retrieved the files in {:?}", start.elapsed());
     let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
 
     let start = std::time::Instant::now();
     let config_file = repo.get("config.json")?;
-    let device = if cuda_is_available() {
-        Device::new_cuda(0).expect("create cuda device failed!")
-    } else {
-        Device::Cpu
-    };
-    
-    let dtype = if device.is_cuda() {
-        DType::BF16
-    } else {
-        DType::F32
-    };
+    let (device, dtype) = select_device(&LoadOptions {
+        device: args.device.clone(),
+        dtype: args.dtype.clone(),
+        ..Default::default()
+    })?;
     let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-    let model =  {
-            let config: ConfigBase = serde_json::from_str(&std::fs::read_to_string(config_file)?)?;
-            ModelBase::new(&config, vb)?
-    };
+    let config: ConfigBase = serde_json::from_str(&std::fs::read_to_string(config_file)?)?;
+    let model = Transformer::load(vb, &moonweb::qwen2::transformer_config(&config))?;
 
     println!("loaded the model in {:?}", start.elapsed());
     let temp = args.temperature.unwrap_or_else(|| 0.3f64);
     let top_p = args.top_p.unwrap_or_else(|| 0.95f64);
-    let mut pipeline = TextGeneration::new(
-        model,
-        tokenizer,
-        299792458u64,
-        Some(temp),
-        Some(top_p),
-        1.8f32,
-        64usize,
-        &device,
-    );
+    let eos_tokens = tokenizer.token_to_id("<|endoftext|>").into_iter().collect();
+    let mut pipeline = TextGeneration {
+        generator: Generator::new(
+            TransformerDecoder::new(model),
+            tokenizer,
+            eos_tokens,
+            temp,
+            top_p,
+            args.repeat_penalty,
+            args.repeat_last_n,
+            config.max_position_embeddings,
+        )
+        .with_chat_template(ChatTemplate::load(
+            tokenizer_config.as_deref(),
+            args.chat_template.as_deref(),
+        )?)
+        .with_sampling(sampling),
+    };
     let ipc_name = args.ipc_name;
-    let (receiver,sender) = accept(ipc_name);
+    let sender = WorkerChannel::accept(ipc_name);
+    sender.ready(&model_id).unwrap();
     println!("{} server start!",model_id);
     loop {
-        let msg = receiver.recv().unwrap();
-        if let Ok(req) = serde_json::from_str::<Request>(msg.as_str()) {
-            if req.cmd.eq("QUIT") {
-                    break;
-            }
-            let prompt = messages_chat_template(&req.msg_list,"你是源胖子开发的AI助手，你善于回答科普问题。");
-            
-            pipeline.run(&sender,prompt.as_str(), 1000usize)?;
+        let req = sender.recv().unwrap();
+        if req.cmd.eq("QUIT") {
+            break;
         }
+        let truncation = req.truncation.unwrap_or_default();
+        let prompt = fit_context(
+            &pipeline,
+            &req.msg_list,
+            "你是源胖子开发的AI助手，你善于回答科普问题。",
+            &req.tools,
+            &req.params,
+            &truncation,
+        );
         
+        let parser = RefCell::new(ToolCallParser::new());
+        let run = prompt.and_then(|prompt| {
+            if req.tools.is_empty() {
+                return pipeline.run(&sender, prompt.as_str(), &req.params, req.session_id.as_deref());
+            }
+            let output = ToolCallOutput::new(&parser, &sender);
+            let mut usage = pipeline.run(&output, prompt.as_str(), &req.params, req.session_id.as_deref())?;
+            parser.borrow_mut().finish(&sender, &mut usage)?;
+            Ok(usage)
+        });
+        match run {
+            Ok(usage) => sender.usage(&usage).unwrap(),
+            Err(e) => sender.error(format!("Failed to generate: {}", e)).unwrap(),
+        }
+        sender.end().unwrap();
     }
     
     Ok(())
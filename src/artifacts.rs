@@ -0,0 +1,188 @@
+use crate::master_server::valid_token;
+use crate::master_state::ArtifactConfig;
+use anyhow::{Error, Result};
+use axum::{
+    extract::{Path, Query},
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use axum_auth::AuthBearer;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+static ARTIFACT_STORE: OnceLock<ArtifactStore> = OnceLock::new();
+const UPLOADS_DIR: &str = "uploads";
+
+const CONTENT_TYPES: [(&str, &str); 5] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("gif", "image/gif"),
+    ("bin", "application/octet-stream"),
+];
+
+fn extension_of(content_type: &str) -> &'static str {
+    CONTENT_TYPES
+        .iter()
+        .find(|(_, ct)| *ct == content_type)
+        .map(|(ext, _)| *ext)
+        .unwrap_or("bin")
+}
+
+/// Files produced by the workers, stored under the sha256 of their content
+/// so that the same image is only kept once. Uploads go to the `uploads`
+/// directory, which garbage collection leaves alone since saved sessions
+/// reference them.
+pub struct ArtifactStore {
+    dir: PathBuf,
+    max_age: Option<Duration>,
+    quota: Option<u64>,
+}
+
+impl ArtifactStore {
+    pub(crate) fn open(config: &ArtifactConfig) -> Result<ArtifactStore> {
+        let dir = PathBuf::from(config.dir.clone());
+        fs::create_dir_all(dir.join(UPLOADS_DIR))?;
+        Ok(ArtifactStore {
+            dir,
+            max_age: config.max_age_hours.map(|h| Duration::from_secs(h * 3600)),
+            quota: config.quota_mb.map(|mb| mb * 1024 * 1024),
+        })
+    }
+
+    /// Stores the bytes and returns the id to fetch them from `/api/files/{id}`.
+    pub fn store(&self, content_type: &str, data: &[u8]) -> Result<String> {
+        store_in(&self.dir, content_type, data)
+    }
+
+    /// Stores a file uploaded by a user, which is never collected.
+    pub fn store_upload(&self, content_type: &str, data: &[u8]) -> Result<String> {
+        store_in(&self.dir.join(UPLOADS_DIR), content_type, data)
+    }
+
+    pub fn load(&self, id: &str) -> Option<(Vec<u8>, &'static str)> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        [self.dir.clone(), self.dir.join(UPLOADS_DIR)].iter().find_map(|dir| {
+            CONTENT_TYPES.iter().find_map(|(ext, content_type)| {
+                fs::read(dir.join(format!("{}.{}", id, ext)))
+                    .ok()
+                    .map(|data| (data, *content_type))
+            })
+        })
+    }
+
+    /// Removes artifacts older than the configured age, then the oldest ones
+    /// until the directory fits in the quota.
+    pub fn collect_garbage(&self) -> Result<()> {
+        let now = SystemTime::now();
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if !meta.is_file() {
+                continue;
+            }
+            let modified = meta.modified()?;
+            let age = now.duration_since(modified).unwrap_or_default();
+            if self.max_age.is_some_and(|max_age| age > max_age) {
+                fs::remove_file(entry.path())?;
+                println!("artifact {:?} expired", entry.path());
+            } else {
+                files.push((modified, meta.len(), entry.path()));
+            }
+        }
+        if let Some(quota) = self.quota {
+            let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+            files.sort_by_key(|(modified, _, _)| *modified);
+            for (_, len, path) in files {
+                if total <= quota {
+                    break;
+                }
+                fs::remove_file(&path)?;
+                total -= len;
+                println!("artifact {:?} removed for quota", path);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn store_in(dir: &std::path::Path, content_type: &str, data: &[u8]) -> Result<String> {
+    let id = format!("{:x}", Sha256::digest(data));
+    let path = dir.join(format!("{}.{}", id, extension_of(content_type)));
+    if path.exists() {
+        // refresh the age so garbage collection keeps files still in use.
+        fs::File::options()
+            .append(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())?;
+    } else {
+        let tmp = dir.join(format!("{}.tmp", id));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(id)
+}
+
+pub(crate) fn init(config: &ArtifactConfig) {
+    let store = ArtifactStore::open(config)
+        .unwrap_or_else(|e| panic!("Failed to open artifact directory {}: {}", config.dir, e));
+    let _ = ARTIFACT_STORE.set(store);
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            if let Err(e) = artifact_store().collect_garbage() {
+                println!("artifact garbage collection failed: {:?}", e);
+            }
+        }
+    });
+}
+
+pub fn artifact_store() -> &'static ArtifactStore {
+    ARTIFACT_STORE.get().expect("Artifact store is not opened!")
+}
+
+/// Stores an artifact received from a worker and returns the markdown that
+/// shows it in the chat.
+pub fn save_artifact(content_type: &str, alt: &str, data: &[u8]) -> Result<String, Error> {
+    let id = artifact_store().store(content_type, data)?;
+    Ok(format!("![{}](/api/files/{})", alt, id))
+}
+
+#[derive(Deserialize)]
+pub struct FileQuery {
+    token: Option<String>,
+}
+
+/// `<img>` tags cannot send a bearer header, so the token may also be given
+/// as the `token` query parameter.
+pub async fn get_file(
+    auth: Option<AuthBearer>,
+    Query(query): Query<FileQuery>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let token = match auth {
+        Some(AuthBearer(token)) => Some(token),
+        None => query.token,
+    };
+    if !token.is_some_and(|token| valid_token(token.as_str())) {
+        return Err((StatusCode::UNAUTHORIZED, "Authentication failed."));
+    }
+    match artifact_store().load(id.as_str()) {
+        Some((data, content_type)) => Ok((
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::CACHE_CONTROL, "private, max-age=31536000, immutable"),
+            ],
+            data,
+        )),
+        None => Err((StatusCode::NOT_FOUND, "File is not exist!")),
+    }
+}
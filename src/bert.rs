@@ -0,0 +1,112 @@
+use anyhow::{Error, Result};
+
+use crate::data::Embeddings;
+use crate::model::TextEmbedModel;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use crate::registry::{parse_device, parse_dtype, FileCheck, LoadOptions, ModelSource};
+use tokenizers::{PaddingParams, Tokenizer};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Pooling {
+    /// the hidden state of the [CLS] token, as the bge models are trained.
+    Cls,
+    /// the average of the token hidden states, as sentence-transformers do.
+    Mean,
+}
+
+pub struct TextEmbedding {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+    pooling: Pooling,
+}
+
+impl TextEmbedModel for TextEmbedding {
+    fn embed(&mut self, input: &[String]) -> Result<Embeddings, Error> {
+        if input.is_empty() {
+            return Ok(Embeddings {
+                embeddings: Vec::new(),
+                prompt_tokens: 0,
+            });
+        }
+        let encodings = self
+            .tokenizer
+            .encode_batch(input.to_vec(), true)
+            .map_err(Error::msg)?;
+        let mut prompt_tokens = 0usize;
+        let mut token_ids = Vec::with_capacity(encodings.len());
+        let mut attention_mask = Vec::with_capacity(encodings.len());
+        for encoding in encodings.iter() {
+            prompt_tokens += encoding.get_attention_mask().iter().sum::<u32>() as usize;
+            token_ids.push(Tensor::new(encoding.get_ids(), &self.device)?);
+            attention_mask.push(Tensor::new(encoding.get_attention_mask(), &self.device)?);
+        }
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+        let token_type_ids = token_ids.zeros_like()?;
+        let hidden = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+        let pooled = match self.pooling {
+            Pooling::Cls => hidden.narrow(1, 0, 1)?.squeeze(1)?,
+            Pooling::Mean => {
+                let mask = attention_mask.to_dtype(hidden.dtype())?.unsqueeze(2)?;
+                let sum = hidden.broadcast_mul(&mask)?.sum(1)?;
+                sum.broadcast_div(&mask.sum(1)?)?
+            }
+        };
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let normalized = pooled.broadcast_div(&norm)?;
+        Ok(Embeddings {
+            embeddings: normalized.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+            prompt_tokens,
+        })
+    }
+}
+
+/// Loads a BERT style embedding model (bge, MiniLM, ...), on the CPU unless
+/// the options name a device.
+pub fn load(model_id: &str, options: &LoadOptions) -> Result<TextEmbedding> {
+    let device = match options.device.as_deref() {
+        Some(name) => parse_device(name)?,
+        None => Device::Cpu,
+    };
+    let dtype = match options.dtype.as_deref() {
+        Some(name) => parse_dtype(name)?,
+        None => DTYPE,
+    };
+    let source = ModelSource::new(model_id, options)?;
+    let mut files = FileCheck::new(&source);
+    let config_filename = files.require("config.json");
+    let tokenizer_filename = files.require("tokenizer.json");
+    let weights_filename = files.require("model.safetensors");
+    files.finish()?;
+    let config_filename = config_filename.unwrap_or_default();
+    let tokenizer_filename = tokenizer_filename.unwrap_or_default();
+    let weights_filename = weights_filename.unwrap_or_default();
+
+    let config: Config = serde_json::from_str(&std::fs::read_to_string(config_filename)?)?;
+    let mut tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(Error::msg)?;
+    tokenizer.with_padding(Some(PaddingParams::default()));
+    tokenizer
+        .with_truncation(Some(tokenizers::TruncationParams {
+            max_length: config.max_position_embeddings,
+            ..Default::default()
+        }))
+        .map_err(Error::msg)?;
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], dtype, &device)? };
+    let model = BertModel::load(vb, &config)?;
+    let pooling = if model_id.to_lowercase().contains("bge") {
+        Pooling::Cls
+    } else {
+        Pooling::Mean
+    };
+    Ok(TextEmbedding {
+        model,
+        tokenizer,
+        device,
+        pooling,
+    })
+}
@@ -0,0 +1,331 @@
+use anyhow::{Error, Result};
+use minijinja::{context, Environment, ErrorKind};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::Path;
+
+use crate::data::{Message, Role, Tool};
+
+const TEMPLATE_NAME: &str = "chat";
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ChatToolCall<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ChatToolCall<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    kind: &'a str,
+    function: ChatFunctionCall<'a>,
+}
+
+/// Templates expect the arguments as a mapping rather than a JSON string.
+#[derive(Serialize)]
+struct ChatFunctionCall<'a> {
+    name: &'a str,
+    arguments: Value,
+}
+
+/// The Jinja `chat_template` of a model, rendered the way `transformers`
+/// does in `apply_chat_template`.
+pub struct ChatTemplate {
+    env: Environment<'static>,
+    bos_token: String,
+    eos_token: String,
+    /// whether the template renders the `tools` of a request.
+    supports_tools: bool,
+    /// gemma and mistral templates raise on a system message, their system
+    /// prompt goes into the first user message.
+    supports_system: bool,
+}
+
+impl ChatTemplate {
+    pub fn new(source: String, bos_token: String, eos_token: String) -> Result<Self> {
+        let mut env = Environment::new();
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", |msg: String| -> Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(ErrorKind::InvalidOperation, msg))
+        });
+        let supports_tools = source.contains("tools");
+        env.add_template_owned(TEMPLATE_NAME, source)?;
+        let mut template = ChatTemplate {
+            env,
+            bos_token,
+            eos_token,
+            supports_tools,
+            supports_system: true,
+        };
+        let message = |role, content| ChatMessage {
+            role,
+            content,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        };
+        let with_system = template.render(&[message("system", "Be brief."), message("user", "Hi")], &[]);
+        let without = template.render(&[message("user", "Hi")], &[]);
+        template.supports_system = with_system.is_ok() || without.is_err();
+        Ok(template)
+    }
+
+    /// Reads the template from `tokenizer_config.json`, or from the
+    /// `override_file` when one is configured for the server. `None` when
+    /// neither has one.
+    pub fn load(tokenizer_config: Option<&Path>, override_file: Option<&str>) -> Result<Option<Self>> {
+        let config: Value = match tokenizer_config {
+            Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            None => Value::Null,
+        };
+        let source = match override_file {
+            Some(file) => Some(std::fs::read_to_string(file)?),
+            None => template_source(&config),
+        };
+        let source = match source {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        let template = ChatTemplate::new(
+            source,
+            special_token(&config, "bos_token"),
+            special_token(&config, "eos_token"),
+        )?;
+        Ok(Some(template))
+    }
+
+    pub fn supports_tools(&self) -> bool {
+        self.supports_tools
+    }
+
+    pub fn apply(&self, msg_list: &[Message], system_prompt: &str, tools: &[Tool]) -> Result<String> {
+        let mut messages = Vec::with_capacity(msg_list.len() + 1);
+        let system_prompt = Some(system_prompt).filter(|prompt| !prompt.is_empty());
+        // without a system role the system prompt opens the first user
+        // message, or is one when there is none.
+        let first_user = msg_list.iter().position(|msg| msg.role == Role::User);
+        let merged = match (system_prompt, first_user) {
+            (Some(prompt), Some(index)) if !self.supports_system => {
+                Some(format!("{}\n\n{}", prompt, msg_list[index].content))
+            }
+            _ => None,
+        };
+        if let Some(prompt) = system_prompt.filter(|_| merged.is_none()) {
+            messages.push(ChatMessage {
+                role: if self.supports_system { "system" } else { "user" },
+                content: prompt,
+                tool_calls: Vec::new(),
+                tool_call_id: None,
+            });
+        }
+        for (index, msg) in msg_list.iter().enumerate() {
+            let role = match msg.role {
+                Role::User => "user",
+                Role::Robot => "assistant",
+                Role::Tool => "tool",
+                Role::Administrator => continue,
+            };
+            let tool_calls = msg
+                .tool_calls
+                .iter()
+                .map(|call| ChatToolCall {
+                    id: call.id.as_str(),
+                    kind: call.kind.as_str(),
+                    function: ChatFunctionCall {
+                        name: call.function.name.as_str(),
+                        arguments: serde_json::from_str(&call.function.arguments)
+                            .unwrap_or_else(|_| Value::String(call.function.arguments.clone())),
+                    },
+                })
+                .collect();
+            let content = match (&merged, first_user) {
+                (Some(merged), Some(first)) if first == index => merged.as_str(),
+                _ => msg.content.as_str(),
+            };
+            messages.push(ChatMessage {
+                role,
+                content,
+                tool_calls,
+                tool_call_id: msg.tool_call_id.as_deref(),
+            });
+        }
+        self.render(&messages, tools)
+    }
+
+    fn render(&self, messages: &[ChatMessage], tools: &[Tool]) -> Result<String> {
+        let template = self.env.get_template(TEMPLATE_NAME)?;
+        let prompt = template
+            .render(context! {
+                messages => messages,
+                tools => (!tools.is_empty()).then_some(tools),
+                add_generation_prompt => true,
+                bos_token => self.bos_token,
+                eos_token => self.eos_token,
+            })
+            .map_err(|e| Error::msg(format!("Failed to render the chat template: {}", e)))?;
+        Ok(prompt)
+    }
+}
+
+/// `chat_template` is either the template itself or a list of named ones.
+fn template_source(config: &Value) -> Option<String> {
+    match config.get("chat_template")? {
+        Value::String(source) => Some(source.clone()),
+        Value::Array(templates) => templates
+            .iter()
+            .find(|t| t.get("name").and_then(Value::as_str) == Some("default"))
+            .and_then(|t| t.get("template"))
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Special tokens are either plain strings or serialized `AddedToken`s.
+fn special_token(config: &Value, name: &str) -> String {
+    match config.get(name) {
+        Some(Value::String(token)) => token.clone(),
+        Some(token) => token
+            .get("content")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// The `tokenizer_config.json` of Meta-Llama-3-8B-Instruct, trimmed to
+    /// what the template needs.
+    fn llama3() -> Value {
+        json!({
+            "bos_token": "<|begin_of_text|>",
+            "eos_token": "<|eot_id|>",
+            "chat_template": "{% set loop_messages = messages %}{% for message in loop_messages %}{% set content = '<|start_header_id|>' + message['role'] + '<|end_header_id|>\n\n'+ message['content'] | trim + '<|eot_id|>' %}{% if loop.index0 == 0 %}{% set content = bos_token + content %}{% endif %}{{ content }}{% endfor %}{% if add_generation_prompt %}{{ '<|start_header_id|>assistant<|end_header_id|>\n\n' }}{% endif %}"
+        })
+    }
+
+    /// Qwen2-7B-Instruct.
+    fn qwen2() -> Value {
+        json!({
+            "bos_token": null,
+            "eos_token": "<|im_end|>",
+            "chat_template": "{% for message in messages %}{% if loop.first and messages[0]['role'] != 'system' %}{{ '<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n' }}{% endif %}{{'<|im_start|>' + message['role'] + '\n' + message['content'] + '<|im_end|>' + '\n'}}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}"
+        })
+    }
+
+    /// gemma-2b-it, which has no system role.
+    fn gemma() -> Value {
+        json!({
+            "bos_token": { "content": "<bos>", "lstrip": false, "normalized": false, "rstrip": false, "single_word": false },
+            "eos_token": "<eos>",
+            "chat_template": "{{ bos_token }}{% if messages[0]['role'] == 'system' %}{{ raise_exception('System role not supported') }}{% endif %}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if (message['role'] == 'assistant') %}{% set role = 'model' %}{% else %}{% set role = message['role'] %}{% endif %}{{ '<start_of_turn>' + role + '\n' + message['content'] | trim + '<end_of_turn>\n' }}{% endfor %}{% if add_generation_prompt %}{{'<start_of_turn>model\n'}}{% endif %}"
+        })
+    }
+
+    /// Mistral-7B-Instruct-v0.2, which only knows users and assistants.
+    fn mistral() -> Value {
+        json!({
+            "bos_token": "<s>",
+            "eos_token": "</s>",
+            "chat_template": "{{ bos_token }}{% for message in messages %}{% if (message['role'] == 'user') != (loop.index0 % 2 == 0) %}{{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}{% endif %}{% if message['role'] == 'user' %}{{ '[INST] ' + message['content'] + ' [/INST]' }}{% elif message['role'] == 'assistant' %}{{ message['content'] + eos_token}}{% else %}{{ raise_exception('Only user and assistant roles are supported!') }}{% endif %}{% endfor %}"
+        })
+    }
+
+    /// Loads the template the way a model does, from its tokenizer_config.json.
+    fn template(name: &str, config: Value) -> ChatTemplate {
+        let path = std::env::temp_dir().join(format!("chat_template_{}_{}.json", name, std::process::id()));
+        std::fs::write(&path, config.to_string()).unwrap();
+        let template = ChatTemplate::load(Some(&path), None).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        template
+    }
+
+    fn message(role: &str, content: &str) -> Message {
+        serde_json::from_value(json!({
+            "id": 0,
+            "role": role,
+            "content": content,
+            "img": null,
+            "loading": false
+        }))
+        .unwrap()
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            message("User", "Hi"),
+            message("Robot", "Hello!"),
+            message("User", "How are you?"),
+        ]
+    }
+
+    #[test]
+    fn llama3_renders_the_system_prompt() {
+        let prompt = template("llama3", llama3())
+            .apply(&conversation(), "You are helpful assistant!", &[])
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nYou are helpful assistant!<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\nHello!<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHow are you?<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+    }
+
+    #[test]
+    fn qwen2_renders_the_system_prompt() {
+        let template = template("qwen2", qwen2());
+        let prompt = template.apply(&conversation(), "You are helpful assistant!", &[]).unwrap();
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nYou are helpful assistant!<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n\
+             <|im_start|>user\nHow are you?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+        // without one the template brings its own.
+        let prompt = template.apply(&conversation()[..1], "", &[]).unwrap();
+        assert!(prompt.starts_with("<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n"));
+    }
+
+    #[test]
+    fn gemma_gets_the_system_prompt_in_the_first_user_message() {
+        let prompt = template("gemma", gemma())
+            .apply(&conversation(), "You are helpful assistant!", &[])
+            .unwrap();
+        assert_eq!(
+            prompt,
+            "<bos><start_of_turn>user\nYou are helpful assistant!\n\nHi<end_of_turn>\n\
+             <start_of_turn>model\nHello!<end_of_turn>\n\
+             <start_of_turn>user\nHow are you?<end_of_turn>\n\
+             <start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn mistral_gets_the_system_prompt_in_the_first_user_message() {
+        let template = template("mistral", mistral());
+        let prompt = template.apply(&conversation(), "You are helpful assistant!", &[]).unwrap();
+        assert_eq!(
+            prompt,
+            "<s>[INST] You are helpful assistant!\n\nHi [/INST]Hello!</s>[INST] How are you? [/INST]"
+        );
+        // a conversation without a user message has the system prompt as one.
+        let prompt = template.apply(&[], "Say hi.", &[]).unwrap();
+        assert_eq!(prompt, "<s>[INST] Say hi. [/INST]");
+    }
+}
@@ -0,0 +1,441 @@
+use anyhow::{Error, Result};
+use candle_core::Tensor;
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::{Anchored, MatchKind};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokenizers::decoders::DecoderWrapper;
+use tokenizers::Tokenizer;
+
+use crate::data::Constraint;
+
+/// JSON values without a schema are matched this many levels deep, deeper
+/// documents can not be expressed by a regular expression.
+const JSON_DEPTH: usize = 3;
+/// the compiled automaton of one request may not get bigger than this.
+const DFA_SIZE_LIMIT: usize = 64 << 20;
+const WS: &str = r"[ \n]*";
+const STRING: &str = r#""([^"\\\x00-\x1f]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})*""#;
+const INTEGER: &str = r"-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+
+/// The bytes each token of a vocabulary decodes to, `None` for the special
+/// tokens, which never match a constraint.
+pub struct Vocabulary {
+    tokens: Vec<Option<Vec<u8>>>,
+}
+
+impl Vocabulary {
+    pub fn new(tokenizer: &Tokenizer) -> Self {
+        let byte_level = matches!(tokenizer.get_decoder(), Some(DecoderWrapper::ByteLevel(_)));
+        let chars = unicode_bytes();
+        let special = tokenizer.get_added_tokens_decoder();
+        let mut tokens = vec![None; tokenizer.get_vocab_size(true)];
+        for (token, id) in tokenizer.get_vocab(true) {
+            let id = id as usize;
+            if id >= tokens.len() {
+                continue;
+            }
+            tokens[id] = if let Some(added) = special.get(&(id as u32)) {
+                (!added.special).then(|| added.content.clone().into_bytes())
+            } else if byte_level {
+                token.chars().map(|c| chars.get(&c).copied()).collect()
+            } else {
+                Some(sentencepiece_bytes(&token))
+            };
+        }
+        Vocabulary { tokens }
+    }
+}
+
+/// The inverse of the byte to unicode table of byte level BPE.
+fn unicode_bytes() -> HashMap<char, u8> {
+    let mut bytes: Vec<u8> = (b'!'..=b'~').chain(b'\xA1'..=b'\xAC').chain(b'\xAE'..=b'\xFF').collect();
+    let mut chars: Vec<u32> = bytes.iter().map(|b| *b as u32).collect();
+    let mut n = 0;
+    for b in 0..=255u8 {
+        if !bytes.contains(&b) {
+            bytes.push(b);
+            chars.push(256 + n);
+            n += 1;
+        }
+    }
+    chars
+        .into_iter()
+        .filter_map(char::from_u32)
+        .zip(bytes)
+        .collect()
+}
+
+fn sentencepiece_bytes(token: &str) -> Vec<u8> {
+    if token.len() == 6 && token.starts_with("<0x") && token.ends_with('>') {
+        if let Ok(byte) = u8::from_str_radix(&token[3..5], 16) {
+            return vec![byte];
+        }
+    }
+    token.replace('\u{2581}', " ").into_bytes()
+}
+
+/// A constraint compiled for the vocabulary of a model, following the tokens
+/// of one reply.
+pub struct ConstraintState {
+    dfa: dense::DFA<Vec<u32>>,
+    vocabulary: Arc<Vocabulary>,
+    state: StateID,
+    /// the tokens that do not lead to a dead state, by state.
+    allowed: HashMap<StateID, Arc<Vec<u32>>>,
+}
+
+impl ConstraintState {
+    pub fn new(constraint: &Constraint, vocabulary: Arc<Vocabulary>) -> Result<Self> {
+        let pattern = match constraint {
+            Constraint::Regex(regex) => regex.clone(),
+            Constraint::JsonSchema(schema) => format!("{WS}{}{WS}", schema_regex(schema, schema, 0)?),
+        };
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .match_kind(MatchKind::All)
+                    .start_kind(StartKind::Anchored)
+                    .dfa_size_limit(Some(DFA_SIZE_LIMIT))
+                    .determinize_size_limit(Some(DFA_SIZE_LIMIT)),
+            )
+            .build(&format!("(?:{pattern})$"))
+            .map_err(|e| Error::msg(format!("invalid constraint: {e}")))?;
+        let state = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(Error::msg)?;
+        Ok(ConstraintState {
+            dfa,
+            vocabulary,
+            state,
+            allowed: HashMap::new(),
+        })
+    }
+
+    fn walk(&self, mut state: StateID, bytes: &[u8]) -> StateID {
+        for byte in bytes {
+            state = self.dfa.next_state(state, *byte);
+            if self.dfa.is_dead_state(state) || self.dfa.is_quit_state(state) {
+                break;
+            }
+        }
+        state
+    }
+
+    fn is_complete(&self) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(self.state))
+    }
+
+    /// Sets the logits of the tokens that can not continue the reply to minus
+    /// infinity. The eos tokens are only left once the reply is complete, or
+    /// when no token fits any more.
+    pub fn mask(&mut self, logits: &Tensor, eos_tokens: &[u32]) -> Result<Tensor> {
+        let allowed = match self.allowed.get(&self.state) {
+            Some(allowed) => allowed.clone(),
+            None => {
+                let allowed: Vec<u32> = self
+                    .vocabulary
+                    .tokens
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(id, bytes)| {
+                        let bytes = bytes.as_deref().filter(|bytes| !bytes.is_empty())?;
+                        let state = self.walk(self.state, bytes);
+                        let dead = self.dfa.is_dead_state(state) || self.dfa.is_quit_state(state);
+                        (!dead).then_some(id as u32)
+                    })
+                    .collect();
+                let allowed = Arc::new(allowed);
+                self.allowed.insert(self.state, allowed.clone());
+                allowed
+            }
+        };
+        let values = logits.to_vec1::<f32>()?;
+        let mut masked = vec![f32::NEG_INFINITY; values.len()];
+        let mut keep = |id: u32| {
+            if let Some(value) = values.get(id as usize) {
+                masked[id as usize] = *value;
+            }
+        };
+        allowed.iter().copied().for_each(&mut keep);
+        if allowed.is_empty() || self.is_complete() {
+            eos_tokens.iter().copied().for_each(&mut keep);
+        }
+        Ok(Tensor::new(masked, logits.device())?)
+    }
+
+    /// Moves past a sampled token.
+    pub fn advance(&mut self, token: u32) {
+        if let Some(Some(bytes)) = self.vocabulary.tokens.get(token as usize) {
+            self.state = self.walk(self.state, bytes);
+        }
+    }
+}
+
+/// A regular expression for the JSON documents valid against `schema`, for
+/// the types, enums, properties, items, unions and local references of JSON
+/// schema. Properties are written in the order of the schema.
+fn schema_regex(schema: &Value, root: &Value, depth: usize) -> Result<String> {
+    if depth > 16 {
+        anyhow::bail!("the JSON schema is nested too deep or recursive");
+    }
+    let schema = match schema {
+        Value::Bool(true) => return Ok(any_json(JSON_DEPTH)),
+        Value::Object(schema) => schema,
+        _ => anyhow::bail!("unsupported JSON schema {}", schema),
+    };
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| root.pointer(pointer))
+            .ok_or_else(|| Error::msg(format!("unresolved $ref {reference}")))?;
+        return schema_regex(target, root, depth + 1);
+    }
+    if let Some(value) = schema.get("const") {
+        return Ok(escape(&value.to_string()));
+    }
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        let values: Vec<String> = values.iter().map(|v| escape(&v.to_string())).collect();
+        return Ok(format!("({})", values.join("|")));
+    }
+    for key in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(schemas)) = schema.get(key) {
+            let alternatives = schemas
+                .iter()
+                .map(|s| schema_regex(s, root, depth + 1))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(format!("({})", alternatives.join("|")));
+        }
+    }
+    let types = match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        None if schema.contains_key("properties") => vec!["object"],
+        None if schema.contains_key("items") => vec!["array"],
+        None => return Ok(any_json(JSON_DEPTH)),
+        Some(t) => anyhow::bail!("unsupported JSON schema type {}", t),
+    };
+    let mut alternatives = Vec::new();
+    for t in types {
+        alternatives.push(match t {
+            "string" => string_regex(schema),
+            "integer" => INTEGER.to_string(),
+            "number" => NUMBER.to_string(),
+            "boolean" => "(true|false)".to_string(),
+            "null" => "null".to_string(),
+            "array" => {
+                let item = match schema.get("items") {
+                    Some(items) => schema_regex(items, root, depth + 1)?,
+                    None => any_json(JSON_DEPTH - 1),
+                };
+                format!(r"\[{WS}({item}({WS},{WS}{item})*)?{WS}\]")
+            }
+            "object" => object_regex(schema, root, depth)?,
+            _ => anyhow::bail!("unsupported JSON schema type {}", t),
+        });
+    }
+    Ok(format!("({})", alternatives.join("|")))
+}
+
+/// The properties listed in `required` are always written, the others may be
+/// left out. Other properties only follow when `additionalProperties` allows
+/// them, or when the object declares no properties at all.
+fn object_regex(schema: &serde_json::Map<String, Value>, root: &Value, depth: usize) -> Result<String> {
+    let no_properties = serde_json::Map::new();
+    let properties = match schema.get("properties") {
+        Some(Value::Object(properties)) => properties,
+        _ => &no_properties,
+    };
+    let required: Vec<&str> = match schema.get("required") {
+        Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let additional = match schema.get("additionalProperties") {
+        Some(Value::Bool(false)) => None,
+        None if !properties.is_empty() => None,
+        None | Some(Value::Bool(true)) => Some(any_json(JSON_DEPTH - 1)),
+        Some(additional) => Some(schema_regex(additional, root, depth + 1)?),
+    };
+    let sep = format!("{WS},{WS}");
+    // built from the last field back: `after_some` follows a field already
+    // written, `after_none` starts the object.
+    let (mut after_some, mut after_none) = match additional {
+        Some(value) => {
+            let field = format!("{STRING}{WS}:{WS}{value}");
+            (format!("({sep}{field})*"), format!("({field}({sep}{field})*)?"))
+        }
+        None => (String::new(), String::new()),
+    };
+    for (name, property) in properties.iter().rev() {
+        let field = format!(
+            "{}{WS}:{WS}{}",
+            escape(&Value::String(name.clone()).to_string()),
+            schema_regex(property, root, depth + 1)?
+        );
+        if required.contains(&name.as_str()) {
+            after_none = format!("{field}{after_some}");
+            after_some = format!("{sep}{field}{after_some}");
+        } else {
+            after_none = format!("({field}{after_some}|{after_none})");
+            after_some = format!("({sep}{field})?{after_some}");
+        }
+    }
+    Ok(format!(r"\{{{WS}{after_none}{WS}\}}"))
+}
+
+fn string_regex(schema: &serde_json::Map<String, Value>) -> String {
+    if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+        let pattern = pattern.trim_start_matches('^').trim_end_matches('$');
+        return format!("\"({pattern})\"");
+    }
+    let min = schema.get("minLength").and_then(Value::as_u64);
+    let max = schema.get("maxLength").and_then(Value::as_u64);
+    if min.is_none() && max.is_none() {
+        return STRING.to_string();
+    }
+    let character = r#"([^"\\\x00-\x1f]|\\["\\/bfnrt]|\\u[0-9a-fA-F]{4})"#;
+    let max = max.map(|max| max.to_string()).unwrap_or_default();
+    format!("\"{character}{{{},{max}}}\"", min.unwrap_or(0))
+}
+
+/// Any JSON value with at most `depth` levels of arrays and objects.
+fn any_json(depth: usize) -> String {
+    let scalar = format!("({STRING}|{NUMBER}|true|false|null)");
+    if depth == 0 {
+        return scalar;
+    }
+    let value = any_json(depth - 1);
+    format!(r"({scalar}|\[{WS}({value}({WS},{WS}{value})*)?{WS}\]|{})", any_object(depth - 1))
+}
+
+fn any_object(depth: usize) -> String {
+    let value = any_json(depth);
+    let field = format!("{STRING}{WS}:{WS}{value}");
+    format!(r"\{{{WS}({field}({WS},{WS}{field})*)?{WS}\}}")
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if r"\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+    use serde_json::json;
+
+    fn compile(constraint: Constraint, tokens: &[&str]) -> ConstraintState {
+        let vocabulary = Vocabulary {
+            tokens: tokens.iter().map(|token| Some(token.as_bytes().to_vec())).collect(),
+        };
+        ConstraintState::new(&constraint, Arc::new(vocabulary)).unwrap()
+    }
+
+    fn accepts(schema: &Value, text: &str) -> bool {
+        let mut state = compile(Constraint::JsonSchema(schema.clone()), &[]);
+        state.state = state.walk(state.state, text.as_bytes());
+        state.is_complete()
+    }
+
+    #[test]
+    fn required_properties_must_be_written() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "age": {"type": "integer"},
+                "name": {"type": "string"},
+                "tags": {"type": "array", "items": {"type": "string"}}
+            },
+            "required": ["name"]
+        });
+        for text in [
+            r#"{"name": "a"}"#,
+            r#"{"age": 3, "name": "a"}"#,
+            r#"{"name": "a", "tags": ["x", "y"]}"#,
+            r#"{ "age":3,"name":"a","tags":[] }"#,
+        ] {
+            assert!(accepts(&schema, text), "{text}");
+        }
+        for text in [
+            "{}",
+            r#"{"age": 3}"#,
+            r#"{"name": 1}"#,
+            r#"{, "name": "a"}"#,
+            r#"{"name": "a",}"#,
+            r#"{"name": "a", "age": 3}"#,
+            r#"{"name": "a", "extra": 1}"#,
+        ] {
+            assert!(!accepts(&schema, text), "{text}");
+        }
+    }
+
+    #[test]
+    fn optional_properties_may_all_be_left_out() {
+        let schema = json!({"properties": {"a": {"type": "integer"}, "b": {"type": "boolean"}}});
+        for text in ["{}", r#"{"a": 1}"#, r#"{"b": true}"#, r#"{"a": 1, "b": false}"#] {
+            assert!(accepts(&schema, text), "{text}");
+        }
+        for text in [r#"{"b": true, "a": 1}"#, r#"{, "b": true}"#, r#"{"a": 1,}"#] {
+            assert!(!accepts(&schema, text), "{text}");
+        }
+    }
+
+    #[test]
+    fn additional_properties() {
+        let map = json!({"type": "object", "additionalProperties": {"type": "integer"}});
+        assert!(accepts(&map, "{}"));
+        assert!(accepts(&map, r#"{"x": 1, "y": 2}"#));
+        assert!(!accepts(&map, r#"{"x": "1"}"#));
+
+        let open = json!({
+            "properties": {"id": {"type": "integer"}},
+            "required": ["id"],
+            "additionalProperties": true
+        });
+        assert!(accepts(&open, r#"{"id": 1, "note": "x"}"#));
+        assert!(!accepts(&open, r#"{"note": "x"}"#));
+
+        let closed = json!({"type": "object", "additionalProperties": false});
+        assert!(accepts(&closed, "{}"));
+        assert!(!accepts(&closed, r#"{"x": 1}"#));
+    }
+
+    #[test]
+    fn references_and_enums() {
+        let schema = json!({
+            "$defs": {"color": {"enum": ["red", "green"]}},
+            "type": "array",
+            "items": {"$ref": "#/$defs/color"}
+        });
+        assert!(accepts(&schema, r#"["red", "green"]"#));
+        assert!(!accepts(&schema, r#"["blue"]"#));
+    }
+
+    #[test]
+    fn mask_allows_eos_only_once_complete() {
+        const EOS: u32 = 3;
+        let mut state = compile(Constraint::Regex("ab?".to_string()), &["a", "b", "ab"]);
+        let logits = Tensor::zeros(4, candle_core::DType::F32, &Device::Cpu).unwrap();
+        let allowed = |state: &mut ConstraintState| -> Vec<usize> {
+            let masked = state.mask(&logits, &[EOS]).unwrap().to_vec1::<f32>().unwrap();
+            (0..masked.len()).filter(|i| masked[*i].is_finite()).collect()
+        };
+        assert_eq!(allowed(&mut state), [0, 2]);
+        state.advance(0);
+        assert_eq!(allowed(&mut state), [1, EOS as usize]);
+        state.advance(1);
+        // nothing fits after "ab", the reply can only end.
+        assert_eq!(allowed(&mut state), [EOS as usize]);
+    }
+}
@@ -6,6 +6,8 @@ pub enum Role {
     Robot,
     User,
     Administrator,
+    /// the result of a tool call, answering the `tool_call_id` of the message.
+    Tool,
 }
 
 impl std::str::FromStr for Role {
@@ -15,6 +17,7 @@ impl std::str::FromStr for Role {
             "User" => Ok(Role::User),
             "Robot" => Ok(Role::Robot),
             "Administrator" => Ok(Role::Administrator),
+            "Tool" => Ok(Role::Tool),
             _ => Err(format!("'{}' is not a valid value for Role", s)),
         }
     }
@@ -26,13 +29,259 @@ pub struct Message {
     pub role: Role,
     pub content: String,
     pub img: Option<String>,
+    /// id of a file sent through `/api/uploads`, resolved by the master.
+    #[serde(default)]
+    pub attachment: Option<String>,
     pub loading: bool,
+    /// token usage of a generated message.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// the tools a generated message calls.
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    /// the call a `Role::Tool` message is the result of.
+    #[serde(default)]
+    pub tool_call_id: Option<String>,
 }
-#[derive(Debug, Serialize, Deserialize)]
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+/// A function the model may call, as in the `tools` of the OpenAI API.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// JSON schema of the arguments.
+    #[serde(default)]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// A call the model made, as in the `tool_calls` of the OpenAI API.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// the arguments encoded as a JSON object.
+    pub arguments: String,
+}
+/// Sampling settings of one request. Unset values fall back to the defaults
+/// of the model.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct SamplingParams {
+    /// 0 always picks the likeliest token.
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    /// 1 turns nucleus sampling off.
+    #[serde(default)]
+    pub top_p: Option<f64>,
+    /// 0 turns top-k sampling off.
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    /// drops the tokens less likely than this fraction of the likeliest one.
+    #[serde(default)]
+    pub min_p: Option<f64>,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub repeat_penalty: Option<f32>,
+    /// number of recent tokens the repeat penalty applies to.
+    #[serde(default)]
+    pub repeat_last_n: Option<usize>,
+    /// subtracted from a logit for each time the reply already has the token.
+    #[serde(default)]
+    pub frequency_penalty: Option<f32>,
+    /// subtracted from a logit once the reply has the token.
+    #[serde(default)]
+    pub presence_penalty: Option<f32>,
+    /// generation halts before any of these strings would be emitted.
+    #[serde(default)]
+    pub stop: Vec<String>,
+    /// only replies matching it can be sampled.
+    #[serde(default)]
+    pub constraint: Option<Constraint>,
+    /// stream the log probability of each generated token.
+    #[serde(default)]
+    pub logprobs: bool,
+    /// with `logprobs`, also this many of the likeliest tokens at each position.
+    #[serde(default)]
+    pub top_logprobs: Option<usize>,
+    /// number of replies to generate, streamed as indexed choices when above 1.
+    #[serde(default)]
+    pub n: Option<usize>,
+}
+
+impl SamplingParams {
+    /// These parameters, with the unset ones taken from `defaults`.
+    pub fn or(&self, defaults: &SamplingParams) -> SamplingParams {
+        SamplingParams {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            top_k: self.top_k.or(defaults.top_k),
+            min_p: self.min_p.or(defaults.min_p),
+            max_tokens: self.max_tokens.or(defaults.max_tokens),
+            seed: self.seed.or(defaults.seed),
+            repeat_penalty: self.repeat_penalty.or(defaults.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.or(defaults.repeat_last_n),
+            frequency_penalty: self.frequency_penalty.or(defaults.frequency_penalty),
+            presence_penalty: self.presence_penalty.or(defaults.presence_penalty),
+            stop: if self.stop.is_empty() {
+                defaults.stop.clone()
+            } else {
+                self.stop.clone()
+            },
+            constraint: self.constraint.clone().or_else(|| defaults.constraint.clone()),
+            logprobs: self.logprobs || defaults.logprobs,
+            top_logprobs: self.top_logprobs.or(defaults.top_logprobs),
+            n: self.n.or(defaults.n),
+        }
+    }
+}
+
+/// The log probability of a generated token and its likeliest alternatives,
+/// as in the `logprobs` of the OpenAI API.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f32,
+}
+
+/// The form of a reply, enforced while sampling by masking the tokens that
+/// could not continue it.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type", content = "value")]
+pub enum Constraint {
+    /// a JSON document valid against the schema, any JSON value for `{}`.
+    JsonSchema(serde_json::Value),
+    /// a regular expression the whole reply matches.
+    Regex(String),
+}
+
+/// What to do with a conversation longer than the context of the model.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", tag = "policy")]
+pub enum Truncation {
+    /// drop the oldest turns until the prompt fits.
+    #[default]
+    DropOldest,
+    /// keep the system prompt and the last `n` messages.
+    KeepLast { n: usize },
+    /// refuse the request.
+    Error,
+}
+
+/// Why a generation ended.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Eos,
+    Length,
+    Stop,
+    /// the reply ended with calls of the request's tools.
+    ToolCalls,
+    /// the requester went away before the generation finished.
+    Cancelled,
+}
+
+/// Token counts and speed of one generation.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    /// milliseconds from the start of the request to the first generated token.
+    pub time_to_first_token: f64,
+    pub tokens_per_second: f64,
+    pub finish_reason: FinishReason,
+}
+
+#[derive(Props, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub name: String,
+    pub mode_id: String,
+    pub system_prompt: String,
+    pub history: Option<Vec<Message>>,
+    #[serde(default)]
+    pub params: SamplingParams,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Request {
+    /// set by the master to route the replies of interleaved requests.
+    #[serde(default)]
+    pub id:u64,
     pub cmd:String,
     pub system_prompt:String,
     pub msg_list:Vec<Message>,
+    /// texts to embed for the `embed` command.
+    #[serde(default)]
+    pub input:Vec<String>,
+    #[serde(flatten)]
+    pub params:SamplingParams,
+    /// filled by the master from the worker config when not given.
+    #[serde(default)]
+    pub truncation:Option<Truncation>,
+    /// the conversation the request continues, lets workers reuse their kv cache.
+    #[serde(default)]
+    pub session_id:Option<String>,
+    /// functions the model may call instead of replying.
+    #[serde(default)]
+    pub tools:Vec<Tool>,
+}
+
+/// Reply of an embedding worker to the `embed` command.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Embeddings {
+    pub embeddings: Vec<Vec<f32>>,
+    pub prompt_tokens: usize,
+}
+
+#[derive(Props, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct UploadResponse {
+    pub id: String,
+    pub content_type: String,
+    pub size: usize,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    Chat,
+    Vision,
+    ImageGeneration,
+    Embeddings,
+}
+
+#[derive(Props, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ModelInfo {
+    pub model_id: String,
+    pub capabilities: Vec<Capability>,
 }
 
 #[derive(Props, Clone, PartialEq, Debug, Serialize, Deserialize)]
@@ -0,0 +1,720 @@
+use crate::master_server::valid_admin_token;
+use crate::master_state::get_download_config;
+use anyhow::{Error, Result};
+use axum::{extract::Path, http::StatusCode, Json};
+use axum_auth::AuthBearer;
+use chrono::Utc;
+use dashmap::{mapref::entry::Entry, DashMap};
+use futures::StreamExt;
+use hf_hub::{Cache, Repo};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+
+const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+
+lazy_static! {
+    static ref DOWNLOADS: DashMap<String, Download> = DashMap::<String, Download>::new();
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Pending,
+    Downloading,
+    Verifying,
+    Done,
+    Failed,
+}
+
+/// What the hub says a file hashes to.
+#[derive(Clone, Debug)]
+enum Checksum {
+    /// sha256 of the content, for files stored in lfs.
+    Sha256(String),
+    /// git blob id, for the small files stored in git.
+    GitSha1(String),
+    None,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FileProgress {
+    pub file: String,
+    pub size: u64,
+    pub downloaded: u64,
+    pub status: FileStatus,
+    pub error: Option<String>,
+    #[serde(skip)]
+    checksum: Checksum,
+}
+
+/// A model being fetched into the hub cache through `POST /api/downloads`.
+#[derive(Clone, Debug, Serialize)]
+pub struct Download {
+    pub model_id: String,
+    /// the commit the files are downloaded from.
+    pub revision: Option<String>,
+    pub status: DownloadStatus,
+    pub files: Vec<FileProgress>,
+    pub error: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadRequest {
+    pub model_id: String,
+    /// files of the repo to fetch, by default its json and safetensors files.
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedModel {
+    pub model_id: String,
+    pub size_bytes: u64,
+    pub revisions: Vec<String>,
+    pub download: Option<Download>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoInfo {
+    sha: String,
+    siblings: Vec<Sibling>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sibling {
+    rfilename: String,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default, rename = "blobId")]
+    blob_id: Option<String>,
+    #[serde(default)]
+    lfs: Option<Lfs>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Lfs {
+    sha256: String,
+    size: u64,
+}
+
+fn unauthorized() -> (StatusCode, String) {
+    (
+        StatusCode::UNAUTHORIZED,
+        String::from("Only administrators can manage downloads."),
+    )
+}
+
+fn update_download(model_id: &str, f: impl FnOnce(&mut Download)) {
+    if let Some(mut download) = DOWNLOADS.get_mut(model_id) {
+        f(download.value_mut());
+    }
+}
+
+fn update_file(model_id: &str, index: usize, f: impl FnOnce(&mut FileProgress)) {
+    update_download(model_id, |download| {
+        if let Some(file) = download.files.get_mut(index) {
+            f(file);
+        }
+    });
+}
+
+/// The mirror of server.config, then `HF_ENDPOINT`, then the hub itself.
+async fn endpoint() -> String {
+    get_download_config()
+        .await
+        .mirror
+        .or_else(|| std::env::var("HF_ENDPOINT").ok())
+        .unwrap_or_else(|| DEFAULT_ENDPOINT.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+fn repo_dir(cache: &Cache, model_id: &str) -> PathBuf {
+    cache.path().join(Repo::model(model_id.to_string()).folder_name())
+}
+
+fn authorize(cache: &Cache, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match cache.token() {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+fn selected(sibling: &Sibling, files: &[String]) -> bool {
+    if files.is_empty() {
+        !sibling.rfilename.contains('/')
+            && (sibling.rfilename.ends_with(".json") || sibling.rfilename.ends_with(".safetensors"))
+    } else {
+        files.contains(&sibling.rfilename)
+    }
+}
+
+async fn run_download(model_id: String, files: Vec<String>) {
+    let result = fetch_model(&endpoint().await, &Cache::default(), model_id.as_str(), &files).await;
+    update_download(model_id.as_str(), |download| {
+        download.finished_at = Some(Utc::now().timestamp_millis());
+        match result {
+            Ok(()) if download.files.iter().all(|f| f.status == FileStatus::Done) => {
+                download.status = DownloadStatus::Succeeded;
+            }
+            Ok(()) => {
+                download.status = DownloadStatus::Failed;
+                download.error = Some(String::from("Some files failed to download."));
+            }
+            Err(e) => {
+                download.status = DownloadStatus::Failed;
+                download.error = Some(e.to_string());
+            }
+        }
+    });
+    println!("download of {} finished", model_id);
+}
+
+/// Downloads the `files` of `model_id` from the hub at `endpoint` into `cache`.
+async fn fetch_model(endpoint: &str, cache: &Cache, model_id: &str, files: &[String]) -> Result<()> {
+    let client = reqwest::Client::new();
+    let info: RepoInfo = authorize(cache, client.get(format!(
+        "{}/api/models/{}/revision/main?blobs=true",
+        endpoint, model_id
+    )))
+    .send()
+    .await?
+    .error_for_status()?
+    .json()
+    .await?;
+    let progress: Vec<FileProgress> = info
+        .siblings
+        .iter()
+        .filter(|sibling| selected(sibling, files))
+        .map(|sibling| FileProgress {
+            file: sibling.rfilename.clone(),
+            size: sibling
+                .lfs
+                .as_ref()
+                .map(|lfs| lfs.size)
+                .or(sibling.size)
+                .unwrap_or(0),
+            downloaded: 0,
+            status: FileStatus::Pending,
+            error: None,
+            checksum: match (&sibling.lfs, &sibling.blob_id) {
+                (Some(lfs), _) => Checksum::Sha256(lfs.sha256.clone()),
+                (None, Some(blob_id)) => Checksum::GitSha1(blob_id.clone()),
+                (None, None) => Checksum::None,
+            },
+        })
+        .collect();
+    if progress.is_empty() {
+        anyhow::bail!("{} has none of the requested files", model_id);
+    }
+    update_download(model_id, |download| {
+        download.revision = Some(info.sha.clone());
+        download.files = progress.clone();
+    });
+
+    // the same layout hf_hub reads, so workers find the files offline.
+    let repo_dir = repo_dir(cache, model_id);
+    tokio::fs::create_dir_all(repo_dir.join("refs")).await?;
+    tokio::fs::write(repo_dir.join("refs").join("main"), info.sha.as_bytes()).await?;
+    let snapshot = repo_dir.join("snapshots").join(info.sha.as_str());
+    for (index, file) in progress.iter().enumerate() {
+        let url = format!("{}/{}/resolve/{}/{}", endpoint, model_id, info.sha, file.file);
+        let request = authorize(cache, client.get(url.as_str()));
+        if let Err(e) = fetch_file(request, model_id, index, file, &snapshot).await {
+            println!("download of {} {} failed: {}", model_id, file.file, e);
+            update_file(model_id, index, |f| {
+                f.status = FileStatus::Failed;
+                f.error = Some(e.to_string());
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Downloads into `<file>.incomplete`, continuing what an earlier attempt
+/// left there, and moves the file in place once its checksum matches.
+async fn fetch_file(
+    mut request: reqwest::RequestBuilder,
+    model_id: &str,
+    index: usize,
+    file: &FileProgress,
+    snapshot: &std::path::Path,
+) -> Result<()> {
+    let path = snapshot.join(file.file.as_str());
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    if path.is_file() {
+        update_file(model_id, index, |f| f.status = FileStatus::Verifying);
+        if verify(path.clone(), file.checksum.clone()).await? {
+            let size = tokio::fs::metadata(&path).await?.len();
+            update_file(model_id, index, |f| {
+                f.downloaded = size;
+                f.status = FileStatus::Done;
+            });
+            return Ok(());
+        }
+        println!("{} of {} does not match its checksum, downloading it again", file.file, model_id);
+        tokio::fs::remove_file(&path).await?;
+    }
+
+    let partial = snapshot.join(format!("{}.incomplete", file.file));
+    let mut offset = match tokio::fs::metadata(&partial).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    if file.size == 0 || offset < file.size {
+        if offset > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+        }
+        let response = request.send().await?.error_for_status()?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            offset = 0;
+        }
+        let mut out = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(offset > 0)
+            .truncate(offset == 0)
+            .open(&partial)
+            .await?;
+        update_file(model_id, index, |f| {
+            f.downloaded = offset;
+            f.status = FileStatus::Downloading;
+        });
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            out.write_all(&chunk).await?;
+            update_file(model_id, index, |f| f.downloaded += chunk.len() as u64);
+        }
+        out.flush().await?;
+    }
+
+    update_file(model_id, index, |f| f.status = FileStatus::Verifying);
+    if !verify(partial.clone(), file.checksum.clone()).await? {
+        tokio::fs::remove_file(&partial).await?;
+        anyhow::bail!("checksum mismatch");
+    }
+    tokio::fs::rename(&partial, &path).await?;
+    update_file(model_id, index, |f| f.status = FileStatus::Done);
+    Ok(())
+}
+
+async fn verify(path: PathBuf, checksum: Checksum) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut buffer = vec![0u8; 1 << 20];
+        let digest = match &checksum {
+            Checksum::None => return Ok(true),
+            Checksum::Sha256(_) => {
+                let mut hasher = Sha256::new();
+                loop {
+                    let n = file.read(&mut buffer)?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..n]);
+                }
+                format!("{:x}", hasher.finalize())
+            }
+            Checksum::GitSha1(_) => {
+                let mut hasher = Sha1::new();
+                hasher.update(format!("blob {}\0", file.metadata()?.len()).as_bytes());
+                loop {
+                    let n = file.read(&mut buffer)?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..n]);
+                }
+                format!("{:x}", hasher.finalize())
+            }
+        };
+        let expected = match checksum {
+            Checksum::Sha256(expected) | Checksum::GitSha1(expected) => expected,
+            Checksum::None => unreachable!(),
+        };
+        Ok(digest == expected)
+    })
+    .await
+    .map_err(Error::new)?
+}
+
+/// Bytes of the files under `path`, not following the snapshot links.
+fn dir_size(path: &PathBuf) -> u64 {
+    let mut size = 0;
+    if let Ok(entries) = std::fs::read_dir(path) {
+        for entry in entries.flatten() {
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => size += dir_size(&entry.path()),
+                Ok(metadata) if metadata.is_file() => size += metadata.len(),
+                _ => {}
+            }
+        }
+    }
+    size
+}
+
+/// The models in `cache`, with the downloads that have not written anything yet.
+fn cached_models(cache: &Cache) -> Vec<CachedModel> {
+    let mut models = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(cache.path()) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let model_id = match name.strip_prefix("models--") {
+                Some(model_id) => model_id.replacen("--", "/", 1),
+                None => continue,
+            };
+            let revisions = std::fs::read_dir(entry.path().join("snapshots"))
+                .map(|snapshots| {
+                    snapshots
+                        .flatten()
+                        .map(|s| s.file_name().to_string_lossy().to_string())
+                        .collect()
+                })
+                .unwrap_or_default();
+            models.push(CachedModel {
+                download: DOWNLOADS.get(model_id.as_str()).map(|d| d.value().clone()),
+                model_id,
+                size_bytes: dir_size(&entry.path()),
+                revisions,
+            });
+        }
+    }
+    for download in DOWNLOADS.iter() {
+        if !models.iter().any(|m| m.model_id == download.model_id) {
+            models.push(CachedModel {
+                model_id: download.model_id.clone(),
+                size_bytes: 0,
+                revisions: Vec::new(),
+                download: Some(download.value().clone()),
+            });
+        }
+    }
+    models.sort_by(|a, b| a.model_id.cmp(&b.model_id));
+    models
+}
+
+/// Starts tracking a download of `model_id`, unless one is running already.
+fn register(model_id: &str) -> Result<Download, (StatusCode, String)> {
+    let download = Download {
+        model_id: model_id.to_string(),
+        revision: None,
+        status: DownloadStatus::Running,
+        files: Vec::new(),
+        error: None,
+        started_at: Utc::now().timestamp_millis(),
+        finished_at: None,
+    };
+    // checked and inserted under the lock of the entry, so two requests can
+    // not both start the download.
+    match DOWNLOADS.entry(model_id.to_string()) {
+        Entry::Occupied(entry) if entry.get().status == DownloadStatus::Running => Err((
+            StatusCode::CONFLICT,
+            format!("{} is already downloading.", model_id),
+        )),
+        Entry::Occupied(mut entry) => {
+            entry.insert(download.clone());
+            Ok(download)
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(download.clone());
+            Ok(download)
+        }
+    }
+}
+
+/// Removes `model_id` from `cache`, returning the directory it was in.
+async fn remove_cached(cache: &Cache, model_id: &str) -> Result<PathBuf, (StatusCode, String)> {
+    if let Some(download) = DOWNLOADS.get(model_id) {
+        if download.status == DownloadStatus::Running {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("{} is still downloading.", model_id),
+            ));
+        }
+    }
+    let repo_dir = repo_dir(cache, model_id);
+    if !repo_dir.is_dir() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("{} is not in the cache.", model_id),
+        ));
+    }
+    tokio::fs::remove_dir_all(&repo_dir)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    DOWNLOADS.remove(model_id);
+    Ok(repo_dir)
+}
+
+pub async fn start_download(
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<DownloadRequest>,
+) -> Result<(StatusCode, Json<Download>), (StatusCode, String)> {
+    if !valid_admin_token(token.as_str()) {
+        return Err(unauthorized());
+    }
+    let download = register(request.model_id.as_str())?;
+    tokio::spawn(run_download(request.model_id, request.files));
+    Ok((StatusCode::ACCEPTED, Json::from(download)))
+}
+
+pub async fn list_downloads(
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<CachedModel>>, (StatusCode, String)> {
+    if !valid_admin_token(token.as_str()) {
+        return Err(unauthorized());
+    }
+    Ok(Json::from(cached_models(&Cache::default())))
+}
+
+pub async fn get_download(
+    AuthBearer(token): AuthBearer,
+    Path(model_id): Path<String>,
+) -> Result<Json<Download>, (StatusCode, String)> {
+    if !valid_admin_token(token.as_str()) {
+        return Err(unauthorized());
+    }
+    match DOWNLOADS.get(model_id.as_str()) {
+        Some(download) => Ok(Json::from(download.value().clone())),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            format!("{} is not downloading.", model_id),
+        )),
+    }
+}
+
+pub async fn delete_cached_model(
+    AuthBearer(token): AuthBearer,
+    Path(model_id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !valid_admin_token(token.as_str()) {
+        return Err(unauthorized());
+    }
+    let repo_dir = remove_cached(&Cache::default(), model_id.as_str()).await?;
+    println!("deleted {:?}", repo_dir);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::State,
+        http::{header, HeaderMap, Uri},
+        response::{IntoResponse, Response},
+        Router,
+    };
+    use std::sync::{Arc, Mutex};
+
+    const SHA: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    /// One repo served the way huggingface.co does, Range requests included.
+    struct Hub {
+        model_id: String,
+        files: Vec<(String, Vec<u8>)>,
+        /// a file whose content does not match what the repo info says.
+        corrupt: Option<String>,
+        /// the Range headers of the requests for files.
+        ranges: Mutex<Vec<String>>,
+    }
+
+    fn weights() -> Vec<u8> {
+        (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn git_sha1(content: &[u8]) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(format!("blob {}\0", content.len()).as_bytes());
+        hasher.update(content);
+        format!("{:x}", hasher.finalize())
+    }
+
+    async fn serve_hub(State(hub): State<Arc<Hub>>, uri: Uri, headers: HeaderMap) -> Response {
+        let path = uri.path();
+        if path == format!("/api/models/{}/revision/main", hub.model_id) {
+            let siblings: Vec<serde_json::Value> = hub
+                .files
+                .iter()
+                .map(|(name, content)| match name.ends_with(".safetensors") {
+                    true => serde_json::json!({
+                        "rfilename": name,
+                        "size": content.len(),
+                        "blobId": git_sha1(b"a git lfs pointer"),
+                        "lfs": { "sha256": format!("{:x}", Sha256::digest(content)), "size": content.len() }
+                    }),
+                    false => serde_json::json!({
+                        "rfilename": name,
+                        "size": content.len(),
+                        "blobId": git_sha1(content)
+                    }),
+                })
+                .collect();
+            return Json(serde_json::json!({ "sha": SHA, "siblings": siblings })).into_response();
+        }
+        let prefix = format!("/{}/resolve/{}/", hub.model_id, SHA);
+        let (name, content) = match path
+            .strip_prefix(prefix.as_str())
+            .and_then(|file| hub.files.iter().find(|(name, _)| name == file))
+        {
+            Some(file) => file,
+            None => return StatusCode::NOT_FOUND.into_response(),
+        };
+        let mut content = content.clone();
+        if hub.corrupt.as_ref() == Some(name) {
+            content[0] ^= 0xff;
+        }
+        let range = headers.get(header::RANGE).and_then(|range| range.to_str().ok());
+        let start = match range.and_then(|range| range.strip_prefix("bytes=")?.strip_suffix('-')?.parse::<usize>().ok()) {
+            Some(start) => start,
+            None => return content.into_response(),
+        };
+        hub.ranges.lock().unwrap().push(range.unwrap_or_default().to_string());
+        let content_range = format!("bytes {}-{}/{}", start, content.len() - 1, content.len());
+        (
+            StatusCode::PARTIAL_CONTENT,
+            [(header::CONTENT_RANGE, content_range)],
+            content[start..].to_vec(),
+        )
+            .into_response()
+    }
+
+    /// Serves a repo of a config, weights and a readme, returning the endpoint.
+    async fn start_hub(model_id: &str, corrupt: Option<&str>) -> (String, Arc<Hub>) {
+        let hub = Arc::new(Hub {
+            model_id: model_id.to_string(),
+            files: vec![
+                ("config.json".to_string(), br#"{"hidden_size": 16}"#.to_vec()),
+                ("model.safetensors".to_string(), weights()),
+                ("README.md".to_string(), b"# not downloaded".to_vec()),
+            ],
+            corrupt: corrupt.map(str::to_string),
+            ranges: Mutex::new(Vec::new()),
+        });
+        let app = Router::new().fallback(serve_hub).with_state(hub.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{}", addr), hub)
+    }
+
+    fn temp_cache(name: &str) -> Cache {
+        let path = std::env::temp_dir().join(format!("downloads_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        Cache::new(path)
+    }
+
+    fn file_progress(model_id: &str, file: &str) -> FileProgress {
+        let download = DOWNLOADS.get(model_id).unwrap();
+        download.files.iter().find(|f| f.file == file).unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn downloads_verifies_lists_and_deletes_a_model() {
+        let model_id = "org/verified";
+        let (endpoint, hub) = start_hub(model_id, None).await;
+        let cache = temp_cache("verified");
+        register(model_id).unwrap();
+        fetch_model(&endpoint, &cache, model_id, &[]).await.unwrap();
+
+        let download = DOWNLOADS.get(model_id).unwrap().clone();
+        assert_eq!(download.revision.as_deref(), Some(SHA));
+        assert_eq!(download.files.len(), 2);
+        for file in &download.files {
+            assert_eq!(file.status, FileStatus::Done, "{}", file.file);
+            assert_eq!(file.downloaded, file.size);
+        }
+        assert_eq!(file_progress(model_id, "model.safetensors").size, weights().len() as u64);
+        let repo = repo_dir(&cache, model_id);
+        assert_eq!(std::fs::read_to_string(repo.join("refs/main")).unwrap(), SHA);
+        let snapshot = repo.join("snapshots").join(SHA);
+        assert_eq!(std::fs::read(snapshot.join("model.safetensors")).unwrap(), weights());
+        assert!(!snapshot.join("README.md").exists());
+        assert!(hub.ranges.lock().unwrap().is_empty());
+
+        let models = cached_models(&cache);
+        let model = models.iter().find(|m| m.model_id == model_id).unwrap();
+        assert_eq!(model.revisions, vec![SHA.to_string()]);
+        assert!(model.size_bytes >= weights().len() as u64);
+
+        // a finished download can be deleted, once.
+        update_download(model_id, |download| download.status = DownloadStatus::Succeeded);
+        assert_eq!(remove_cached(&cache, model_id).await.unwrap(), repo);
+        assert!(!repo.exists());
+        assert!(DOWNLOADS.get(model_id).is_none());
+        assert_eq!(remove_cached(&cache, model_id).await.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_incomplete_file() {
+        let model_id = "org/resumed";
+        let (endpoint, hub) = start_hub(model_id, None).await;
+        let cache = temp_cache("resumed");
+        let snapshot = repo_dir(&cache, model_id).join("snapshots").join(SHA);
+        std::fs::create_dir_all(&snapshot).unwrap();
+        std::fs::write(snapshot.join("model.safetensors.incomplete"), &weights()[..40_000]).unwrap();
+        register(model_id).unwrap();
+        fetch_model(&endpoint, &cache, model_id, &["model.safetensors".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!(*hub.ranges.lock().unwrap(), vec!["bytes=40000-".to_string()]);
+        let file = file_progress(model_id, "model.safetensors");
+        assert_eq!((file.status, file.downloaded), (FileStatus::Done, weights().len() as u64));
+        assert_eq!(std::fs::read(snapshot.join("model.safetensors")).unwrap(), weights());
+        assert!(!snapshot.join("model.safetensors.incomplete").exists());
+        std::fs::remove_dir_all(cache.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_checksum_mismatch_fails_the_file() {
+        let model_id = "org/corrupt";
+        let (endpoint, _hub) = start_hub(model_id, Some("model.safetensors")).await;
+        let cache = temp_cache("corrupt");
+        register(model_id).unwrap();
+        fetch_model(&endpoint, &cache, model_id, &[]).await.unwrap();
+
+        let file = file_progress(model_id, "model.safetensors");
+        assert_eq!(file.status, FileStatus::Failed);
+        assert_eq!(file.error.as_deref(), Some("checksum mismatch"));
+        assert_eq!(file_progress(model_id, "config.json").status, FileStatus::Done);
+        let snapshot = repo_dir(&cache, model_id).join("snapshots").join(SHA);
+        assert!(!snapshot.join("model.safetensors").exists());
+        assert!(!snapshot.join("model.safetensors.incomplete").exists());
+        std::fs::remove_dir_all(cache.path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_running_download_is_started_once() {
+        let model_id = "org/raced";
+        let started: Vec<bool> = (0..8)
+            .map(|_| std::thread::spawn(move || register(model_id).is_ok()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        assert_eq!(started.iter().filter(|started| **started).count(), 1);
+        let cache = temp_cache("raced");
+        assert_eq!(remove_cached(&cache, model_id).await.unwrap_err().0, StatusCode::CONFLICT);
+        // once it is over it can start again.
+        update_download(model_id, |download| download.status = DownloadStatus::Failed);
+        assert!(register(model_id).is_ok());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::data::{Embeddings, Request};
+use crate::ipc::IpcMessage;
+use crate::master_server::{dispatch, valid_token};
+use axum::{http::StatusCode, Json};
+use axum_auth::AuthBearer;
+use serde::{Deserialize, Serialize};
+
+/// `input` of an OpenAI embeddings request, either one text or a batch.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbeddingData {
+    pub object: String,
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+pub async fn create_embeddings(
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<EmbeddingRequest>,
+) -> Result<Json<EmbeddingResponse>, (StatusCode, String)> {
+    if !valid_token(token.as_str()) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            String::from("Authentication failed. Please sign in first."),
+        ));
+    }
+    let input = match request.input {
+        EmbeddingInput::Single(text) => vec![text],
+        EmbeddingInput::Batch(texts) => texts,
+    };
+    let req = Request {
+        cmd: "embed".to_string(),
+        input,
+        ..Default::default()
+    };
+    // what goes wrong once the worker has the request is the worker's fault.
+    let mut rx = dispatch(request.model.as_str(), req).await?;
+    let mut reply = String::new();
+    while let Some(message) = rx.recv().await {
+        match message {
+            IpcMessage::Token { text, .. } => reply.push_str(text.as_str()),
+            IpcMessage::Error { message, .. } => return Err((StatusCode::BAD_GATEWAY, message)),
+            _ => {}
+        }
+    }
+    let embeddings = serde_json::from_str::<Embeddings>(reply.as_str())
+        .map_err(|e| (StatusCode::BAD_GATEWAY, format!("Invalid reply from the worker: {}", e)))?;
+    let data = embeddings
+        .embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| EmbeddingData {
+            object: String::from("embedding"),
+            index,
+            embedding,
+        })
+        .collect();
+    Ok(Json::from(EmbeddingResponse {
+        object: String::from("list"),
+        data,
+        model: request.model,
+        usage: EmbeddingUsage {
+            prompt_tokens: embeddings.prompt_tokens,
+            total_tokens: embeddings.prompt_tokens,
+        },
+    }))
+}
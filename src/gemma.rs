@@ -0,0 +1,74 @@
+use anyhow::Result;
+use candle_transformers::models::gemma::Config;
+
+use crate::data::{Message, Role};
+use crate::generation::{ChatModel, Generator};
+use crate::model::TextGenModel;
+use crate::registry::{select_device, LoadOptions, ModelFiles};
+use crate::transformer::{self, Layout, Rope, Transformer, TransformerDecoder};
+
+pub(crate) fn transformer_config(config: &Config) -> Result<transformer::Config> {
+    let hidden_act = match (config.hidden_act, config.hidden_activation) {
+        (None, Some(act)) | (Some(act), None) => act,
+        (Some(_), Some(_)) => anyhow::bail!("both hidden_act and hidden_activation are set"),
+        (None, None) => anyhow::bail!("none of hidden_act and hidden_activation are set"),
+    };
+    Ok(transformer::Config {
+        layout: Layout::Gemma,
+        vocab_size: config.vocab_size,
+        hidden_size: config.hidden_size,
+        intermediate_size: config.intermediate_size,
+        num_hidden_layers: config.num_hidden_layers,
+        num_attention_heads: config.num_attention_heads,
+        num_key_value_heads: config.num_key_value_heads,
+        head_dim: config.head_dim,
+        rms_norm_eps: config.rms_norm_eps,
+        hidden_act,
+        rope: Rope::new(config.head_dim, config.rope_theta),
+        sliding_window: None,
+        attention_bias: config.attention_bias,
+        tie_word_embeddings: true,
+    })
+}
+
+/// Used when the model ships without a chat template. Gemma has no system
+/// role, the system prompt goes before the first user turn.
+fn gemma_chat_template(msg_list: &[Message], system_prompt: &str) -> String {
+    let mut history = String::new();
+    for (i, msg) in msg_list.iter().enumerate() {
+        if msg.role == Role::User {
+            history.push_str("<start_of_turn>user\n");
+            if i == 0 && !system_prompt.is_empty() {
+                history.push_str(system_prompt);
+                history.push_str("\n\n");
+            }
+        } else {
+            history.push_str("<start_of_turn>model\n");
+        }
+        history.push_str(msg.content.as_str());
+        history.push_str("<end_of_turn>\n");
+    }
+    history.push_str("<start_of_turn>model\n");
+    history
+}
+
+pub fn load(files: &ModelFiles, options: &LoadOptions) -> Result<Box<dyn TextGenModel>> {
+    let (device, dtype) = select_device(options)?;
+    let config: Config = files.config()?;
+    let model = Transformer::load(files.var_builder(dtype, &device)?, &transformer_config(&config)?)?;
+    let tokenizer = files.tokenizer()?;
+    let eos_tokens = files.eos_tokens(&tokenizer, &["<end_of_turn>"])?;
+    let generator = Generator::new(
+        TransformerDecoder::new(model),
+        tokenizer,
+        eos_tokens,
+        options.temp,
+        options.top_p,
+        1.1f32,
+        64usize,
+        config.max_position_embeddings,
+    )
+    .with_chat_template(files.chat_template(options)?)
+    .with_sampling(options.sampling.clone());
+    Ok(Box::new(ChatModel::new(generator, gemma_chat_template)))
+}
@@ -0,0 +1,858 @@
+use anyhow::{Error, Result};
+use candle_core::{DType, Tensor};
+use candle_transformers::generation::LogitsProcessor;
+use std::any::Any;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokenizers::Tokenizer;
+
+use crate::chat_template::ChatTemplate;
+use crate::constraint::{ConstraintState, Vocabulary};
+use crate::data::{FinishReason, Message, SamplingParams, TokenLogprob, Tool, TopLogprob, Usage};
+use crate::ipc::OutputStream;
+use crate::model::{choice_params, logits_processor, Sequence, TextGenModel, UsageMeter, DEFAULT_MAX_TOKENS};
+use crate::token_output_stream::TokenOutputStream;
+use crate::tools::inline_tools;
+
+/// The forward pass of a causal language model together with its kv cache.
+/// Cloning a decoder shares the weights, so every sequence can own one.
+pub trait Decoder: Clone {
+    /// Feeds `tokens` starting at position `pos`, past which the kv cache is
+    /// dropped, and returns the logits of the last one.
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor>;
+
+    /// Feeds `tokens` in one forward and returns the logits of each of them,
+    /// a row per token.
+    fn forward_all(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor>;
+
+    /// Feeds a token to each of `decoders`, at its own position, in one
+    /// forward and returns their logits, a row per decoder.
+    fn forward_batch(decoders: &mut [&mut Self], tokens: &[u32], positions: &[usize]) -> Result<Tensor>;
+
+    /// Drops the kv cache past the first `len` tokens.
+    fn truncate(&mut self, len: usize) -> Result<()>;
+}
+
+/// A decoder of any architecture, so a model can take a draft of another.
+pub trait DraftDecoder {
+    fn draft_forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor>;
+    fn draft_truncate(&mut self, len: usize) -> Result<()>;
+    fn boxed_clone(&self) -> Box<dyn DraftDecoder>;
+}
+
+impl<D: Decoder + 'static> DraftDecoder for D {
+    fn draft_forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+        self.forward(tokens, pos)
+    }
+
+    fn draft_truncate(&mut self, len: usize) -> Result<()> {
+        self.truncate(len)
+    }
+
+    fn boxed_clone(&self) -> Box<dyn DraftDecoder> {
+        Box::new(self.clone())
+    }
+}
+
+/// A small model proposing tokens for a bigger one with the same tokenizer.
+pub struct Draft {
+    decoder: Box<dyn DraftDecoder>,
+    vocab_size: usize,
+}
+
+struct CachedPrompt<D> {
+    session_id: String,
+    tokens: Vec<u32>,
+    decoder: D,
+}
+
+/// Conversations whose decoders the prompt cache keeps.
+const PROMPT_CACHE_SIZE: usize = 4;
+
+/// Keeps the decoders of the last finished conversations, so their next turn
+/// only prefills what is new.
+pub struct PromptCache<D> {
+    /// the most recently used first.
+    entries: Mutex<VecDeque<CachedPrompt<D>>>,
+}
+
+impl<D: Decoder> Default for PromptCache<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: Decoder> PromptCache<D> {
+    pub fn new() -> Self {
+        PromptCache {
+            entries: Mutex::new(VecDeque::with_capacity(PROMPT_CACHE_SIZE)),
+        }
+    }
+
+    /// Takes the decoder of the conversation with the number of leading
+    /// `prompt` tokens its kv cache holds, cut back to what the prompt shares
+    /// with it.
+    pub fn take(&self, session_id: Option<&str>, prompt: &[u32]) -> Option<(D, usize)> {
+        let session_id = session_id?;
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries
+            .iter()
+            .position(|cached| cached.session_id == session_id)?;
+        let mut cached = entries.remove(index)?;
+        let common = cached
+            .tokens
+            .iter()
+            .zip(prompt.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // one token at least must be fed to get the next logits.
+        let common = common.min(prompt.len().saturating_sub(1));
+        if common == 0 {
+            return None;
+        }
+        cached.decoder.truncate(common).ok()?;
+        Some((cached.decoder, common))
+    }
+
+    /// Keeps `decoder`, whose kv cache holds `tokens`, evicting the least
+    /// recently used conversation when full.
+    pub fn put(&self, session_id: Option<&str>, tokens: &[u32], decoder: D) {
+        if let Some(session_id) = session_id {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|cached| cached.session_id != session_id);
+            entries.truncate(PROMPT_CACHE_SIZE - 1);
+            entries.push_front(CachedPrompt {
+                session_id: session_id.to_string(),
+                tokens: tokens.to_vec(),
+                decoder,
+            });
+        }
+    }
+}
+
+/// Everything a model needs to start sequences, shared by the candle models.
+pub struct Generator<D> {
+    /// never run, so its kv cache stays empty.
+    decoder: D,
+    prompt_cache: Arc<PromptCache<D>>,
+    tokenizer: Tokenizer,
+    eos_tokens: Vec<u32>,
+    temp: f64,
+    top_p: f64,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    max_context: usize,
+    chat_template: Option<ChatTemplate>,
+    /// what requests leave unset, from the worker config.
+    defaults: SamplingParams,
+    /// proposes `draft_tokens` tokens per step.
+    draft: Option<Draft>,
+    draft_tokens: usize,
+    /// read from the tokenizer by the first constrained request.
+    vocabulary: OnceLock<Arc<Vocabulary>>,
+}
+
+impl<D: Decoder + 'static> Generator<D> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        decoder: D,
+        tokenizer: Tokenizer,
+        eos_tokens: Vec<u32>,
+        temp: f64,
+        top_p: f64,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        max_context: usize,
+    ) -> Self {
+        Generator {
+            decoder,
+            prompt_cache: Arc::new(PromptCache::new()),
+            tokenizer,
+            eos_tokens,
+            temp,
+            top_p,
+            repeat_penalty,
+            repeat_last_n,
+            max_context,
+            chat_template: None,
+            defaults: SamplingParams::default(),
+            draft: None,
+            draft_tokens: 0,
+            vocabulary: OnceLock::new(),
+        }
+    }
+
+    pub fn set_draft(&mut self, draft: Draft, k: usize) -> Result<(), Error> {
+        let vocab_size = self.tokenizer.get_vocab_size(true);
+        if draft.vocab_size != vocab_size {
+            anyhow::bail!(
+                "the draft model has {} tokens in its vocabulary, the model {}",
+                draft.vocab_size,
+                vocab_size
+            );
+        }
+        self.draft = Some(draft);
+        self.draft_tokens = k;
+        Ok(())
+    }
+
+    pub fn into_draft(self) -> Draft {
+        Draft {
+            vocab_size: self.tokenizer.get_vocab_size(true),
+            decoder: Box::new(self.decoder),
+        }
+    }
+
+    pub fn with_chat_template(mut self, chat_template: Option<ChatTemplate>) -> Self {
+        self.chat_template = chat_template;
+        self
+    }
+
+    pub fn with_sampling(mut self, defaults: SamplingParams) -> Self {
+        self.defaults = defaults;
+        self
+    }
+
+    pub fn start(
+        &self,
+        prompt: &str,
+        params: &SamplingParams,
+        session_id: Option<&str>,
+    ) -> Result<Box<dyn Sequence>, Error> {
+        let mut sequences = self.start_n(prompt, params, session_id, 1)?;
+        Ok(sequences.remove(0))
+    }
+
+    /// Starts `n` replies to `prompt`. The prompt is fed once and the replies
+    /// go on from copies of the decoder.
+    pub fn start_n(
+        &self,
+        prompt: &str,
+        params: &SamplingParams,
+        session_id: Option<&str>,
+        n: usize,
+    ) -> Result<Vec<Box<dyn Sequence>>, Error> {
+        let params = params.or(&self.defaults);
+        let tokens = self.encode(prompt)?;
+        if tokens.is_empty() {
+            anyhow::bail!("Empty prompts are not supported.")
+        }
+        let sample_len = params
+            .max_tokens
+            .unwrap_or(DEFAULT_MAX_TOKENS)
+            .min(self.max_context.saturating_sub(tokens.len()));
+        let (mut decoder, mut fed) = self
+            .prompt_cache
+            .take(session_id, &tokens)
+            .unwrap_or_else(|| (self.decoder.clone(), 0));
+        // every reply feeds the last prompt token itself for its first logits.
+        if n > 1 && fed + 1 < tokens.len() {
+            decoder.forward(&tokens[fed..tokens.len() - 1], fed)?;
+            fed = tokens.len() - 1;
+        }
+        let mut sequences: Vec<Box<dyn Sequence>> = Vec::with_capacity(n);
+        for index in 0..n {
+            let params = choice_params(&params, index);
+            let constraint = match &params.constraint {
+                Some(constraint) => {
+                    let vocabulary = self
+                        .vocabulary
+                        .get_or_init(|| Arc::new(Vocabulary::new(&self.tokenizer)));
+                    Some(ConstraintState::new(constraint, vocabulary.clone())?)
+                }
+                None => None,
+            };
+            let mut tokenizer = TokenOutputStream::new(self.tokenizer.clone());
+            tokenizer.set_stop_sequences(&params.stop);
+            sequences.push(Box::new(TokenSequence {
+                decoder: Some(decoder.clone()),
+                prompt_cache: self.prompt_cache.clone(),
+                session_id: session_id.map(str::to_string),
+                draft: self.draft.as_ref().map(|draft| DraftState {
+                    decoder: draft.decoder.boxed_clone(),
+                    fed: 0,
+                    k: self.draft_tokens,
+                    proposed: 0,
+                    accepted: 0,
+                }),
+                constraint,
+                top_logprobs: params
+                    .logprobs
+                    .then(|| params.top_logprobs.unwrap_or(0).min(MAX_TOP_LOGPROBS)),
+                tokenizer,
+                sampler: Sampler {
+                    logits_processor: logits_processor(&params, self.temp, self.top_p),
+                    temperature: params.temperature.unwrap_or(self.temp),
+                    repeat_penalty: params.repeat_penalty.unwrap_or(self.repeat_penalty),
+                    repeat_last_n: params.repeat_last_n.unwrap_or(self.repeat_last_n),
+                    frequency_penalty: params.frequency_penalty.unwrap_or(0.),
+                    presence_penalty: params.presence_penalty.unwrap_or(0.),
+                    min_p: params.min_p.unwrap_or(0.),
+                    prompt_len: tokens.len(),
+                },
+                eos_tokens: self.eos_tokens.clone(),
+                meter: UsageMeter::new(tokens.len()),
+                tokens: tokens.clone(),
+                fed,
+                generated: 0,
+                sample_len,
+            }));
+        }
+        Ok(sequences)
+    }
+
+    pub fn count_tokens(&self, text: &str) -> Result<usize, Error> {
+        Ok(self.encode(text)?.len())
+    }
+
+    /// Chat templates write the special tokens themselves, the tokenizer only
+    /// adds them to prompts of the hand-written formats.
+    fn encode(&self, text: &str) -> Result<Vec<u32>, Error> {
+        let add_special_tokens = self.chat_template.is_none();
+        let encoding = self
+            .tokenizer
+            .encode(text, add_special_tokens)
+            .map_err(Error::msg)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    pub fn max_context(&self) -> usize {
+        self.max_context
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    /// Renders the model's own chat template, or `fallback` for models
+    /// without one. Tools a template does not render are described in the
+    /// system prompt.
+    pub fn apply_chat_template(
+        &self,
+        msg_list: &[Message],
+        system_prompt: &str,
+        tools: &[Tool],
+        fallback: fn(&[Message], &str) -> String,
+    ) -> Result<String, Error> {
+        match &self.chat_template {
+            Some(template) if template.supports_tools() => template.apply(msg_list, system_prompt, tools),
+            template => {
+                let (msg_list, system_prompt) = inline_tools(msg_list, system_prompt, tools);
+                match template {
+                    Some(template) => template.apply(&msg_list, &system_prompt, &[]),
+                    None => Ok(fallback(&msg_list, &system_prompt)),
+                }
+            }
+        }
+    }
+}
+
+/// A chat model made of a decoder and the prompt format used when the model
+/// ships without a chat template.
+pub struct ChatModel<D> {
+    generator: Generator<D>,
+    fallback_template: fn(&[Message], &str) -> String,
+}
+
+impl<D: Decoder + 'static> ChatModel<D> {
+    pub fn new(generator: Generator<D>, fallback_template: fn(&[Message], &str) -> String) -> Self {
+        ChatModel {
+            generator,
+            fallback_template,
+        }
+    }
+}
+
+impl<D: Decoder + 'static> TextGenModel for ChatModel<D> {
+    fn start(
+        &mut self,
+        prompt: &str,
+        params: &SamplingParams,
+        session_id: Option<&str>,
+    ) -> Result<Box<dyn Sequence>, Error> {
+        self.generator.start(prompt, params, session_id)
+    }
+
+    fn start_n(
+        &mut self,
+        prompt: &str,
+        params: &SamplingParams,
+        session_id: Option<&str>,
+        n: usize,
+    ) -> Result<Vec<Box<dyn Sequence>>, Error> {
+        self.generator.start_n(prompt, params, session_id, n)
+    }
+
+    fn max_context(&self) -> usize {
+        self.generator.max_context()
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize, Error> {
+        self.generator.count_tokens(text)
+    }
+
+    fn messages_chat_template(&self, msg_list: &[Message], system_prompt: &str, tools: &[Tool]) -> Result<String, Error> {
+        self.generator
+            .apply_chat_template(msg_list, system_prompt, tools, self.fallback_template)
+    }
+
+    fn into_draft(self: Box<Self>) -> Option<Draft> {
+        Some(self.generator.into_draft())
+    }
+
+    fn set_draft(&mut self, draft: Draft, k: usize) -> Result<(), Error> {
+        self.generator.set_draft(draft, k)
+    }
+
+    fn step_batch(
+        &mut self,
+        sequences: &mut [&mut dyn Sequence],
+        outputs: &[&dyn OutputStream],
+    ) -> Vec<Result<Option<Usage>, Error>> {
+        step_batch::<D>(sequences, outputs)
+    }
+}
+
+/// A draft model running along a sequence.
+struct DraftState {
+    decoder: Box<dyn DraftDecoder>,
+    /// number of tokens already in the kv cache of the draft.
+    fed: usize,
+    k: usize,
+    proposed: usize,
+    accepted: usize,
+}
+
+/// One reply being generated, advanced a token per `step`, or several when a
+/// draft model proposes them.
+struct TokenSequence<D> {
+    /// given back to the prompt cache when the sequence finishes.
+    decoder: Option<D>,
+    prompt_cache: Arc<PromptCache<D>>,
+    session_id: Option<String>,
+    draft: Option<DraftState>,
+    constraint: Option<ConstraintState>,
+    /// the number of alternatives to report with the logprob of each token,
+    /// `None` when the request wants no logprobs.
+    top_logprobs: Option<usize>,
+    tokenizer: TokenOutputStream,
+    sampler: Sampler,
+    eos_tokens: Vec<u32>,
+    meter: UsageMeter,
+    tokens: Vec<u32>,
+    /// number of tokens already in the kv cache.
+    fed: usize,
+    generated: usize,
+    sample_len: usize,
+}
+
+/// Most alternatives reported per token, as in the OpenAI API.
+const MAX_TOP_LOGPROBS: usize = 20;
+
+/// Turns the logits of a position into the next token.
+struct Sampler {
+    logits_processor: LogitsProcessor,
+    temperature: f64,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    min_p: f64,
+    /// the frequency and presence penalties count the tokens after the prompt.
+    prompt_len: usize,
+}
+
+impl Sampler {
+    /// Samples the token following `context`, returning the logits it was
+    /// sampled from.
+    fn sample(&mut self, logits: &Tensor, context: &[u32]) -> Result<(u32, Tensor)> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let logits = if self.repeat_penalty == 1. {
+            logits
+        } else {
+            let start_at = context.len().saturating_sub(self.repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(&logits, self.repeat_penalty, &context[start_at..])?
+        };
+        let logits = self.penalize(logits, &context[self.prompt_len.min(context.len())..])?;
+        Ok((self.logits_processor.sample(&logits)?, logits))
+    }
+
+    fn penalize(&self, logits: Tensor, reply: &[u32]) -> Result<Tensor> {
+        let min_p = self.min_p > 0. && self.temperature >= 1e-7;
+        if self.frequency_penalty == 0. && self.presence_penalty == 0. && !min_p {
+            return Ok(logits);
+        }
+        let mut values = logits.to_vec1::<f32>()?;
+        let mut counts = std::collections::HashMap::new();
+        for token in reply {
+            *counts.entry(*token as usize).or_insert(0usize) += 1;
+        }
+        for (token, count) in counts {
+            if let Some(value) = values.get_mut(token) {
+                *value -= self.frequency_penalty * count as f32 + self.presence_penalty;
+            }
+        }
+        if min_p {
+            // p < min_p * p_max once the temperature divides the logits.
+            let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let threshold = max + (self.temperature * self.min_p.ln()) as f32;
+            for value in values.iter_mut().filter(|value| **value < threshold) {
+                *value = f32::NEG_INFINITY;
+            }
+        }
+        Ok(Tensor::new(values, logits.device())?)
+    }
+}
+
+/// The log probability of `token` and of the `top` likeliest tokens under
+/// `logits`, before the temperature is applied.
+fn token_logprob(tokenizer: &Tokenizer, logits: &Tensor, token: u32, top: usize) -> Result<TokenLogprob> {
+    let logprobs = candle_nn::ops::log_softmax(logits, candle_core::D::Minus1)?.to_vec1::<f32>()?;
+    let decode = |id: u32| tokenizer.decode(&[id], false).map_err(Error::msg);
+    let mut ids: Vec<u32> = (0..logprobs.len() as u32).collect();
+    let top = top.min(ids.len());
+    if top > 0 {
+        ids.select_nth_unstable_by(top - 1, |a, b| logprobs[*b as usize].total_cmp(&logprobs[*a as usize]));
+        ids.truncate(top);
+        ids.sort_by(|a, b| logprobs[*b as usize].total_cmp(&logprobs[*a as usize]));
+    }
+    Ok(TokenLogprob {
+        token: decode(token)?,
+        logprob: logprobs[token as usize],
+        top_logprobs: ids[..top]
+            .iter()
+            .map(|id| {
+                Ok(TopLogprob {
+                    token: decode(*id)?,
+                    logprob: logprobs[*id as usize],
+                })
+            })
+            .collect::<Result<_>>()?,
+    })
+}
+
+impl<D: Decoder> TokenSequence<D> {
+    fn finish(
+        &mut self,
+        output: &dyn OutputStream,
+        mut finish_reason: FinishReason,
+    ) -> Result<Option<Usage>, Error> {
+        if finish_reason == FinishReason::Eos || finish_reason == FinishReason::Length {
+            let (rest, stopped) = self.tokenizer.finish()?;
+            if let Some(rest) = rest {
+                if output.write(rest).is_err() {
+                    finish_reason = FinishReason::Cancelled;
+                }
+            }
+            if stopped {
+                finish_reason = FinishReason::Stop;
+            }
+        }
+        if let Some(decoder) = self.decoder.take() {
+            self.prompt_cache
+                .put(self.session_id.as_deref(), &self.tokens[..self.fed], decoder);
+        }
+        if let Some(draft) = self.draft.as_ref().filter(|draft| draft.proposed > 0) {
+            println!(
+                "draft model: {} of {} proposed tokens accepted ({:.1}%)",
+                draft.accepted,
+                draft.proposed,
+                100.0 * draft.accepted as f64 / draft.proposed as f64
+            );
+        }
+        Ok(Some(self.meter.finish(finish_reason)))
+    }
+
+    /// Adds a sampled token to the reply, `Some` once the reply is finished.
+    fn push(
+        &mut self,
+        output: &dyn OutputStream,
+        next_token: u32,
+        logprob: Option<TokenLogprob>,
+    ) -> Result<Option<Usage>, Error> {
+        if let Some(logprob) = logprob {
+            if output.logprob(&logprob).is_err() {
+                return self.finish(output, FinishReason::Cancelled);
+            }
+        }
+        self.meter.token();
+        self.tokens.push(next_token);
+        if let Some(constraint) = self.constraint.as_mut() {
+            constraint.advance(next_token);
+        }
+        self.generated += 1;
+        if self.eos_tokens.contains(&next_token) {
+            return self.finish(output, FinishReason::Eos);
+        }
+        let (text, stopped) = self.tokenizer.next_token_until_stop(next_token)?;
+        if let Some(t) = text {
+            if output.write(t).is_err() {
+                return self.finish(output, FinishReason::Cancelled);
+            }
+        }
+        if stopped {
+            return self.finish(output, FinishReason::Stop);
+        }
+        if self.generated >= self.sample_len {
+            return self.finish(output, FinishReason::Length);
+        }
+        Ok(None)
+    }
+
+    /// The draft proposes `k` tokens greedily and the model scores them in one
+    /// forward. Proposals are kept while they match what the model samples
+    /// itself, so the reply is distributed as without a draft.
+    fn speculate(&mut self, output: &dyn OutputStream, k: usize) -> Result<Option<Usage>, Error> {
+        let (decoder, draft) = match (self.decoder.as_mut(), self.draft.as_mut()) {
+            (Some(decoder), Some(draft)) => (decoder, draft),
+            _ => anyhow::bail!("the sequence is finished"),
+        };
+        let mut logits = draft
+            .decoder
+            .draft_forward(&self.tokens[draft.fed..], draft.fed)?;
+        draft.fed = self.tokens.len();
+        let mut proposals = Vec::with_capacity(k);
+        for i in 0..k {
+            let token = logits.argmax(candle_core::D::Minus1)?.to_scalar::<u32>()?;
+            proposals.push(token);
+            if i + 1 < k {
+                logits = draft.decoder.draft_forward(&[token], draft.fed + i)?;
+            }
+        }
+
+        let unfed = self.tokens.len() - self.fed;
+        let mut input = self.tokens[self.fed..].to_vec();
+        input.extend_from_slice(&proposals);
+        let logits = decoder.forward_all(&input, self.fed)?;
+        let mut context = self.tokens.clone();
+        let mut sampled = Vec::with_capacity(k + 1);
+        let mut logprobs = Vec::with_capacity(k + 1);
+        // the row after the last proposal samples one more token.
+        let proposed = proposals.iter().copied().map(Some).chain([None]);
+        for (i, proposal) in proposed.enumerate() {
+            let (token, row) = self.sampler.sample(&logits.get(unfed - 1 + i)?, &context)?;
+            if let Some(top) = self.top_logprobs {
+                logprobs.push(token_logprob(self.tokenizer.tokenizer(), &row, token, top)?);
+            }
+            sampled.push(token);
+            context.push(token);
+            if proposal != Some(token) || self.eos_tokens.contains(&token) {
+                break;
+            }
+        }
+        let accepted = sampled
+            .iter()
+            .zip(proposals.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        draft.proposed += k;
+        draft.accepted += accepted;
+
+        // rejected proposals are cut from the kv caches, the last sampled
+        // token is fed by the next step.
+        let kept = unfed + sampled.len() - 1;
+        self.fed += kept;
+        decoder.truncate(self.fed)?;
+        draft.fed += accepted.min(k - 1);
+        draft.decoder.draft_truncate(draft.fed)?;
+        let mut logprobs = logprobs.into_iter();
+        for token in sampled {
+            if let Some(usage) = self.push(output, token, logprobs.next())? {
+                return Ok(Some(usage));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<D: Decoder> TokenSequence<D> {
+    /// The token the next step feeds, when that step is a plain decode of
+    /// one token, which a forward can share with other sequences.
+    fn batchable(&self) -> Option<u32> {
+        if self.decoder.is_none() || self.generated >= self.sample_len || self.tokens.len() != self.fed + 1 {
+            return None;
+        }
+        let speculates = self.draft.is_some() && self.constraint.is_none() && self.sample_len - self.generated > 1;
+        (!speculates).then(|| self.tokens[self.fed])
+    }
+
+    /// Samples the next token from `logits`, those of the last fed token.
+    fn advance(&mut self, output: &dyn OutputStream, logits: Tensor) -> Result<Option<Usage>, Error> {
+        let logits = match self.constraint.as_mut() {
+            Some(constraint) => constraint.mask(&logits.to_dtype(DType::F32)?, &self.eos_tokens)?,
+            None => logits,
+        };
+        let (next_token, logits) = self.sampler.sample(&logits, &self.tokens)?;
+        let logprob = match self.top_logprobs {
+            Some(top) => Some(token_logprob(self.tokenizer.tokenizer(), &logits, next_token, top)?),
+            None => None,
+        };
+        self.push(output, next_token, logprob)
+    }
+}
+
+impl<D: Decoder + 'static> Sequence for TokenSequence<D> {
+    fn step(&mut self, output: &dyn OutputStream) -> Result<Option<Usage>, Error> {
+        if self.generated >= self.sample_len {
+            return self.finish(output, FinishReason::Length);
+        }
+        // the prompt is prefilled without the draft, and the last token of
+        // the reply has nothing left to verify. The draft does not know
+        // about constraints, so constrained replies go a token at a time.
+        let remaining = self.sample_len - self.generated;
+        if let (Some(draft), None) = (&self.draft, &self.constraint) {
+            if self.tokens.len() - self.fed == 1 && remaining > 1 {
+                return self.speculate(output, draft.k.min(remaining - 1));
+            }
+        }
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => anyhow::bail!("the sequence is finished"),
+        };
+        let logits = decoder.forward(&self.tokens[self.fed..], self.fed)?;
+        self.fed = self.tokens.len();
+        self.advance(output, logits)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Steps `sequences`, decoding those with one token to feed together in a
+/// `[batch, 1]` forward. The others, prefilling or speculating, step alone.
+fn step_batch<D: Decoder + 'static>(
+    sequences: &mut [&mut dyn Sequence],
+    outputs: &[&dyn OutputStream],
+) -> Vec<Result<Option<Usage>, Error>> {
+    let mut results: Vec<Option<Result<Option<Usage>, Error>>> = sequences.iter().map(|_| None).collect();
+    let batchable: Vec<bool> = sequences
+        .iter_mut()
+        .map(|sequence| {
+            let sequence = sequence.as_any_mut().downcast_mut::<TokenSequence<D>>();
+            sequence.is_some_and(|sequence| sequence.batchable().is_some())
+        })
+        .collect();
+    let mut batch: Vec<(usize, &mut TokenSequence<D>)> = Vec::new();
+    for (index, sequence) in sequences.iter_mut().enumerate() {
+        if batchable[index] {
+            if let Some(sequence) = sequence.as_any_mut().downcast_mut::<TokenSequence<D>>() {
+                batch.push((index, sequence));
+            }
+        } else {
+            results[index] = Some(sequence.step(outputs[index]));
+        }
+    }
+    if batch.len() == 1 {
+        let (index, sequence) = batch.pop().unwrap();
+        results[index] = Some(sequence.step(outputs[index]));
+    }
+    if !batch.is_empty() {
+        let tokens: Vec<u32> = batch.iter().filter_map(|(_, sequence)| sequence.batchable()).collect();
+        let positions: Vec<usize> = batch.iter().map(|(_, sequence)| sequence.fed).collect();
+        let mut decoders: Vec<&mut D> = batch
+            .iter_mut()
+            .filter_map(|(_, sequence)| sequence.decoder.as_mut())
+            .collect();
+        match D::forward_batch(&mut decoders, &tokens, &positions) {
+            Ok(logits) => {
+                for (row, (index, sequence)) in batch.into_iter().enumerate() {
+                    sequence.fed += 1;
+                    let result = logits
+                        .get(row)
+                        .map_err(Error::from)
+                        .and_then(|logits| sequence.advance(outputs[index], logits));
+                    results[index] = Some(result);
+                }
+            }
+            Err(e) => {
+                for (index, _) in batch {
+                    results[index] = Some(Err(anyhow::anyhow!("{}", e)));
+                }
+            }
+        }
+    }
+    results.into_iter().map(|result| result.unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records how many tokens its kv cache holds.
+    #[derive(Clone, Default)]
+    struct FakeDecoder {
+        len: usize,
+    }
+
+    impl Decoder for FakeDecoder {
+        fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+            self.len = pos + tokens.len();
+            Ok(Tensor::zeros(4, DType::F32, &candle_core::Device::Cpu)?)
+        }
+
+        fn forward_all(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+            self.len = pos + tokens.len();
+            Ok(Tensor::zeros((tokens.len(), 4), DType::F32, &candle_core::Device::Cpu)?)
+        }
+
+        fn forward_batch(decoders: &mut [&mut Self], _tokens: &[u32], positions: &[usize]) -> Result<Tensor> {
+            for (decoder, pos) in decoders.iter_mut().zip(positions) {
+                decoder.len = pos + 1;
+            }
+            Ok(Tensor::zeros((decoders.len(), 4), DType::F32, &candle_core::Device::Cpu)?)
+        }
+
+        fn truncate(&mut self, len: usize) -> Result<()> {
+            self.len = self.len.min(len);
+            Ok(())
+        }
+    }
+
+    fn put(cache: &PromptCache<FakeDecoder>, session_id: &str, tokens: &[u32]) {
+        cache.put(Some(session_id), tokens, FakeDecoder { len: tokens.len() });
+    }
+
+    #[test]
+    fn prompt_cache_reuses_the_longest_common_prefix() {
+        let cache = PromptCache::new();
+        put(&cache, "a", &[1, 2, 3, 4, 5]);
+        // an edited turn keeps the shared start of the conversation.
+        let (decoder, fed) = cache.take(Some("a"), &[1, 2, 3, 9, 9, 9]).unwrap();
+        assert_eq!((decoder.len, fed), (3, 3));
+        // the same prompt again still feeds its last token.
+        put(&cache, "a", &[1, 2, 3]);
+        let (decoder, fed) = cache.take(Some("a"), &[1, 2, 3]).unwrap();
+        assert_eq!((decoder.len, fed), (2, 2));
+        put(&cache, "a", &[1, 2, 3]);
+        assert!(cache.take(Some("a"), &[7, 8]).is_none());
+        assert!(cache.take(None, &[1, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn prompt_cache_keeps_conversations_apart() {
+        let cache = PromptCache::new();
+        put(&cache, "a", &[1, 2, 3]);
+        put(&cache, "b", &[1, 2, 7]);
+        assert!(cache.take(Some("c"), &[1, 2, 3, 4]).is_none());
+        assert_eq!(cache.take(Some("a"), &[1, 2, 3, 4]).unwrap().1, 3);
+        assert_eq!(cache.take(Some("b"), &[1, 2, 7, 4]).unwrap().1, 3);
+        assert!(cache.take(Some("a"), &[1, 2, 3, 4]).is_none());
+    }
+
+    #[test]
+    fn prompt_cache_evicts_the_least_recently_used() {
+        let cache = PromptCache::new();
+        for session_id in ["a", "b", "c", "d"] {
+            put(&cache, session_id, &[1, 2, 3]);
+        }
+        // "a" is used again, so "b" is the oldest when "e" comes.
+        let (decoder, _) = cache.take(Some("a"), &[1, 2, 3, 4]).unwrap();
+        cache.put(Some("a"), &[1, 2, 3, 4, 5], decoder);
+        put(&cache, "e", &[1, 2, 3]);
+        assert!(cache.take(Some("b"), &[1, 2, 3, 4]).is_none());
+        for session_id in ["a", "c", "d", "e"] {
+            assert!(cache.take(Some(session_id), &[1, 2, 3, 4]).is_some());
+        }
+    }
+}
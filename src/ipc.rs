@@ -1,8 +1,14 @@
 use anyhow::{Error, Result};
-
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use serde::{Deserialize, Serialize};
+use crate::data::{Request, TokenLogprob, ToolCall, Usage};
+use std::cell::Cell;
+use std::time::Duration;
 
 use ipc_channel::ipc::{self, IpcSender, IpcReceiver};
 
+/// Connects a worker to the master, and keeps sending heartbeats until the
+/// master is gone.
 pub fn accept(ipc_name: String) -> (IpcReceiver<String>, IpcSender<String>) {
     let (client_sender, receiver): (IpcSender<String>, IpcReceiver<String>) = ipc::channel().unwrap();
     let connector = IpcSender::connect(ipc_name.clone()).expect(format!("Failed to connect {}",ipc_name).as_str());
@@ -11,24 +17,393 @@ pub fn accept(ipc_name: String) -> (IpcReceiver<String>, IpcSender<String>) {
     let client_name = receiver.recv().expect("Failed to recv!");
     let connector = IpcSender::connect(client_name.clone()).expect(format!("Failed to connect client: {}",client_name).as_str());
     connector.send(client_receiver).expect("Failed to send client receive");
+    let heartbeat = sender.clone();
+    std::thread::spawn(move || {
+        while send(&heartbeat, IpcMessage::Heartbeat).is_ok() {
+            std::thread::sleep(HEARTBEAT_INTERVAL);
+        }
+    });
     (receiver, sender)
 }
 
+/// A file (e.g. a generated image) sent instead of text.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Artifact {
+    pub content_type: String,
+    pub alt: String,
+    /// base64 encoded file content.
+    pub data: String,
+}
+
+impl Artifact {
+    pub fn new(content_type: &str, alt: &str, data: &[u8]) -> Self {
+        Artifact {
+            content_type: content_type.to_string(),
+            alt: alt.to_string(),
+            data: STANDARD.encode(data),
+        }
+    }
+
+    pub fn bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(STANDARD.decode(self.data.as_str())?)
+    }
+}
+
+/// Version of the messages below, told by a worker in `Ready`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// How often a worker says it is alive.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A message between the master and a worker, sent as JSON. Replies carry
+/// the id of their request, and the index of the reply when the request
+/// asked for several. The big payloads are boxed, so tokens and heartbeats
+/// stay small.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum IpcMessage {
+    /// to the worker, a request to serve; the `QUIT` command stops it.
+    Request(Box<Request>),
+    /// to the worker, the requester went away.
+    Cancel { id: u64 },
+    /// text of a reply.
+    Token {
+        id: u64,
+        #[serde(default)]
+        choice: Option<usize>,
+        text: String,
+    },
+    /// a file (e.g. a generated image) instead of text.
+    Artifact { id: u64, artifact: Box<Artifact> },
+    ToolCall {
+        id: u64,
+        #[serde(default)]
+        choice: Option<usize>,
+        call: ToolCall,
+    },
+    Logprob {
+        id: u64,
+        #[serde(default)]
+        choice: Option<usize>,
+        logprob: TokenLogprob,
+    },
+    /// sent when a reply is done.
+    Usage {
+        id: u64,
+        #[serde(default)]
+        choice: Option<usize>,
+        usage: Usage,
+    },
+    /// the request or one of its replies failed.
+    Error {
+        id: u64,
+        #[serde(default)]
+        choice: Option<usize>,
+        message: String,
+    },
+    /// the last message for a request.
+    End { id: u64 },
+    /// from the worker once its model is loaded.
+    Ready { version: u32, model_id: String },
+    /// from the worker every `HEARTBEAT_INTERVAL`.
+    Heartbeat,
+}
+
+impl IpcMessage {
+    pub fn encode(&self) -> String {
+        serde_json::json!(self).to_string()
+    }
+
+    pub fn decode(message: &str) -> Result<IpcMessage, Error> {
+        Ok(serde_json::from_str::<IpcMessage>(message)?)
+    }
+
+    /// The request this message is about.
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            IpcMessage::Request(request) => Some(request.id),
+            IpcMessage::Cancel { id }
+            | IpcMessage::Token { id, .. }
+            | IpcMessage::Artifact { id, .. }
+            | IpcMessage::ToolCall { id, .. }
+            | IpcMessage::Logprob { id, .. }
+            | IpcMessage::Usage { id, .. }
+            | IpcMessage::Error { id, .. }
+            | IpcMessage::End { id } => Some(*id),
+            IpcMessage::Ready { .. } | IpcMessage::Heartbeat => None,
+        }
+    }
+}
+
+pub fn send(sender: &IpcSender<String>, message: IpcMessage) -> Result<(), Error> {
+    sender.send(message.encode())?;
+    Ok(())
+}
+
+/// Tells the master the model of the worker is loaded.
+pub fn ready(sender: &IpcSender<String>, model_id: &str) -> Result<(), Error> {
+    send(
+        sender,
+        IpcMessage::Ready {
+            version: PROTOCOL_VERSION,
+            model_id: model_id.to_string(),
+        },
+    )
+}
+
 pub trait OutputStream {
     fn write(&self, text: String) -> Result<(), Error>;
     fn end(&self) -> Result<(), Error>;
+    fn artifact(&self, content_type: &str, alt: &str, data: &[u8]) -> Result<(), Error>;
+    fn usage(&self, usage: &Usage) -> Result<(), Error>;
+    fn tool_call(&self, call: &ToolCall) -> Result<(), Error>;
+    fn logprob(&self, logprob: &TokenLogprob) -> Result<(), Error>;
+    fn error(&self, message: String) -> Result<(), Error>;
+}
+
+/// The reply to one request.
+pub struct TaggedOutput<'a> {
+    sender: &'a IpcSender<String>,
+    id: u64,
+    /// set for one of several replies to the request.
+    choice: Option<usize>,
+}
+
+impl<'a> TaggedOutput<'a> {
+    pub fn new(sender: &'a IpcSender<String>, id: u64) -> Self {
+        TaggedOutput {
+            sender,
+            id,
+            choice: None,
+        }
+    }
+
+    pub fn with_choice(mut self, choice: Option<usize>) -> Self {
+        self.choice = choice;
+        self
+    }
+}
+
+impl<'a> OutputStream for TaggedOutput<'a> {
+    fn write(&self, text: String) -> Result<(), Error> {
+        let (id, choice) = (self.id, self.choice);
+        send(self.sender, IpcMessage::Token { id, choice, text })
+    }
+
+    fn end(&self) -> Result<(), Error> {
+        send(self.sender, IpcMessage::End { id: self.id })
+    }
+
+    fn artifact(&self, content_type: &str, alt: &str, data: &[u8]) -> Result<(), Error> {
+        let artifact = Artifact::new(content_type, alt, data);
+        send(self.sender, IpcMessage::Artifact { id: self.id, artifact: Box::new(artifact) })
+    }
+
+    fn usage(&self, usage: &Usage) -> Result<(), Error> {
+        let (id, choice, usage) = (self.id, self.choice, usage.clone());
+        send(self.sender, IpcMessage::Usage { id, choice, usage })
+    }
+
+    fn tool_call(&self, call: &ToolCall) -> Result<(), Error> {
+        let (id, choice, call) = (self.id, self.choice, call.clone());
+        send(self.sender, IpcMessage::ToolCall { id, choice, call })
+    }
+
+    fn logprob(&self, logprob: &TokenLogprob) -> Result<(), Error> {
+        let (id, choice, logprob) = (self.id, self.choice, logprob.clone());
+        send(self.sender, IpcMessage::Logprob { id, choice, logprob })
+    }
+
+    fn error(&self, message: String) -> Result<(), Error> {
+        let (id, choice) = (self.id, self.choice);
+        send(self.sender, IpcMessage::Error { id, choice, message })
+    }
 }
 
-impl OutputStream for IpcSender<String> {
+/// The end of the channel held by a worker that serves one request at a
+/// time, writing the reply to the request it received last.
+pub struct WorkerChannel {
+    receiver: IpcReceiver<String>,
+    sender: IpcSender<String>,
+    request_id: Cell<u64>,
+}
+
+impl WorkerChannel {
+    pub fn accept(ipc_name: String) -> Self {
+        let (receiver, sender) = accept(ipc_name);
+        WorkerChannel {
+            receiver,
+            sender,
+            request_id: Cell::new(0),
+        }
+    }
+
+    pub fn ready(&self, model_id: &str) -> Result<(), Error> {
+        ready(&self.sender, model_id)
+    }
+
+    /// Waits for the next request. Cancels come too late for a worker that
+    /// already finished the reply, they are skipped.
+    pub fn recv(&self) -> Result<Request, Error> {
+        loop {
+            let message = self.receiver.recv().map_err(|e| Error::msg(format!("{:?}", e)))?;
+            match IpcMessage::decode(message.as_str()) {
+                Ok(IpcMessage::Request(request)) => {
+                    self.request_id.set(request.id);
+                    return Ok(*request);
+                }
+                Ok(_) => {}
+                Err(e) => println!("dropped an unreadable message: {}", e),
+            }
+        }
+    }
+
+    fn output(&self) -> TaggedOutput<'_> {
+        TaggedOutput::new(&self.sender, self.request_id.get())
+    }
+}
+
+impl OutputStream for WorkerChannel {
     fn write(&self, text: String) -> Result<(), Error> {
-        self.send(text)?;
-        Ok(())
+        self.output().write(text)
     }
 
     fn end(&self) -> Result<(), Error> {
-        self.send("<|endoftext|>".to_string())?;
-        Ok(())
+        self.output().end()
+    }
+
+    fn artifact(&self, content_type: &str, alt: &str, data: &[u8]) -> Result<(), Error> {
+        self.output().artifact(content_type, alt, data)
+    }
+
+    fn usage(&self, usage: &Usage) -> Result<(), Error> {
+        self.output().usage(usage)
+    }
+
+    fn tool_call(&self, call: &ToolCall) -> Result<(), Error> {
+        self.output().tool_call(call)
+    }
+
+    fn logprob(&self, logprob: &TokenLogprob) -> Result<(), Error> {
+        self.output().logprob(logprob)
+    }
+
+    fn error(&self, message: String) -> Result<(), Error> {
+        self.output().error(message)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{FinishReason, FunctionCall, TopLogprob, Truncation};
+    use serde_json::json;
+
+    /// A message of every variant, with the tag it is sent under.
+    fn messages() -> Vec<(&'static str, IpcMessage)> {
+        let request: Request = serde_json::from_value(json!({
+            "id": 7,
+            "cmd": "chat",
+            "system_prompt": "Be brief.",
+            "msg_list": [{ "id": 0, "role": "User", "content": "Hi", "img": null, "loading": false }],
+            "temperature": 0.5,
+            "n": 2,
+            "stop": ["\n"],
+            "truncation": { "policy": "keep-last", "n": 4 },
+            "session_id": "session",
+            "tools": [{ "type": "function", "function": { "name": "add", "parameters": {} } }]
+        }))
+        .unwrap();
+        let usage = Usage {
+            prompt_tokens: 3,
+            completion_tokens: 2,
+            time_to_first_token: 12.5,
+            tokens_per_second: 30.,
+            finish_reason: FinishReason::ToolCalls,
+        };
+        vec![
+            ("request", IpcMessage::Request(Box::new(request))),
+            ("cancel", IpcMessage::Cancel { id: 7 }),
+            ("token", IpcMessage::Token { id: 7, choice: Some(1), text: "Hello".to_string() }),
+            ("artifact", IpcMessage::Artifact { id: 7, artifact: Box::new(Artifact::new("image/png", "a cat", &[0, 1, 255])) }),
+            (
+                "tool_call",
+                IpcMessage::ToolCall {
+                    id: 7,
+                    choice: None,
+                    call: ToolCall {
+                        id: "call_0".to_string(),
+                        kind: "function".to_string(),
+                        function: FunctionCall { name: "add".to_string(), arguments: r#"{"a":1}"#.to_string() },
+                    },
+                },
+            ),
+            (
+                "logprob",
+                IpcMessage::Logprob {
+                    id: 7,
+                    choice: Some(0),
+                    logprob: TokenLogprob {
+                        token: "Hi".to_string(),
+                        logprob: -0.25,
+                        top_logprobs: vec![TopLogprob { token: "Hey".to_string(), logprob: -1.5 }],
+                    },
+                },
+            ),
+            ("usage", IpcMessage::Usage { id: 7, choice: None, usage }),
+            ("error", IpcMessage::Error { id: 7, choice: Some(1), message: "failed".to_string() }),
+            ("end", IpcMessage::End { id: 7 }),
+            ("ready", IpcMessage::Ready { version: PROTOCOL_VERSION, model_id: "model".to_string() }),
+            ("heartbeat", IpcMessage::Heartbeat),
+        ]
+    }
 
+    #[test]
+    fn every_message_survives_the_round_trip() {
+        for (tag, message) in messages() {
+            let encoded = message.encode();
+            let value: serde_json::Value = serde_json::from_str(&encoded).unwrap();
+            assert_eq!(value["type"], tag);
+            let decoded = IpcMessage::decode(&encoded).unwrap();
+            assert_eq!(decoded.encode(), encoded);
+            assert_eq!(decoded.id(), message.id());
+        }
+    }
+
+    #[test]
+    fn decoded_messages_keep_their_fields() {
+        let messages: Vec<IpcMessage> = messages()
+            .into_iter()
+            .map(|(_, message)| IpcMessage::decode(&message.encode()).unwrap())
+            .collect();
+        match &messages[0] {
+            IpcMessage::Request(request) => {
+                assert_eq!((request.id, request.cmd.as_str()), (7, "chat"));
+                assert_eq!(request.params.temperature, Some(0.5));
+                assert_eq!(request.params.n, Some(2));
+                assert_eq!(request.truncation, Some(Truncation::KeepLast { n: 4 }));
+                assert_eq!(request.session_id.as_deref(), Some("session"));
+                assert_eq!(request.msg_list[0].content, "Hi");
+                assert_eq!(request.tools.len(), 1);
+            }
+            message => panic!("decoded {:?}", message),
+        }
+        match &messages[3] {
+            IpcMessage::Artifact { artifact, .. } => assert_eq!(artifact.bytes().unwrap(), vec![0, 1, 255]),
+            message => panic!("decoded {:?}", message),
+        }
+        match &messages[6] {
+            IpcMessage::Usage { usage, .. } => assert_eq!(usage.finish_reason, FinishReason::ToolCalls),
+            message => panic!("decoded {:?}", message),
+        }
+        match &messages[9] {
+            IpcMessage::Ready { version, model_id } => assert_eq!((*version, model_id.as_str()), (PROTOCOL_VERSION, "model")),
+            message => panic!("decoded {:?}", message),
+        }
+        // replies to a request for one reply may leave the choice out.
+        match IpcMessage::decode(r#"{"type":"token","data":{"id":3,"text":"a"}}"#).unwrap() {
+            IpcMessage::Token { id: 3, choice: None, text } => assert_eq!(text, "a"),
+            message => panic!("decoded {:?}", message),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use crate::data::{Message, Request, Role, SamplingParams, TokenLogprob, Tool, ToolCall, Usage};
+use crate::ipc::IpcMessage;
+use crate::master_server::{dispatch, valid_token};
+use crate::master_state::JobConfig;
+use axum::{extract::Path, http::StatusCode, Json};
+use axum_auth::AuthBearer;
+use chrono::Utc;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+lazy_static! {
+    static ref JOBS: DashMap<String, Job> = DashMap::<String, Job>::new();
+}
+static JOB_SEQ: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A generation submitted through `POST /api/jobs`. `model_id` names the
+/// worker; the prompt is either given directly or as a full message list.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobRequest {
+    pub model_id: String,
+    #[serde(default)]
+    pub prompt: Option<String>,
+    #[serde(default)]
+    pub system_prompt: String,
+    #[serde(default)]
+    pub msg_list: Vec<Message>,
+    #[serde(flatten)]
+    pub params: SamplingParams,
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    /// URL receiving the finished job as a JSON `POST`.
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub model_id: String,
+    pub status: JobStatus,
+    /// Number of output chunks the worker has streamed back so far.
+    pub progress: usize,
+    /// the reply, the first one when the request asked for several.
+    pub result: Option<String>,
+    /// the text of each reply when the request asked for several.
+    #[serde(default)]
+    pub choices: Vec<String>,
+    /// the tools the reply called.
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    /// one per generated token, when the request asked for logprobs.
+    #[serde(default)]
+    pub logprobs: Vec<TokenLogprob>,
+    pub error: Option<String>,
+    pub usage: Option<Usage>,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+    #[serde(skip)]
+    owner: String,
+    #[serde(skip)]
+    webhook: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobCreated {
+    pub id: String,
+}
+
+fn new_job_id() -> String {
+    format!(
+        "job_{}_{}",
+        Utc::now().timestamp_millis(),
+        JOB_SEQ.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+fn update_job(id: &str, f: impl FnOnce(&mut Job)) {
+    if let Some(mut job) = JOBS.get_mut(id) {
+        f(job.value_mut());
+    }
+}
+
+/// Forgets the jobs finished more than `ttl_ms` before `now`.
+fn expire_jobs(now: i64, ttl_ms: i64) {
+    JOBS.retain(|_, job| job.finished_at.is_none_or(|finished_at| now - finished_at <= ttl_ms));
+}
+
+pub(crate) fn init(config: &JobConfig) {
+    let ttl_ms = (config.ttl_minutes * 60 * 1000) as i64;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            expire_jobs(Utc::now().timestamp_millis(), ttl_ms);
+        }
+    });
+}
+
+async fn notify_webhook(job: &Job) {
+    if let Some(ref url) = job.webhook {
+        let result = reqwest::Client::new().post(url).json(job).send().await;
+        match result {
+            Ok(response) => println!("job {} webhook {} -> {}", job.id, url, response.status()),
+            Err(e) => println!("job {} webhook {} failed: {}", job.id, url, e),
+        }
+    }
+}
+
+async fn run_job(id: String, request: Request) {
+    let model_id = request.cmd.clone();
+    let req = Request {
+        cmd: "chat".to_string(),
+        ..request
+    };
+    match dispatch(model_id.as_str(), req).await {
+        Ok(mut rx) => {
+            update_job(id.as_str(), |job| job.status = JobStatus::Running);
+            let mut output = String::new();
+            let mut error: Option<String> = None;
+            while let Some(message) = rx.recv().await {
+                match message {
+                    // the other replies of a request for several only keep their text.
+                    IpcMessage::Token { choice: Some(index), text, .. } => {
+                        update_job(id.as_str(), |job| {
+                            if job.choices.len() <= index {
+                                job.choices.resize(index + 1, String::new());
+                            }
+                            job.choices[index].push_str(text.as_str());
+                        });
+                        if index == 0 {
+                            output.push_str(text.as_str());
+                            update_job(id.as_str(), |job| job.progress += 1);
+                        }
+                    }
+                    IpcMessage::Token { text, .. } => {
+                        output.push_str(text.as_str());
+                        update_job(id.as_str(), |job| job.progress += 1);
+                    }
+                    IpcMessage::Usage { choice: None | Some(0), usage, .. } => {
+                        update_job(id.as_str(), |job| job.usage = Some(usage))
+                    }
+                    IpcMessage::ToolCall { choice: None | Some(0), call, .. } => {
+                        update_job(id.as_str(), |job| job.tool_calls.push(call))
+                    }
+                    IpcMessage::Logprob { choice: None | Some(0), logprob, .. } => {
+                        update_job(id.as_str(), |job| job.logprobs.push(logprob))
+                    }
+                    IpcMessage::Error { message, .. } => error = Some(message),
+                    _ => {}
+                }
+            }
+            update_job(id.as_str(), |job| {
+                match error {
+                    Some(error) => {
+                        job.status = JobStatus::Failed;
+                        job.error = Some(error);
+                    }
+                    None => job.status = JobStatus::Succeeded,
+                }
+                job.result = Some(output);
+                job.finished_at = Some(Utc::now().timestamp_millis());
+            });
+        }
+        Err((_, e)) => update_job(id.as_str(), |job| {
+            job.status = JobStatus::Failed;
+            job.error = Some(e);
+            job.finished_at = Some(Utc::now().timestamp_millis());
+        }),
+    }
+    let job = JOBS.get(id.as_str()).map(|job| job.value().clone());
+    if let Some(job) = job {
+        notify_webhook(&job).await;
+    }
+}
+
+pub async fn submit_job(
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<JobRequest>,
+) -> Result<(StatusCode, Json<JobCreated>), (StatusCode, String)> {
+    if !valid_token(token.as_str()) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            String::from("Authentication failed. Please sign in first."),
+        ));
+    }
+    let mut msg_list = request.msg_list;
+    if let Some(prompt) = request.prompt {
+        msg_list.push(Message {
+            id: msg_list.len(),
+            role: Role::User,
+            content: prompt,
+            img: None,
+            attachment: None,
+            loading: false,
+            usage: None,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        });
+    }
+    if msg_list.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            String::from("A job needs a prompt or a message list."),
+        ));
+    }
+    let id = new_job_id();
+    JOBS.insert(
+        id.clone(),
+        Job {
+            id: id.clone(),
+            model_id: request.model_id.clone(),
+            status: JobStatus::Queued,
+            progress: 0,
+            result: None,
+            choices: Vec::new(),
+            tool_calls: Vec::new(),
+            logprobs: Vec::new(),
+            error: None,
+            usage: None,
+            created_at: Utc::now().timestamp_millis(),
+            finished_at: None,
+            owner: token,
+            webhook: request.webhook,
+        },
+    );
+    tokio::spawn(run_job(
+        id.clone(),
+        Request {
+            cmd: request.model_id,
+            system_prompt: request.system_prompt,
+            msg_list,
+            params: request.params,
+            tools: request.tools,
+            ..Default::default()
+        },
+    ));
+    Ok((StatusCode::ACCEPTED, Json::from(JobCreated { id })))
+}
+
+pub async fn get_job(
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, (StatusCode, String)> {
+    match JOBS.get(id.as_str()) {
+        Some(job) if job.owner == token => Ok(Json::from(job.value().clone())),
+        _ => Err((StatusCode::NOT_FOUND, format!("Job {} is not exist!", id))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{routing::post, Router};
+    use tokio::sync::mpsc;
+
+    fn job(id: &str, finished_at: Option<i64>, webhook: Option<String>) -> Job {
+        Job {
+            id: id.to_string(),
+            model_id: String::from("model"),
+            status: JobStatus::Succeeded,
+            progress: 3,
+            result: Some(String::from("done")),
+            choices: Vec::new(),
+            tool_calls: Vec::new(),
+            logprobs: Vec::new(),
+            error: None,
+            usage: None,
+            created_at: 0,
+            finished_at,
+            owner: String::from("owner"),
+            webhook,
+        }
+    }
+
+    #[test]
+    fn finished_jobs_expire_after_the_ttl() {
+        JOBS.insert("job_old".into(), job("job_old", Some(1_000), None));
+        JOBS.insert("job_new".into(), job("job_new", Some(9_000), None));
+        JOBS.insert("job_running".into(), job("job_running", None, None));
+        expire_jobs(10_000, 5_000);
+        assert!(!JOBS.contains_key("job_old"));
+        assert!(JOBS.contains_key("job_new"));
+        assert!(JOBS.contains_key("job_running"));
+    }
+
+    #[tokio::test]
+    async fn webhook_receives_the_finished_job() {
+        let (tx, mut rx) = mpsc::channel::<Job>(1);
+        let app = Router::new().route(
+            "/hook",
+            post(move |Json(job): Json<Job>| async move {
+                tx.send(job).await.unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let url = format!("http://{}/hook", addr);
+        notify_webhook(&job("job_hook", Some(5), Some(url))).await;
+        let received = rx.recv().await.unwrap();
+        assert_eq!(received.id, "job_hook");
+        assert_eq!(received.status, JobStatus::Succeeded);
+        assert_eq!(received.result.as_deref(), Some("done"));
+    }
+}
@@ -6,6 +6,28 @@ pub mod model;
 pub mod llama;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod phi3;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod qwen2;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod mistral;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod gemma;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod registry;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod quantized;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod generation;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transformer;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod chat_template;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod constraint;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod tools;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod bert;
 pub mod data;
 pub mod web;
 #[cfg(not(target_arch = "wasm32"))]
@@ -15,6 +37,18 @@ pub mod worker_server;
 #[cfg(not(target_arch = "wasm32"))]
 mod master_state;
 #[cfg(not(target_arch = "wasm32"))]
+pub mod session_store;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod jobs;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod downloads;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod artifacts;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod uploads;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod embeddings;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod master_server;
 pub mod web_state;
 pub mod authorization;
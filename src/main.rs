@@ -9,6 +9,8 @@ use std::str::FromStr;
 #[cfg(not(target_arch = "wasm32"))]
 use moonweb::master_server::master_server;
 #[cfg(not(target_arch = "wasm32"))]
+use moonweb::registry::LoadOptions;
+#[cfg(not(target_arch = "wasm32"))]
 use moonweb::worker_server::worker_server;
 
 // Urls are relative to your Cargo.toml file
@@ -53,6 +55,37 @@ struct Args {
 
     #[clap(short='e', long)]
     master_port: Option<u32>,
+
+    #[clap(long)]
+    chat_template: Option<String>,
+
+    #[clap(long)]
+    gguf: Option<String>,
+
+    #[clap(long)]
+    model_dir: Option<String>,
+
+    #[clap(long)]
+    offline: bool,
+
+    #[clap(long)]
+    draft_model: Option<String>,
+
+    #[clap(long)]
+    draft_tokens: Option<usize>,
+
+    /// sampling defaults as JSON, e.g. `{"top_k":40,"repeat_penalty":1.1}`.
+    #[clap(long)]
+    sampling: Option<String>,
+
+    #[clap(long)]
+    device: Option<String>,
+
+    #[clap(long)]
+    dtype: Option<String>,
+
+    #[clap(long)]
+    threads: Option<usize>,
     
 }
 
@@ -83,13 +116,33 @@ fn main() {
             let temp = args.temp.unwrap_or_else(|| 0.6f64);
             let top_p = args.top_p.unwrap_or_else(|| 0.9f64);
             let ipc_name = args.ipc_name.unwrap();
+            let sampling = match args.sampling.as_deref().map(serde_json::from_str) {
+                Some(Ok(sampling)) => sampling,
+                Some(Err(e)) => {
+                    println!("invalid --sampling: {}", e);
+                    std::process::exit(1);
+                }
+                None => Default::default(),
+            };
+            if let Some(threads) = args.threads {
+                // read by candle and rayon when their thread pools start.
+                std::env::set_var("RAYON_NUM_THREADS", threads.to_string());
+            }
             let runtime = tokio::runtime::Runtime::new().expect("Create runtime failed!");
-            runtime.block_on(worker_server(
-                ipc_name,
-                model_id.clone(),
+            let options = LoadOptions {
                 temp,
                 top_p,
-            ));
+                chat_template: args.chat_template,
+                gguf: args.gguf,
+                model_dir: args.model_dir,
+                offline: args.offline,
+                draft_model: args.draft_model,
+                draft_tokens: args.draft_tokens,
+                sampling,
+                device: args.device,
+                dtype: args.dtype,
+            };
+            runtime.block_on(worker_server(ipc_name, model_id.clone(), options));
         }
         }
     }
@@ -1,12 +1,23 @@
-use crate::data::{AuthRequest, AuthResponse, Message, Request, Role};
+use crate::data::{
+    AuthRequest, AuthResponse, Capability, ModelInfo, Request, Role, SamplingParams,
+    Session,
+};
 
+use crate::artifacts::{self, get_file, save_artifact};
+use crate::ipc::{send, IpcMessage, HEARTBEAT_INTERVAL, PROTOCOL_VERSION};
 use crate::master_state::{
-    get_master_addr, get_program, get_servers, get_working_servers, new_working_server,
-    remove_working_server,
+    get_artifact_config, get_job_config, get_master_addr, get_program, get_servers, get_session_db,
+    get_working_servers, new_working_server, remove_working_server, WorkerServer,
 };
+use crate::embeddings::create_embeddings;
+use crate::downloads::{delete_cached_model, get_download, list_downloads, start_download};
+use crate::jobs::{self, get_job, submit_job};
+use crate::session_store::SessionStore;
+use crate::uploads::{resolve_attachments, upload_file};
 use axum::{
     self,
-    extract::DefaultBodyLimit,
+    extract::{DefaultBodyLimit, Path},
+    http::StatusCode,
     response::sse::{Event, Sse},
     routing::{get, post},
     Json, Router,
@@ -21,13 +32,15 @@ use ipc_channel::ipc::{IpcOneShotServer, IpcReceiver, IpcSender};
 use lazy_static::lazy_static;
 use sqids::Sqids;
 use std::convert::Infallible;
-use std::path::PathBuf;
 use std::process;
 use std::process::Command;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
 use tokio::signal;
 #[cfg(unix)]
 use tokio::signal::unix::SignalKind;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
 use tokio::time::Duration;
 use tower_http::services::{ServeDir, ServeFile};
 
@@ -39,41 +52,273 @@ const SALT: &str = "Akpu#fqDiy@EoY931J_VRHIrU2";
 lazy_static! {
     static ref WORKER_HUB: DashMap<String, Worker> = DashMap::<String, Worker>::new();
 }
+static SESSION_STORE: OnceLock<SessionStore> = OnceLock::new();
+/// Requests to the actor of a worker, with where to send the replies.
+pub type WorkerSender = Sender<(Option<UnboundedSender<IpcMessage>>, Request)>;
+
 pub struct Worker {
     pub model_id: String,
-    pub sender: Sender<(Option<Sender<String>>, Request)>,
+    pub sender: WorkerSender,
+    pub(crate) health: Arc<Mutex<WorkerHealth>>,
+}
+
+/// What the master hears from a worker, kept by the thread reading its messages.
+pub(crate) struct WorkerHealth {
+    last_seen: Instant,
+    /// the protocol version of a worker speaking another one than the master.
+    mismatch: Option<u32>,
+}
+
+impl WorkerHealth {
+    fn new() -> Self {
+        WorkerHealth {
+            last_seen: Instant::now(),
+            mismatch: None,
+        }
+    }
+
+    fn silent(&self) -> bool {
+        self.last_seen.elapsed() > HEARTBEAT_INTERVAL * 3
+    }
+
+    /// Why the worker can not take requests, `None` when it can.
+    fn unusable(&self, model_id: &str) -> Option<String> {
+        match self.mismatch {
+            Some(version) => Some(format!(
+                "{} model server speaks protocol version {}, the master {}, rebuild it with this version of moonweb",
+                model_id, version, PROTOCOL_VERSION
+            )),
+            None if self.silent() => Some(format!("{} model server stopped answering", model_id)),
+            None => None,
+        }
+    }
+}
+
+/// Replies still expected from a worker, oldest first.
+type Routes = Arc<Mutex<VecDeque<(u64, Option<UnboundedSender<IpcMessage>>)>>>;
+
+/// Reads the messages of a worker and hands the replies to their requesters.
+/// A requester that went away has its request cancelled.
+fn route_replies(
+    model_id: String,
+    receiver: IpcReceiver<String>,
+    sender: IpcSender<String>,
+    routes: Routes,
+    health: Arc<Mutex<WorkerHealth>>,
+) {
+    while let Ok(message) = receiver.recv() {
+        health.lock().unwrap().last_seen = Instant::now();
+        let message = match IpcMessage::decode(message.as_str()) {
+            Ok(message) => message,
+            Err(e) => {
+                println!("dropped an unreadable message from {}: {}", model_id, e);
+                continue;
+            }
+        };
+        let id = match message {
+            // requests to a worker that can not read them fail in dispatch.
+            IpcMessage::Ready { version, .. } if version != PROTOCOL_VERSION => {
+                let mut health = health.lock().unwrap();
+                health.mismatch = Some(version);
+                println!("{}", health.unusable(&model_id).unwrap_or_default());
+                continue;
+            }
+            IpcMessage::Ready { .. } => {
+                println!("{} is ready", model_id);
+                continue;
+            }
+            IpcMessage::Heartbeat | IpcMessage::Request(_) | IpcMessage::Cancel { .. } => continue,
+            IpcMessage::End { id } => {
+                routes.lock().unwrap().retain(|(route_id, _)| *route_id != id);
+                continue;
+            }
+            ref message => message.id().unwrap_or_default(),
+        };
+        let response_tx = routes
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(route_id, _)| *route_id == id)
+            .map(|(_, response_tx)| response_tx.clone());
+        let response_tx = match response_tx {
+            Some(Some(response_tx)) => response_tx,
+            Some(None) => continue,
+            None => {
+                println!("dropped a reply without a requester");
+                continue;
+            }
+        };
+        let message = match message {
+            IpcMessage::Artifact { id, artifact } => {
+                let text = match artifact
+                    .bytes()
+                    .and_then(|data| save_artifact(&artifact.content_type, &artifact.alt, &data))
+                {
+                    Ok(markdown) => markdown,
+                    Err(e) => format!("Failed to save artifact: {}", e),
+                };
+                IpcMessage::Token { id, choice: None, text }
+            }
+            message => message,
+        };
+        // route the rest of the reply nowhere once the requester went away.
+        if response_tx.send(message).is_err() {
+            println!("requester of request {} went away", id);
+            if let Some(route) = routes.lock().unwrap().iter_mut().find(|(route_id, _)| *route_id == id) {
+                route.1 = None;
+            }
+            let _ = send(&sender, IpcMessage::Cancel { id });
+        }
+    }
 }
 
 async fn modal_actor(
+    model_id: String,
     sender: IpcSender<String>,
     receiver: IpcReceiver<String>,
-    mut rx: Receiver<(Option<Sender<String>>, Request)>,
+    mut rx: Receiver<(Option<UnboundedSender<IpcMessage>>, Request)>,
+    health: Arc<Mutex<WorkerHealth>>,
 ) {
+    let routes: Routes = Arc::new(Mutex::new(VecDeque::new()));
+    {
+        let (model_id, sender, routes, health) =
+            (model_id.clone(), sender.clone(), routes.clone(), health.clone());
+        std::thread::spawn(move || route_replies(model_id, receiver, sender, routes, health));
+    }
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    let mut silent = false;
+    let mut next_id = 0u64;
     loop {
-        if let Some((response_tx, request_data)) = rx.recv().await {
-            let data = serde_json::json!(request_data).to_string();
-            sender
-                .send(data)
-                .expect("Failed to send request to worker process!");
-            if request_data.cmd == "QUIT" {
-                break;
-            }
-            loop {
-                if let Ok(response) = receiver.recv() {
-                    if response == "<|endoftext|>" {
-                        break;
-                    }
-                    if response_tx.clone().unwrap().send(response).await.is_err() {
-                        break;
-                    }
-                } else {
-                    break;
+        let (response_tx, mut request_data) = tokio::select! {
+            request = rx.recv() => match request {
+                Some(request) => request,
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                let missed = health.lock().unwrap().silent();
+                if missed && !silent {
+                    println!("{} missed its heartbeats, requests to it fail until it answers", model_id);
+                }
+                if !missed && silent {
+                    println!("{} answers again", model_id);
                 }
+                silent = missed;
+                continue;
             }
         };
+        next_id += 1;
+        request_data.id = next_id;
+        let quit = request_data.cmd == "QUIT";
+        if !quit {
+            routes.lock().unwrap().push_back((next_id, response_tx));
+        }
+        send(&sender, IpcMessage::Request(Box::new(request_data))).expect("Failed to send request to worker process!");
+        if quit {
+            break;
+        }
     }
 }
 
+/// Rejects requests the model cannot serve, e.g. images sent to a text-only
+/// model or a chat sent to an embedding model.
+fn check_capabilities(server: &WorkerServer, request: &Request) -> Result<(), String> {
+    let model_id = server.model_id.as_str();
+    let capabilities = &server.capabilities;
+    match request.cmd.as_str() {
+        "embed" => {
+            if !capabilities.contains(&Capability::Embeddings) {
+                return Err(format!("{} can not create embeddings", model_id));
+            }
+        }
+        _ => {
+            if !capabilities.contains(&Capability::Chat)
+                && !capabilities.contains(&Capability::ImageGeneration)
+            {
+                return Err(format!("{} does not support chat", model_id));
+            }
+            let has_image = request
+                .msg_list
+                .iter()
+                .any(|msg| msg.img.is_some() || msg.attachment.is_some());
+            if has_image && !capabilities.contains(&Capability::Vision) {
+                return Err(format!("{} does not accept images", model_id));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The channel to the worker serving `model_id`, unless the worker can not
+/// take requests.
+fn worker_sender(model_id: &str) -> Result<WorkerSender, String> {
+    match WORKER_HUB.get(model_id) {
+        Some(worker) => match worker.health.lock().unwrap().unusable(model_id) {
+            Some(reason) => Err(reason),
+            None => Ok(worker.sender.clone()),
+        },
+        None => Err(format!("Failed to find {} model server", model_id)),
+    }
+}
+
+/// Hand a request to the worker serving `model_id` and return the channel its
+/// reply is streamed on, or why the worker can not serve it: a bad request
+/// for an unknown model, a capability it lacks or a missing attachment, and
+/// unavailable when the worker is down or unusable.
+pub(crate) async fn dispatch(
+    model_id: &str,
+    mut request: Request,
+) -> Result<UnboundedReceiver<IpcMessage>, (StatusCode, String)> {
+    let server = match get_working_servers()
+        .await
+        .into_iter()
+        .find(|serv| serv.model_id == model_id)
+    {
+        Some(server) => server,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Failed to find {} model server", model_id),
+            ))
+        }
+    };
+    check_capabilities(&server, &request).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if request.truncation.is_none() {
+        request.truncation = Some(server.truncation);
+    }
+    let sender = worker_sender(model_id).map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e))?;
+    request.msg_list = resolve_attachments(request.msg_list).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    // unbounded, so a slow requester never holds up the replies to the others.
+    let (response_tx, response_rx) = mpsc::unbounded_channel::<IpcMessage>();
+    if let Err(e) = sender.send((Some(response_tx), request)).await {
+        println!("Failed to send to worker {}: {}", model_id, e);
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{} model server is stopped", model_id),
+        ));
+    }
+    Ok(response_rx)
+}
+
+/// The SSE event of a message from a worker. When the request asked for
+/// several replies, the data is an object with the `index` of its reply and
+/// plain text comes as a `choice` event.
+fn reply_event(message: IpcMessage) -> Event {
+    let (event, index, mut data) = match message {
+        IpcMessage::Token { choice: None, text, .. } => return Event::default().data(text),
+        IpcMessage::Token { choice, text, .. } => ("choice", choice, serde_json::json!({ "text": text })),
+        IpcMessage::Usage { choice, usage, .. } => ("usage", choice, serde_json::json!(usage)),
+        IpcMessage::ToolCall { choice, call, .. } => ("tool_call", choice, serde_json::json!(call)),
+        IpcMessage::Logprob { choice, logprob, .. } => ("logprobs", choice, serde_json::json!(logprob)),
+        IpcMessage::Error { choice: None, message, .. } => return Event::default().event("error").data(message),
+        IpcMessage::Error { choice, message, .. } => ("error", choice, serde_json::json!({ "message": message })),
+        _ => return Event::default().comment("unexpected message"),
+    };
+    if let (Some(index), Some(data)) = (index, data.as_object_mut()) {
+        data.insert("index".to_string(), index.into());
+    }
+    Event::default().event(event).data(data.to_string())
+}
+
 pub async fn call_worker(
     AuthBearer(token): AuthBearer,
     Json(request): Json<Request>,
@@ -82,42 +327,31 @@ pub async fn call_worker(
     let model_id = request.cmd;
 
     let mut receiver = if valid_token(token.as_str()) {
-        if let Some(worker) = WORKER_HUB.get(&model_id) {
-            let (response_tx, response_rx) = mpsc::channel::<String>(1);
-            let req = Request {
-                cmd: "chat".to_string(),
-                system_prompt: request.system_prompt,
-                msg_list: request.msg_list,
-            };
-            worker
-                .sender
-                .send((Some(response_tx), req))
-                .await
-                .expect("Failed to send to worker");
-            Some(response_rx)
-        } else {
-            None
-        }
+        let req = Request {
+            cmd: "chat".to_string(),
+            ..request
+        };
+        dispatch(model_id.as_str(), req).await.map_err(|(_, e)| e)
     } else {
-        None
+        Err(String::from("Authentication failed. Please sign in first."))
     };
     use tokio_stream::StreamExt as _;
 
     let stream = async_stream::stream! {
         match receiver {
-                Some(ref mut rx)=> loop {
+                Ok(ref mut rx)=> loop {
                      let msg = match rx.recv().await {
-                        Some(text) => Event::default().data(text),
+                        Some(message) => reply_event(message),
                         None => {
                             break;
                         }
                      };
                      yield msg;
                 },
-                None => loop {
-                    println!("worker is None!!!!");
+                Err(ref e) => {
+                    println!("call_worker failed: {}", e);
+                    yield Event::default().data(e.clone());
                     yield Event::default().data("[DONE]");
-                    break;
                 }
         }
     }
@@ -147,8 +381,7 @@ async fn handle_unix_signals() {
         let worker = kv.value_mut();
         let req = Request {
             cmd: "QUIT".to_string(),
-            system_prompt: "".to_string(),
-            msg_list: Vec::<Message>::new(),
+            ..Default::default()
         };
 
         let _ = worker.sender.send((None, req)).await.is_err_and(|x| {
@@ -160,10 +393,18 @@ async fn handle_unix_signals() {
 }
 
 pub async fn master_server() {
+    let session_db = get_session_db().await;
+    let store = SessionStore::open(session_db.as_str())
+        .unwrap_or_else(|e| panic!("Failed to open session database {}: {}", session_db, e));
+    let _ = SESSION_STORE.set(store);
+    let artifact_config = get_artifact_config().await;
+    artifacts::init(&artifact_config);
+    jobs::init(&get_job_config().await);
+    let upload_limit = (artifact_config.upload_limit_mb * 1024 * 1024) as usize;
+
     for server in get_working_servers().await.iter() {
-        let model_id = &server.model_id;
         let program = get_program(server);
-        launch_worker(&program, model_id);
+        launch_worker(&program, server);
     }
 
     #[cfg(unix)]
@@ -179,7 +420,24 @@ pub async fn master_server() {
         
         .route("/api/models", get(modal_list))
         .route("/api/signin", post(signin))
-        .layer(DefaultBodyLimit::disable())
+        .route("/v1/embeddings", post(create_embeddings))
+        .route("/api/files/:id", get(get_file))
+        .route(
+            "/api/uploads",
+            post(upload_file).layer(DefaultBodyLimit::max(upload_limit)),
+        )
+        .route("/api/jobs", post(submit_job))
+        .route("/api/jobs/:id", get(get_job))
+        .route("/api/downloads", get(list_downloads).post(start_download))
+        .route(
+            "/api/downloads/*model_id",
+            get(get_download).delete(delete_cached_model),
+        )
+        .route("/api/sessions", get(list_sessions).post(create_session))
+        .route(
+            "/api/sessions/:id",
+            get(get_session).put(update_session).delete(delete_session),
+        )
         .nest_service("/", serve_dir.clone())
         .fallback_service(serve_dir);
     let addr = get_master_addr().await;
@@ -188,16 +446,48 @@ pub async fn master_server() {
     axum::serve(listener, app).await.unwrap();
 }
 
-fn launch_worker(program: &PathBuf, model_id: &String) {
+fn launch_worker(program: &std::path::Path, server: &WorkerServer) {
+    let model_id = &server.model_id;
     let (one_shot_serv, ipc_name) = IpcOneShotServer::new().expect("Failed to ipc one shot server");
-    let e = Command::new(program.as_os_str())
+    let mut command = Command::new(program.as_os_str());
+    command
         .arg("--server")
         .arg("Worker")
         .arg("--model-id")
         .arg(model_id.as_str())
         .arg("--ipc-name")
-        .arg(ipc_name.as_str())
-        .spawn();
+        .arg(ipc_name.as_str());
+    if let Some(chat_template) = &server.chat_template {
+        command.arg("--chat-template").arg(chat_template);
+    }
+    if let Some(gguf) = &server.gguf {
+        command.arg("--gguf").arg(gguf);
+    }
+    if let Some(model_dir) = &server.model_dir {
+        command.arg("--model-dir").arg(model_dir);
+    }
+    if server.offline {
+        command.arg("--offline");
+    }
+    if let Some(draft_model) = &server.draft_model {
+        command.arg("--draft-model").arg(draft_model);
+    }
+    if let Some(draft_tokens) = server.draft_tokens {
+        command.arg("--draft-tokens").arg(draft_tokens.to_string());
+    }
+    if server.sampling != SamplingParams::default() {
+        command.arg("--sampling").arg(serde_json::json!(server.sampling).to_string());
+    }
+    if let Some(device) = &server.device {
+        command.arg("--device").arg(device);
+    }
+    if let Some(dtype) = &server.dtype {
+        command.arg("--dtype").arg(dtype);
+    }
+    if let Some(threads) = server.threads {
+        command.arg("--threads").arg(threads.to_string());
+    }
+    let e = command.spawn();
     if e.is_err() {
         println!("Worker server {} failed to start", model_id);
         return;
@@ -209,22 +499,27 @@ fn launch_worker(program: &PathBuf, model_id: &String) {
     sender.send(ipc_name).expect("Failed to send ipc name");
     let (_, receiver): (_, IpcReceiver<String>) =
         one_shot_serv.accept().expect("Failed to accept receiver!");
-    let (tx, rx) = mpsc::channel::<(Option<Sender<String>>, Request)>(1);
+    let (tx, rx) = mpsc::channel::<(Option<UnboundedSender<IpcMessage>>, Request)>(1);
+    let health = Arc::new(Mutex::new(WorkerHealth::new()));
     WORKER_HUB.insert(
         model_id.clone(),
         Worker {
             model_id: model_id.clone(),
             sender: tx,
+            health: health.clone(),
         },
     );
-    tokio::spawn(modal_actor(sender, receiver, rx));
+    tokio::spawn(modal_actor(model_id.clone(), sender, receiver, rx, health));
 }
 
-pub async fn modal_list() -> Json<Vec<String>> {
-    let list: Vec<String> = get_working_servers()
+pub async fn modal_list() -> Json<Vec<ModelInfo>> {
+    let list: Vec<ModelInfo> = get_working_servers()
         .await
         .iter()
-        .map(|serv| serv.model_id.clone())
+        .map(|serv| ModelInfo {
+            model_id: serv.model_id.clone(),
+            capabilities: serv.capabilities.clone(),
+        })
         .collect();
     Json::from(list)
 }
@@ -244,7 +539,7 @@ fn get_expire() -> String {
     sqids.encode(&expire_raw).unwrap()
 }
 
-fn valid_token(token: &str) -> bool {
+pub(crate) fn valid_token(token: &str) -> bool {
     if token
         == format!(
             "{:x}",
@@ -262,7 +557,7 @@ fn valid_token(token: &str) -> bool {
     }
 }
 
-fn valid_admin_token(token: &str) -> bool {
+pub(crate) fn valid_admin_token(token: &str) -> bool {
     if token
         == format!(
             "{:x}",
@@ -314,7 +609,7 @@ pub async fn signin(Json(request): Json<AuthRequest>) -> Json<AuthResponse> {
                 response_of_failed
             }
         }
-        Role::Robot => response_of_failed,
+        Role::Robot | Role::Tool => response_of_failed,
     };
     Json::from(response)
 }
@@ -342,7 +637,7 @@ pub async fn call_command(AuthBearer(token): AuthBearer,cmd: String) -> String {
                             .find(|ser| ser.model_id == model_id)
                         {
                             let program = get_program(server);
-                            launch_worker(&program, &model_id);
+                            launch_worker(&program, server);
                             new_working_server(server.clone()).await;
                             format!("{} server start!", model_id)
                         } else {
@@ -357,8 +652,7 @@ pub async fn call_command(AuthBearer(token): AuthBearer,cmd: String) -> String {
                 if let Some((_, server)) = WORKER_HUB.remove(model_id.as_str()) {
                     let req = Request {
                         cmd: "QUIT".to_string(),
-                        system_prompt: "".to_string(),
-                        msg_list: Vec::<Message>::new(),
+                        ..Default::default()
                     };
                     server.sender.send((None, req)).await.unwrap();
                     remove_working_server(model_id.as_str()).await;
@@ -378,3 +672,116 @@ pub async fn call_command(AuthBearer(token): AuthBearer,cmd: String) -> String {
         String::from("Authentication failed. Only administrators can execute commands.")
    }
 }
+
+fn session_store() -> &'static SessionStore {
+    SESSION_STORE.get().expect("Session store is not opened!")
+}
+
+fn session_owner(token: &str) -> Result<&str, (StatusCode, String)> {
+    if valid_token(token) {
+        Ok(token)
+    } else {
+        Err((
+            StatusCode::UNAUTHORIZED,
+            String::from("Authentication failed. Please sign in first."),
+        ))
+    }
+}
+
+fn store_error(e: anyhow::Error) -> (StatusCode, String) {
+    println!("session store error: {:?}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+pub async fn list_sessions(
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Session>>, (StatusCode, String)> {
+    let owner = session_owner(token.as_str())?;
+    let sessions = session_store().list(owner).map_err(store_error)?;
+    Ok(Json::from(sessions))
+}
+
+pub async fn create_session(
+    AuthBearer(token): AuthBearer,
+    Json(session): Json<Session>,
+) -> Result<(StatusCode, Json<Session>), (StatusCode, String)> {
+    let owner = session_owner(token.as_str())?;
+    session_store().save(owner, &session).map_err(store_error)?;
+    Ok((StatusCode::CREATED, Json::from(session)))
+}
+
+pub async fn get_session(
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<String>,
+) -> Result<Json<Session>, (StatusCode, String)> {
+    let owner = session_owner(token.as_str())?;
+    match session_store().get(owner, id.as_str()).map_err(store_error)? {
+        Some(session) => Ok(Json::from(session)),
+        None => Err((StatusCode::NOT_FOUND, format!("Session {} is not exist!", id))),
+    }
+}
+
+pub async fn update_session(
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<String>,
+    Json(session): Json<Session>,
+) -> Result<Json<Session>, (StatusCode, String)> {
+    let owner = session_owner(token.as_str())?;
+    if session.id != id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Session id {} does not match {}", session.id, id),
+        ));
+    }
+    session_store().save(owner, &session).map_err(store_error)?;
+    Ok(Json::from(session))
+}
+
+pub async fn delete_session(
+    AuthBearer(token): AuthBearer,
+    Path(id): Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let owner = session_owner(token.as_str())?;
+    if session_store().remove(owner, id.as_str()).map_err(store_error)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, format!("Session {} is not exist!", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_worker_of_another_protocol_or_without_heartbeats_is_unusable() {
+        let mut health = WorkerHealth::new();
+        assert_eq!(health.unusable("model"), None);
+        health.last_seen = Instant::now() - HEARTBEAT_INTERVAL * 4;
+        assert_eq!(health.unusable("model").unwrap(), "model model server stopped answering");
+        // heartbeats coming back make it usable again, another protocol does not.
+        health.last_seen = Instant::now();
+        health.mismatch = Some(PROTOCOL_VERSION + 1);
+        assert!(health.unusable("model").unwrap().contains("speaks protocol version"));
+    }
+
+    #[test]
+    fn requests_to_an_unusable_worker_fail() {
+        let (tx, _rx) = mpsc::channel(1);
+        let health = Arc::new(Mutex::new(WorkerHealth::new()));
+        WORKER_HUB.insert(
+            "old-worker".to_string(),
+            Worker {
+                model_id: "old-worker".to_string(),
+                sender: tx,
+                health: health.clone(),
+            },
+        );
+        assert!(worker_sender("old-worker").is_ok());
+        health.lock().unwrap().mismatch = Some(PROTOCOL_VERSION + 1);
+        let reason = worker_sender("old-worker").unwrap_err();
+        assert!(reason.starts_with("old-worker model server speaks protocol version"), "{}", reason);
+        WORKER_HUB.remove("old-worker");
+        assert!(worker_sender("old-worker").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::data::{Capability, SamplingParams, Truncation};
 use lazy_static::lazy_static;
 use std::fs;
 use std::io::Read;
@@ -13,6 +14,87 @@ pub(crate) struct WorkerServer {
     pub program: String,
     pub temp: f64,
     pub top_p: f64,
+    #[serde(default = "default_capabilities")]
+    pub capabilities: Vec<Capability>,
+    #[serde(default)]
+    pub truncation: Truncation,
+    /// a Jinja file replacing the chat template of the model.
+    #[serde(default)]
+    pub chat_template: Option<String>,
+    /// quantized weights, a local .gguf file or `repo_id/file.gguf` on the hub.
+    #[serde(default)]
+    pub gguf: Option<String>,
+    /// a local directory with config.json, tokenizer.json and the weights.
+    #[serde(default)]
+    pub model_dir: Option<String>,
+    /// load from the local hub cache without downloading.
+    #[serde(default)]
+    pub offline: bool,
+    /// a small model with the same tokenizer proposing tokens for this one.
+    #[serde(default)]
+    pub draft_model: Option<String>,
+    /// tokens the draft model proposes per step.
+    #[serde(default)]
+    pub draft_tokens: Option<usize>,
+    /// sampling defaults for requests that leave them unset.
+    #[serde(default)]
+    pub sampling: SamplingParams,
+    /// cpu, cuda:N or metal.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// f32, f16 or bf16.
+    #[serde(default)]
+    pub dtype: Option<String>,
+    /// threads the worker computes on the cpu with.
+    #[serde(default)]
+    pub threads: Option<usize>,
+}
+
+fn default_capabilities() -> Vec<Capability> {
+    vec![Capability::Chat]
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct ArtifactConfig {
+    pub dir: String,
+    pub max_age_hours: Option<u64>,
+    pub quota_mb: Option<u64>,
+    /// the largest file a user may upload.
+    #[serde(default = "default_upload_limit_mb")]
+    pub upload_limit_mb: u64,
+}
+
+fn default_upload_limit_mb() -> u64 {
+    20
+}
+
+impl Default for ArtifactConfig {
+    fn default() -> Self {
+        ArtifactConfig {
+            dir: String::from("artifacts"),
+            max_age_hours: Some(24 * 7),
+            quota_mb: Some(1024),
+            upload_limit_mb: default_upload_limit_mb(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub(crate) struct DownloadConfig {
+    /// a hub mirror used instead of https://huggingface.co.
+    pub mirror: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct JobConfig {
+    /// how long a finished job can still be fetched.
+    pub ttl_minutes: u64,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        JobConfig { ttl_minutes: 60 }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -21,6 +103,18 @@ struct ServerConfig {
     pub master_addr: String,
     pub working_servers: Vec<WorkerServer>,
     pub servers: Vec<WorkerServer>,
+    #[serde(default = "default_session_db")]
+    pub session_db: String,
+    #[serde(default)]
+    pub artifacts: ArtifactConfig,
+    #[serde(default)]
+    pub downloads: DownloadConfig,
+    #[serde(default)]
+    pub jobs: JobConfig,
+}
+
+fn default_session_db() -> String {
+    String::from("sessions.db")
 }
 
 lazy_static! {
@@ -58,6 +152,22 @@ pub(crate) async fn get_master_addr() -> String {
     CONFIG.read().await.master_addr.clone()
 }
 
+pub(crate) async fn get_session_db() -> String {
+    CONFIG.read().await.session_db.clone()
+}
+
+pub(crate) async fn get_artifact_config() -> ArtifactConfig {
+    CONFIG.read().await.artifacts.clone()
+}
+
+pub(crate) async fn get_job_config() -> JobConfig {
+    CONFIG.read().await.jobs.clone()
+}
+
+pub(crate) async fn get_download_config() -> DownloadConfig {
+    CONFIG.read().await.downloads.clone()
+}
+
 pub(crate) async fn get_servers()->Vec<WorkerServer> {
     CONFIG.read().await.servers.clone()
 }
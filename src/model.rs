@@ -1,20 +1,206 @@
 use core::str;
 
-use crate::data::Message;
-use crate::llama;
+use crate::data::{Embeddings, FinishReason, Message, Role, SamplingParams, Tool, Truncation, Usage};
 use anyhow::{Error, Result};
+use crate::generation::Draft;
 use crate::ipc::OutputStream;
+use crate::registry::LoadOptions;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use std::any::Any;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+pub const DEFAULT_MAX_TOKENS: usize = 1000;
+
+/// Builds the sampler of one generation from the request parameters, falling
+/// back to the temperature and top_p the model was started with.
+pub fn logits_processor(params: &SamplingParams, temp: f64, top_p: f64) -> LogitsProcessor {
+    let temperature = params.temperature.unwrap_or(temp);
+    let top_p = params.top_p.unwrap_or(top_p);
+    let sampling = if temperature < 1e-7 {
+        Sampling::ArgMax
+    } else {
+        match (params.top_k.filter(|k| *k > 0), top_p < 1.0) {
+            (Some(k), true) => Sampling::TopKThenTopP { k, p: top_p, temperature },
+            (Some(k), false) => Sampling::TopK { k, temperature },
+            (None, true) => Sampling::TopP { p: top_p, temperature },
+            (None, false) => Sampling::All { temperature },
+        }
+    };
+    // without a seed every call samples differently, as a long lived sampler would.
+    let seed = params.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(299792458u64)
+    });
+    LogitsProcessor::from_sampling(seed, sampling)
+}
+
+/// The parameters of the `index`th of several replies to one request, whose
+/// seed is moved so the replies differ.
+pub fn choice_params(params: &SamplingParams, index: usize) -> SamplingParams {
+    let mut params = params.clone();
+    params.seed = params.seed.map(|seed| seed.wrapping_add(index as u64));
+    params
+}
+
+/// Measures one generation for its `Usage`.
+pub struct UsageMeter {
+    start: Instant,
+    first_token: Option<Instant>,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+}
+
+impl UsageMeter {
+    pub fn new(prompt_tokens: usize) -> Self {
+        UsageMeter {
+            start: Instant::now(),
+            first_token: None,
+            prompt_tokens,
+            completion_tokens: 0,
+        }
+    }
+
+    pub fn token(&mut self) {
+        if self.first_token.is_none() {
+            self.first_token = Some(Instant::now());
+        }
+        self.completion_tokens += 1;
+    }
+
+    pub fn finish(&self, finish_reason: FinishReason) -> Usage {
+        let first_token = self.first_token.unwrap_or(self.start);
+        // the first token is paid by the prompt, the speed counts the rest.
+        let decode_time = first_token.elapsed().as_secs_f64();
+        let tokens_per_second = if self.completion_tokens > 1 && decode_time > 0. {
+            (self.completion_tokens - 1) as f64 / decode_time
+        } else {
+            0.
+        };
+        let usage = Usage {
+            prompt_tokens: self.prompt_tokens,
+            completion_tokens: self.completion_tokens,
+            time_to_first_token: (first_token - self.start).as_secs_f64() * 1000.,
+            tokens_per_second,
+            finish_reason,
+        };
+        println!(
+            "\n{} prompt tokens, {} tokens generated ({:.2} token/s, first token in {:.0} ms), finish reason: {:?}",
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.tokens_per_second,
+            usage.time_to_first_token,
+            usage.finish_reason,
+        );
+        usage
+    }
+}
+
+/// A reply in the making. The worker advances all of its sequences a token
+/// at a time, so new requests are admitted between decode steps.
+pub trait Sequence {
+    /// Decodes one token and writes the text it completes to `output`.
+    /// Returns the usage once the sequence is finished.
+    fn step(&mut self, output: &dyn OutputStream) -> Result<Option<Usage>, Error>;
+    /// Lets the model find its own sequences among those it steps together.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
 
 pub trait TextGenModel {
-    fn run(&mut self, output:&dyn OutputStream,prompt: &str, sample_len: usize) -> Result<(), Error>;
-    fn messages_chat_template(&self, msg_list: &Vec<Message>, system_prompt: &str) -> String;
+    fn start(&mut self, prompt: &str, params: &SamplingParams, session_id: Option<&str>) -> Result<Box<dyn Sequence>, Error>;
+    fn run(&mut self, output:&dyn OutputStream,prompt: &str, params: &SamplingParams, session_id: Option<&str>) -> Result<Usage, Error> {
+        let mut sequence = self.start(prompt, params, session_id)?;
+        loop {
+            if let Some(usage) = sequence.step(output)? {
+                return Ok(usage);
+            }
+        }
+    }
+    /// Starts `n` replies to the same prompt, each sampled on its own.
+    fn start_n(&mut self, prompt: &str, params: &SamplingParams, session_id: Option<&str>, n: usize) -> Result<Vec<Box<dyn Sequence>>, Error> {
+        (0..n)
+            .map(|index| self.start(prompt, &choice_params(params, index), session_id))
+            .collect()
+    }
+    /// the number of tokens the model attends to, from its config.json.
+    fn max_context(&self) -> usize;
+    fn count_tokens(&self, text: &str) -> Result<usize, Error>;
+    fn messages_chat_template(&self, msg_list: &[Message], system_prompt: &str, tools: &[Tool]) -> Result<String, Error>;
+    /// The decoder of the model, to propose tokens for a bigger one.
+    fn into_draft(self: Box<Self>) -> Option<Draft> {
+        None
+    }
+    /// Lets `draft` propose `k` tokens per step, verified in one forward.
+    fn set_draft(&mut self, _draft: Draft, _k: usize) -> Result<(), Error> {
+        anyhow::bail!("this model does not support speculative decoding")
+    }
+    /// Steps each of `sequences`, writing to the output of the same index.
+    /// Models that decode sequences together in one forward override it.
+    fn step_batch(&mut self, sequences: &mut [&mut dyn Sequence], outputs: &[&dyn OutputStream]) -> Vec<Result<Option<Usage>, Error>> {
+        sequences
+            .iter_mut()
+            .zip(outputs)
+            .map(|(sequence, output)| sequence.step(*output))
+            .collect()
+    }
+}
+
+/// Templates the conversation into a prompt that leaves room in the context
+/// for the reply, shortening the conversation as `truncation` says.
+pub fn fit_context(
+    model: &dyn TextGenModel,
+    msg_list: &[Message],
+    system_prompt: &str,
+    tools: &[Tool],
+    params: &SamplingParams,
+    truncation: &Truncation,
+) -> Result<String, Error> {
+    let max_context = model.max_context();
+    let reserve = params
+        .max_tokens
+        .unwrap_or(DEFAULT_MAX_TOKENS)
+        .min(max_context / 2);
+    let budget = max_context - reserve;
+    let mut start = match truncation {
+        Truncation::KeepLast { n } => msg_list.len().saturating_sub(*n),
+        _ => 0,
+    };
+    loop {
+        let prompt = model.messages_chat_template(&msg_list[start..], system_prompt, tools)?;
+        let tokens = model.count_tokens(prompt.as_str())?;
+        if tokens <= budget {
+            if start > 0 {
+                println!("dropped {} of {} messages to fit the context", start, msg_list.len());
+            }
+            return Ok(prompt);
+        }
+        if *truncation != Truncation::DropOldest || start + 1 >= msg_list.len() {
+            anyhow::bail!(
+                "The conversation has {} tokens, but only {} of the {} token context are left for it.",
+                tokens,
+                budget,
+                max_context
+            );
+        }
+        // drop a whole turn, the conversation should start with the user.
+        start += 1;
+        while start + 1 < msg_list.len() && msg_list[start].role != Role::User {
+            start += 1;
+        }
+    }
+}
+
+pub trait TextEmbedModel {
+    fn embed(&mut self, input: &[String]) -> Result<Embeddings, Error>;
 }
 
-pub fn load(model_id: &str, temp: f64, top_p: f64) -> Option<Box<dyn TextGenModel>> {
+pub fn load_embed(model_id: &str, options: &LoadOptions) -> Result<Option<Box<dyn TextEmbedModel>>> {
     match model_id {
-        "meta-llama/Meta-Llama-3-8B-Instruct" => Some(Box::new(llama::load_model(model_id, temp, top_p))),
-        "microsoft/Phi-3-medium-4k-instruct" => Some(Box::new(crate::phi3::load())),
-        _ => None,
+        "BAAI/bge-small-en-v1.5"
+        | "BAAI/bge-base-en-v1.5"
+        | "BAAI/bge-large-en-v1.5"
+        | "sentence-transformers/all-MiniLM-L6-v2" => Ok(Some(Box::new(crate::bert::load(model_id, options)?))),
+        _ => Ok(None),
     }
 }
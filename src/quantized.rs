@@ -0,0 +1,108 @@
+use anyhow::Result;
+use candle_core::quantized::gguf_file;
+use candle_core::Device;
+
+use crate::generation::{ChatModel, Generator};
+use crate::model::TextGenModel;
+use crate::registry::{select_device, LoadOptions, ModelFiles};
+use crate::transformer::{Transformer, TransformerDecoder};
+
+/// The device of the options. The weights keep the types of the gguf file.
+fn quantized_device(options: &LoadOptions) -> Result<Device> {
+    if let Some(dtype) = &options.dtype {
+        anyhow::bail!("dtype {} does not apply to gguf weights, which are quantized", dtype);
+    }
+    Ok(select_device(options)?.0)
+}
+
+/// Reads the gguf file of `files`, with the context length its metadata
+/// gives for `architecture`.
+fn read_gguf(files: &ModelFiles, architecture: &str) -> Result<(gguf_file::Content, std::fs::File, usize)> {
+    let path = match files.weights.first() {
+        Some(path) => path,
+        None => anyhow::bail!("no gguf file to load"),
+    };
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(path))?;
+    let max_context = match content.metadata.get(&format!("{architecture}.context_length")) {
+        Some(value) => value.to_u32()? as usize,
+        None => files.config::<serde_json::Value>()?["max_position_embeddings"]
+            .as_u64()
+            .unwrap_or(4096) as usize,
+    };
+    Ok((content, file, max_context))
+}
+
+/// The `general.architecture` of a gguf file, which picks its loader.
+pub fn architecture(path: &std::path::Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(path))?;
+    match content.metadata.get("general.architecture") {
+        Some(value) => Ok(value.to_string()?.clone()),
+        None => anyhow::bail!("no general.architecture in {path:?}"),
+    }
+}
+
+/// Also loads mistral, whose gguf files use the llama architecture.
+pub fn load_llama(files: &ModelFiles, options: &LoadOptions) -> Result<Box<dyn TextGenModel>> {
+    let device = quantized_device(options)?;
+    let (content, mut file, max_context) = read_gguf(files, "llama")?;
+    let model = Transformer::load_gguf(&content, &mut file, &device)?;
+    let tokenizer = files.tokenizer()?;
+    let eos_tokens = files.eos_tokens(&tokenizer, &["<|eot_id|>", "</s>"])?;
+    let generator = Generator::new(
+        TransformerDecoder::new(model),
+        tokenizer,
+        eos_tokens,
+        options.temp,
+        options.top_p,
+        1.1f32,
+        64usize,
+        max_context,
+    )
+    .with_chat_template(files.chat_template(options)?)
+    .with_sampling(options.sampling.clone());
+    Ok(Box::new(ChatModel::new(generator, crate::llama::llama3_chat_template)))
+}
+
+pub fn load_phi3(files: &ModelFiles, options: &LoadOptions) -> Result<Box<dyn TextGenModel>> {
+    let device = quantized_device(options)?;
+    let (content, mut file, max_context) = read_gguf(files, "phi3")?;
+    let model = Transformer::load_gguf(&content, &mut file, &device)?;
+    let tokenizer = files.tokenizer()?;
+    let eos_tokens = files.eos_tokens(&tokenizer, &["<|end|>"])?;
+    let generator = Generator::new(
+        TransformerDecoder::new(model),
+        tokenizer,
+        eos_tokens,
+        options.temp,
+        options.top_p,
+        1.1f32,
+        64usize,
+        max_context,
+    )
+    .with_chat_template(files.chat_template(options)?)
+    .with_sampling(options.sampling.clone());
+    Ok(Box::new(ChatModel::new(generator, crate::phi3::phi3_chat_template)))
+}
+
+pub fn load_qwen2(files: &ModelFiles, options: &LoadOptions) -> Result<Box<dyn TextGenModel>> {
+    let device = quantized_device(options)?;
+    let (content, mut file, max_context) = read_gguf(files, "qwen2")?;
+    let model = Transformer::load_gguf(&content, &mut file, &device)?;
+    let tokenizer = files.tokenizer()?;
+    let eos_tokens = files.eos_tokens(&tokenizer, &["<|im_end|>"])?;
+    let generator = Generator::new(
+        TransformerDecoder::new(model),
+        tokenizer,
+        eos_tokens,
+        options.temp,
+        options.top_p,
+        1.1f32,
+        64usize,
+        max_context,
+    )
+    .with_chat_template(files.chat_template(options)?)
+    .with_sampling(options.sampling.clone());
+    Ok(Box::new(ChatModel::new(generator, crate::qwen2::chatml_chat_template)))
+}
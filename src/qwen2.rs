@@ -0,0 +1,69 @@
+use anyhow::Result;
+use candle_transformers::models::qwen2::Config;
+
+use crate::data::{Message, Role};
+use crate::generation::{ChatModel, Generator};
+use crate::model::TextGenModel;
+use crate::registry::{select_device, LoadOptions, ModelFiles};
+use crate::transformer::{self, Layout, Rope, Transformer, TransformerDecoder};
+
+pub fn transformer_config(config: &Config) -> transformer::Config {
+    let head_dim = config.hidden_size / config.num_attention_heads;
+    transformer::Config {
+        layout: Layout::Llama,
+        vocab_size: config.vocab_size,
+        hidden_size: config.hidden_size,
+        intermediate_size: config.intermediate_size,
+        num_hidden_layers: config.num_hidden_layers,
+        num_attention_heads: config.num_attention_heads,
+        num_key_value_heads: config.num_key_value_heads,
+        head_dim,
+        rms_norm_eps: config.rms_norm_eps,
+        hidden_act: config.hidden_act,
+        rope: Rope::new(head_dim, config.rope_theta),
+        sliding_window: config.use_sliding_window.then_some(config.sliding_window),
+        attention_bias: true,
+        tie_word_embeddings: config.tie_word_embeddings,
+    }
+}
+
+/// Used when the model ships without a chat template.
+pub(crate) fn chatml_chat_template(msg_list: &[Message], system_prompt: &str) -> String {
+    let mut history = String::new();
+    history.push_str("<|im_start|>system\n");
+    history.push_str(system_prompt);
+    history.push_str("<|im_end|>\n");
+    for msg in msg_list {
+        history.push_str("<|im_start|>");
+        if msg.role == Role::User {
+            history.push_str("user\n");
+        } else {
+            history.push_str("assistant\n");
+        }
+        history.push_str(msg.content.as_str());
+        history.push_str("<|im_end|>\n");
+    }
+    history.push_str("<|im_start|>assistant\n");
+    history
+}
+
+pub fn load(files: &ModelFiles, options: &LoadOptions) -> Result<Box<dyn TextGenModel>> {
+    let (device, dtype) = select_device(options)?;
+    let config: Config = files.config()?;
+    let model = Transformer::load(files.var_builder(dtype, &device)?, &transformer_config(&config))?;
+    let tokenizer = files.tokenizer()?;
+    let eos_tokens = files.eos_tokens(&tokenizer, &["<|im_end|>"])?;
+    let generator = Generator::new(
+        TransformerDecoder::new(model),
+        tokenizer,
+        eos_tokens,
+        options.temp,
+        options.top_p,
+        1.1f32,
+        64usize,
+        config.max_position_embeddings,
+    )
+    .with_chat_template(files.chat_template(options)?)
+    .with_sampling(options.sampling.clone());
+    Ok(Box::new(ChatModel::new(generator, chatml_chat_template)))
+}
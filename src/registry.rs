@@ -0,0 +1,440 @@
+use anyhow::{Error, Result};
+use candle_core::utils::{cuda_is_available, metal_is_available};
+use candle_core::{DType, Device};
+use candle_nn::VarBuilder;
+use dashmap::DashMap;
+use hf_hub::api::sync::{Api, ApiRepo};
+use hf_hub::{Cache, CacheRepo, Repo, RepoType};
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::path::PathBuf;
+use tokenizers::Tokenizer;
+
+use crate::chat_template::ChatTemplate;
+use crate::data::SamplingParams;
+use crate::generation::Draft;
+use crate::model::TextGenModel;
+
+/// Settings of a worker that apply to every architecture.
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    pub temp: f64,
+    pub top_p: f64,
+    /// a Jinja file used instead of the chat template of the model.
+    pub chat_template: Option<String>,
+    /// quantized weights to load instead of the safetensors, a local path or
+    /// `repo_id/file.gguf` on the hub.
+    pub gguf: Option<String>,
+    /// a local directory with the files of the model, used instead of the hub.
+    pub model_dir: Option<String>,
+    /// only read the hub cache, never download.
+    pub offline: bool,
+    /// a smaller model with the same tokenizer for speculative decoding.
+    pub draft_model: Option<String>,
+    /// tokens the draft model proposes per step, `DRAFT_TOKENS` when unset.
+    pub draft_tokens: Option<usize>,
+    /// what requests leave unset, from the worker config.
+    pub sampling: SamplingParams,
+    /// cpu, cuda, cuda:N, metal or metal:N; a cuda device when there is one.
+    pub device: Option<String>,
+    /// f32, f16 or bf16 for the weights of safetensors models.
+    pub dtype: Option<String>,
+}
+
+pub const DRAFT_TOKENS: usize = 4;
+
+/// Builds the model of one `model_type` from the files of its repo.
+pub type Loader = fn(&ModelFiles, &LoadOptions) -> Result<Box<dyn TextGenModel>>;
+
+lazy_static! {
+    static ref LOADERS: DashMap<String, Loader> = default_loaders();
+    /// keyed by the `general.architecture` of the gguf file.
+    static ref QUANTIZED_LOADERS: DashMap<String, Loader> = default_quantized_loaders();
+}
+
+fn default_loaders() -> DashMap<String, Loader> {
+    let loaders = DashMap::<String, Loader>::new();
+    loaders.insert("llama".to_string(), crate::llama::load as Loader);
+    loaders.insert("phi3".to_string(), crate::phi3::load as Loader);
+    loaders.insert("qwen2".to_string(), crate::qwen2::load as Loader);
+    loaders.insert("mistral".to_string(), crate::mistral::load as Loader);
+    loaders.insert("gemma".to_string(), crate::gemma::load as Loader);
+    loaders
+}
+
+fn default_quantized_loaders() -> DashMap<String, Loader> {
+    let loaders = DashMap::<String, Loader>::new();
+    loaders.insert("llama".to_string(), crate::quantized::load_llama as Loader);
+    loaders.insert("phi3".to_string(), crate::quantized::load_phi3 as Loader);
+    loaders.insert("qwen2".to_string(), crate::quantized::load_qwen2 as Loader);
+    loaders
+}
+
+/// Adds or replaces the loader of an architecture.
+pub fn register(model_type: &str, loader: Loader) {
+    LOADERS.insert(model_type.to_string(), loader);
+}
+
+/// Adds or replaces the loader of a gguf architecture.
+pub fn register_quantized(architecture: &str, loader: Loader) {
+    QUANTIZED_LOADERS.insert(architecture.to_string(), loader);
+}
+
+/// Loads `model_id` with the loader of the `model_type` in its config.json.
+/// `None` when no loader knows the architecture.
+pub fn load(model_id: &str, options: &LoadOptions) -> Result<Option<Box<dyn TextGenModel>>> {
+    let source = ModelSource::new(model_id, options)?;
+    let mut files = FileCheck::new(&source);
+    let config = files.require("config.json");
+    let tokenizer = files.require("tokenizer.json");
+    let (loader, weights) = match &options.gguf {
+        // the weights come from the gguf file, the rest from the model repo.
+        Some(gguf) => {
+            let gguf = gguf_file(&source, gguf, options)?;
+            let architecture = crate::quantized::architecture(&gguf)?;
+            let loader = match QUANTIZED_LOADERS.get(architecture.as_str()) {
+                Some(loader) => *loader,
+                None => anyhow::bail!("no quantized loader for the {} architecture", architecture),
+            };
+            println!("loading {} as quantized {} from {:?}", model_id, architecture, gguf);
+            (Some(loader), vec![gguf])
+        }
+        None => {
+            let loader = match &config {
+                Some(config) => {
+                    let model_type = model_type(config)?;
+                    match LOADERS.get(model_type.as_str()) {
+                        Some(loader) => {
+                            println!("loading {} as {}", model_id, model_type);
+                            Some(*loader)
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                None => None,
+            };
+            (loader, files.safetensors())
+        }
+    };
+    files.finish()?;
+    let files = ModelFiles {
+        config: config.unwrap_or_default(),
+        tokenizer: tokenizer.unwrap_or_default(),
+        tokenizer_config: source.get("tokenizer_config.json").ok(),
+        generation_config: source.get("generation_config.json").ok(),
+        weights,
+    };
+    let mut model = match loader {
+        Some(loader) => loader(&files, options)?,
+        None => return Ok(None),
+    };
+    if let Some(draft_model) = &options.draft_model {
+        let draft_tokens = options.draft_tokens.unwrap_or(DRAFT_TOKENS);
+        model.set_draft(load_draft(draft_model, options)?, draft_tokens)?;
+        println!("{} proposes tokens for {}", draft_model, model_id);
+    }
+    Ok(Some(model))
+}
+
+/// Loads `draft_model` from the hub, or the hub cache when offline.
+fn load_draft(draft_model: &str, options: &LoadOptions) -> Result<Draft> {
+    let draft_options = LoadOptions {
+        offline: options.offline,
+        device: options.device.clone(),
+        dtype: options.dtype.clone(),
+        ..Default::default()
+    };
+    let draft = match load(draft_model, &draft_options)? {
+        Some(draft) => draft,
+        None => anyhow::bail!("no loader for the draft model {}", draft_model),
+    };
+    match draft.into_draft() {
+        Some(draft) => Ok(draft),
+        None => anyhow::bail!("{} can not be a draft model", draft_model),
+    }
+}
+
+fn model_type(config_file: &PathBuf) -> Result<String> {
+    let config: Value = serde_json::from_str(&std::fs::read_to_string(config_file)?)?;
+    match config.get("model_type").and_then(Value::as_str) {
+        Some(model_type) => Ok(model_type.to_string()),
+        None => anyhow::bail!("no model_type in {config_file:?}"),
+    }
+}
+
+/// A local gguf file, one in the model directory, or the `file` of
+/// `repo_id/file` from the hub.
+fn gguf_file(source: &ModelSource, gguf: &str, options: &LoadOptions) -> Result<PathBuf> {
+    let path = PathBuf::from(gguf);
+    if path.exists() {
+        return Ok(path);
+    }
+    if let ModelSource::Dir(dir) = source {
+        return source.get(gguf).map_err(|_| {
+            Error::msg(format!("{} is neither a file nor in {:?}", gguf, dir))
+        });
+    }
+    match gguf.rsplit_once('/') {
+        Some((repo_id, file)) => ModelSource::new(repo_id, options)?.get(file),
+        None => anyhow::bail!("{} is neither a file nor a hub file", gguf),
+    }
+}
+
+/// Where the files of a model are read from.
+pub enum ModelSource {
+    Hub(ApiRepo, String),
+    /// the hub cache, for servers that can not download.
+    Cache(CacheRepo, String),
+    Dir(PathBuf),
+}
+
+impl ModelSource {
+    pub fn new(model_id: &str, options: &LoadOptions) -> Result<Self> {
+        if let Some(dir) = &options.model_dir {
+            let dir = PathBuf::from(dir);
+            if !dir.is_dir() {
+                anyhow::bail!("the model directory {:?} of {} does not exist", dir, model_id);
+            }
+            return Ok(ModelSource::Dir(dir));
+        }
+        let repo = Repo::with_revision(model_id.to_string(), RepoType::Model, String::from("main"));
+        if options.offline {
+            Ok(ModelSource::Cache(Cache::default().repo(repo), model_id.to_string()))
+        } else {
+            Ok(ModelSource::Hub(Api::new()?.repo(repo), model_id.to_string()))
+        }
+    }
+
+    pub fn get(&self, file: &str) -> Result<PathBuf> {
+        match self {
+            ModelSource::Hub(repo, _) => Ok(repo.get(file)?),
+            ModelSource::Cache(repo, _) => match repo.get(file) {
+                Some(path) => Ok(path),
+                None => anyhow::bail!("{} is not in the hub cache", file),
+            },
+            ModelSource::Dir(dir) => {
+                let path = dir.join(file);
+                if path.is_file() {
+                    Ok(path)
+                } else {
+                    anyhow::bail!("{:?} does not exist", path)
+                }
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            ModelSource::Hub(_, model_id) => format!("the hub repo {}", model_id),
+            ModelSource::Cache(_, model_id) => {
+                format!("{} in the hub cache {:?}", model_id, Cache::default().path())
+            }
+            ModelSource::Dir(dir) => format!("{:?}", dir),
+        }
+    }
+}
+
+/// Gets the files of a model, remembering the missing ones so they are all
+/// reported at once.
+pub struct FileCheck<'a> {
+    source: &'a ModelSource,
+    missing: Vec<String>,
+}
+
+impl<'a> FileCheck<'a> {
+    pub fn new(source: &'a ModelSource) -> Self {
+        FileCheck {
+            source,
+            missing: Vec::new(),
+        }
+    }
+
+    pub fn require(&mut self, file: &str) -> Option<PathBuf> {
+        match self.source.get(file) {
+            Ok(path) => Some(path),
+            Err(e) => {
+                println!("{}: {}", file, e);
+                self.missing.push(file.to_string());
+                None
+            }
+        }
+    }
+
+    /// The shards listed in model.safetensors.index.json, or model.safetensors.
+    pub fn safetensors(&mut self) -> Vec<PathBuf> {
+        let index = match self.source.get("model.safetensors.index.json") {
+            Ok(index) => index,
+            Err(_) => match self.source.get("model.safetensors") {
+                Ok(weights) => return vec![weights],
+                Err(_) => {
+                    self.missing
+                        .push("model.safetensors.index.json or model.safetensors".to_string());
+                    return Vec::new();
+                }
+            },
+        };
+        let mut shards = std::collections::BTreeSet::new();
+        match read_weight_map(&index) {
+            Ok(files) => shards.extend(files),
+            Err(e) => {
+                println!("{:?}: {}", index, e);
+                self.missing.push("model.safetensors.index.json".to_string());
+            }
+        }
+        shards.iter().filter_map(|file| self.require(file)).collect()
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.missing.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!(
+                "{} is missing {}",
+                self.source.describe(),
+                self.missing.join(", ")
+            )
+        }
+    }
+}
+
+fn read_weight_map(index: &PathBuf) -> Result<Vec<String>> {
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(index)?)?;
+    match json.get("weight_map") {
+        Some(Value::Object(map)) => Ok(map
+            .values()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect()),
+        Some(_) => anyhow::bail!("weight map in {index:?} is not a map"),
+        None => anyhow::bail!("no weight map in {index:?}"),
+    }
+}
+
+/// The files of a model repo the loaders read. `weights` are safetensors, or
+/// the gguf file for the quantized loaders.
+pub struct ModelFiles {
+    pub config: PathBuf,
+    pub tokenizer: PathBuf,
+    pub tokenizer_config: Option<PathBuf>,
+    pub generation_config: Option<PathBuf>,
+    pub weights: Vec<PathBuf>,
+}
+
+impl ModelFiles {
+    pub fn config<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(serde_json::from_str(&std::fs::read_to_string(&self.config)?)?)
+    }
+
+    pub fn tokenizer(&self) -> Result<Tokenizer> {
+        Tokenizer::from_file(&self.tokenizer).map_err(Error::msg)
+    }
+
+    pub fn chat_template(&self, options: &LoadOptions) -> Result<Option<ChatTemplate>> {
+        ChatTemplate::load(self.tokenizer_config.as_deref(), options.chat_template.as_deref())
+    }
+
+    pub fn var_builder(&self, dtype: DType, device: &Device) -> Result<VarBuilder<'static>> {
+        Ok(unsafe { VarBuilder::from_mmaped_safetensors(&self.weights, dtype, device)? })
+    }
+
+    /// The ids that end a reply: the `eos_token_id`s of the configs and the
+    /// end of turn tokens in `extra` the tokenizer knows.
+    pub fn eos_tokens(&self, tokenizer: &Tokenizer, extra: &[&str]) -> Result<Vec<u32>> {
+        let mut eos_tokens = Vec::new();
+        for file in [Some(&self.config), self.generation_config.as_ref()].into_iter().flatten() {
+            let config: Value = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+            match config.get("eos_token_id") {
+                Some(Value::Number(id)) => eos_tokens.extend(id.as_u64().map(|id| id as u32)),
+                Some(Value::Array(ids)) => {
+                    eos_tokens.extend(ids.iter().filter_map(Value::as_u64).map(|id| id as u32))
+                }
+                _ => {}
+            }
+        }
+        eos_tokens.extend(extra.iter().filter_map(|token| tokenizer.token_to_id(token)));
+        eos_tokens.sort();
+        eos_tokens.dedup();
+        Ok(eos_tokens)
+    }
+}
+
+/// The device and dtype of the options. Unset, the first cuda device in bf16
+/// when moonweb is built with cuda and there is one, otherwise the cpu in f32.
+pub fn select_device(options: &LoadOptions) -> Result<(Device, DType)> {
+    let device = match options.device.as_deref() {
+        Some(name) => parse_device(name)?,
+        None if cuda_is_available() => match Device::new_cuda(0) {
+            Ok(device) => device,
+            Err(e) => {
+                println!("built with cuda, but there is no cuda device ({}), running on the cpu", e);
+                Device::Cpu
+            }
+        },
+        None => Device::Cpu,
+    };
+    let dtype = match options.dtype.as_deref() {
+        Some(name) => parse_dtype(name)?,
+        None if device.is_cuda() => DType::BF16,
+        None if device.is_metal() => DType::F16,
+        None => DType::F32,
+    };
+    Ok((device, dtype))
+}
+
+pub fn parse_device(name: &str) -> Result<Device> {
+    let (kind, ordinal) = match name.split_once(':') {
+        Some((kind, ordinal)) => match ordinal.parse::<usize>() {
+            Ok(ordinal) => (kind, ordinal),
+            Err(_) => anyhow::bail!("invalid device {}, the ordinal is not a number", name),
+        },
+        None => (name, 0),
+    };
+    match kind.to_lowercase().as_str() {
+        "cpu" => Ok(Device::Cpu),
+        "cuda" if !cuda_is_available() => {
+            anyhow::bail!("{} requested, but moonweb was built without the cuda feature", name)
+        }
+        "cuda" => Device::new_cuda(ordinal)
+            .map_err(|e| Error::msg(format!("moonweb was built with cuda, but can not open {}: {}", name, e))),
+        "metal" if !metal_is_available() => {
+            anyhow::bail!("{} requested, but moonweb was built without the metal feature", name)
+        }
+        "metal" => Device::new_metal(ordinal)
+            .map_err(|e| Error::msg(format!("moonweb was built with metal, but can not open {}: {}", name, e))),
+        _ => anyhow::bail!("unknown device {}, expected cpu, cuda:N or metal", name),
+    }
+}
+
+pub fn parse_dtype(name: &str) -> Result<DType> {
+    match name.to_lowercase().as_str() {
+        "f32" => Ok(DType::F32),
+        "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        _ => anyhow::bail!("unknown dtype {}, expected f32, f16 or bf16", name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_gpu_left_out_of_the_build_is_told_apart() {
+        assert!(parse_device("cpu").unwrap().is_cpu());
+        for (name, feature) in [("cuda:0", "cuda"), ("metal", "metal")] {
+            let available = match feature {
+                "cuda" => cuda_is_available(),
+                _ => metal_is_available(),
+            };
+            match parse_device(name) {
+                Ok(_) => assert!(available),
+                Err(e) if available => {
+                    assert!(e.to_string().starts_with(&format!("moonweb was built with {feature}")), "{e}")
+                }
+                Err(e) => assert!(e.to_string().ends_with(&format!("without the {feature} feature")), "{e}"),
+            }
+        }
+        assert!(parse_device("tpu").is_err());
+    }
+}
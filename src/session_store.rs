@@ -0,0 +1,314 @@
+use crate::data::{Message, Role, SamplingParams, Session};
+use anyhow::{Error, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::sync::Mutex;
+
+/// Conversations of the signed-in users, kept in a SQLite database so that
+/// they survive across browsers and machines. Every row is scoped by the
+/// owner, which is the auth key the user signed in with.
+pub struct SessionStore {
+    conn: Mutex<Connection>,
+}
+
+impl SessionStore {
+    pub fn open(path: &str) -> Result<SessionStore> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                owner TEXT NOT NULL,
+                id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                mode_id TEXT NOT NULL,
+                system_prompt TEXT NOT NULL,
+                params TEXT NOT NULL DEFAULT '{}',
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (owner, id)
+            );
+            CREATE TABLE IF NOT EXISTS messages (
+                owner TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                id INTEGER NOT NULL,
+                role TEXT NOT NULL,
+                content TEXT NOT NULL,
+                img TEXT,
+                attachment TEXT,
+                usage TEXT,
+                tool_calls TEXT,
+                tool_call_id TEXT,
+                PRIMARY KEY (owner, session_id, seq)
+            );",
+        )?;
+        add_column_if_missing(&conn, "sessions", "params", "TEXT NOT NULL DEFAULT '{}'")?;
+        add_column_if_missing(&conn, "messages", "attachment", "TEXT")?;
+        add_column_if_missing(&conn, "messages", "usage", "TEXT")?;
+        add_column_if_missing(&conn, "messages", "tool_calls", "TEXT")?;
+        add_column_if_missing(&conn, "messages", "tool_call_id", "TEXT")?;
+        Ok(SessionStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn list(&self, owner: &str) -> Result<Vec<Session>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, name, mode_id, system_prompt, params FROM sessions
+             WHERE owner = ?1 ORDER BY updated_at",
+        )?;
+        let sessions = stmt
+            .query_map(params![owner], session_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(sessions)
+    }
+
+    pub fn get(&self, owner: &str, id: &str) -> Result<Option<Session>> {
+        let conn = self.conn.lock().unwrap();
+        let session = conn
+            .query_row(
+                "SELECT id, name, mode_id, system_prompt, params FROM sessions
+                 WHERE owner = ?1 AND id = ?2",
+                params![owner, id],
+                session_from_row,
+            )
+            .optional()?;
+        let mut session = match session {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        let mut stmt = conn.prepare(
+            "SELECT id, role, content, img, attachment, usage, tool_calls, tool_call_id FROM messages
+             WHERE owner = ?1 AND session_id = ?2 ORDER BY seq",
+        )?;
+        let history = stmt
+            .query_map(params![owner, id], |row| {
+                let role: String = row.get(1)?;
+                let usage: Option<String> = row.get(5)?;
+                let tool_calls: Option<String> = row.get(6)?;
+                Ok(Message {
+                    id: row.get::<_, i64>(0)? as usize,
+                    role: role.parse().unwrap_or(Role::User),
+                    content: row.get(2)?,
+                    img: row.get(3)?,
+                    attachment: row.get(4)?,
+                    loading: false,
+                    usage: usage.and_then(|usage| serde_json::from_str(usage.as_str()).ok()),
+                    tool_calls: tool_calls
+                        .and_then(|tool_calls| serde_json::from_str(tool_calls.as_str()).ok())
+                        .unwrap_or_default(),
+                    tool_call_id: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        session.history = Some(history);
+        Ok(Some(session))
+    }
+
+    /// Inserts or replaces a session. The stored history is only replaced when
+    /// the session carries one, so renaming a session keeps its messages.
+    pub fn save(&self, owner: &str, session: &Session) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO sessions (owner, id, name, mode_id, system_prompt, params, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT (owner, id) DO UPDATE SET
+                name = excluded.name,
+                mode_id = excluded.mode_id,
+                system_prompt = excluded.system_prompt,
+                params = excluded.params,
+                updated_at = excluded.updated_at",
+            params![
+                owner,
+                session.id,
+                session.name,
+                session.mode_id,
+                session.system_prompt,
+                serde_json::to_string(&session.params)?,
+                chrono::Utc::now().timestamp_millis()
+            ],
+        )?;
+        if let Some(ref history) = session.history {
+            tx.execute(
+                "DELETE FROM messages WHERE owner = ?1 AND session_id = ?2",
+                params![owner, session.id],
+            )?;
+            for (seq, msg) in history.iter().filter(|msg| !msg.loading).enumerate() {
+                tx.execute(
+                    "INSERT INTO messages (owner, session_id, seq, id, role, content, img, attachment, usage, tool_calls, tool_call_id)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                    params![
+                        owner,
+                        session.id,
+                        seq as i64,
+                        msg.id as i64,
+                        format!("{:?}", msg.role),
+                        msg.content,
+                        msg.img,
+                        msg.attachment,
+                        msg.usage.as_ref().map(|usage| serde_json::json!(usage).to_string()),
+                        (!msg.tool_calls.is_empty()).then(|| serde_json::json!(msg.tool_calls).to_string()),
+                        msg.tool_call_id
+                    ],
+                )?;
+            }
+        }
+        tx.commit().map_err(Error::new)
+    }
+
+    pub fn remove(&self, owner: &str, id: &str) -> Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM messages WHERE owner = ?1 AND session_id = ?2",
+            params![owner, id],
+        )?;
+        let removed = tx.execute(
+            "DELETE FROM sessions WHERE owner = ?1 AND id = ?2",
+            params![owner, id],
+        )?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+}
+
+fn session_from_row(row: &rusqlite::Row) -> rusqlite::Result<Session> {
+    let params: String = row.get(4)?;
+    Ok(Session {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        mode_id: row.get(2)?,
+        system_prompt: row.get(3)?,
+        params: serde_json::from_str::<SamplingParams>(params.as_str()).unwrap_or_default(),
+        history: None,
+    })
+}
+
+/// Databases created by older versions lack the newer columns.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let mut stmt = conn.prepare(format!("PRAGMA table_info({})", table).as_str())?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl).as_str(), [])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{FunctionCall, ToolCall};
+
+    fn temp_store(name: &str) -> SessionStore {
+        let path = std::env::temp_dir().join(format!("session_store_{}_{}.db", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        SessionStore::open(path.to_str().unwrap()).unwrap()
+    }
+
+    fn message(id: usize, role: Role, content: &str) -> Message {
+        Message {
+            id,
+            role,
+            content: content.to_string(),
+            img: None,
+            attachment: None,
+            loading: false,
+            usage: None,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    fn session(id: &str, name: &str, history: Option<Vec<Message>>) -> Session {
+        Session {
+            id: id.to_string(),
+            name: name.to_string(),
+            mode_id: "chat".to_string(),
+            system_prompt: "be brief".to_string(),
+            history,
+            params: SamplingParams {
+                temperature: Some(0.2),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn saved_sessions_read_back() {
+        let store = temp_store("round_trip");
+        let history = vec![message(0, Role::User, "hi"), message(1, Role::Robot, "hello")];
+        store.save("alice", &session("a", "first", Some(history.clone()))).unwrap();
+        store.save("alice", &session("b", "second", Some(Vec::new()))).unwrap();
+
+        let got = store.get("alice", "a").unwrap().unwrap();
+        assert_eq!(got, session("a", "first", Some(history)));
+        let names: Vec<String> = store.list("alice").unwrap().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, ["first", "second"]);
+        assert!(store.list("alice").unwrap().iter().all(|s| s.history.is_none()));
+    }
+
+    #[test]
+    fn loading_messages_are_not_saved() {
+        let store = temp_store("loading");
+        let mut pending = message(1, Role::Robot, "");
+        pending.loading = true;
+        store
+            .save("alice", &session("a", "first", Some(vec![message(0, Role::User, "hi"), pending])))
+            .unwrap();
+        let history = store.get("alice", "a").unwrap().unwrap().history.unwrap();
+        assert_eq!(history, [message(0, Role::User, "hi")]);
+    }
+
+    #[test]
+    fn tool_calls_read_back() {
+        let store = temp_store("tool_calls");
+        let mut call = message(1, Role::Robot, "");
+        call.tool_calls = vec![ToolCall {
+            id: "call_0".to_string(),
+            kind: "function".to_string(),
+            function: FunctionCall {
+                name: "weather".to_string(),
+                arguments: r#"{"city":"Paris"}"#.to_string(),
+            },
+        }];
+        let mut result = message(2, Role::Tool, "sunny");
+        result.tool_call_id = Some("call_0".to_string());
+        let history = vec![message(0, Role::User, "weather in Paris?"), call, result];
+        store.save("alice", &session("a", "first", Some(history.clone()))).unwrap();
+        assert_eq!(store.get("alice", "a").unwrap().unwrap().history, Some(history));
+    }
+
+    #[test]
+    fn sessions_are_scoped_by_owner() {
+        let store = temp_store("owner");
+        store.save("alice", &session("a", "mine", Some(vec![message(0, Role::User, "hi")]))).unwrap();
+        store.save("bob", &session("a", "his", None)).unwrap();
+
+        assert_eq!(store.get("alice", "a").unwrap().unwrap().name, "mine");
+        assert_eq!(store.get("bob", "a").unwrap().unwrap().name, "his");
+        assert_eq!(store.get("bob", "a").unwrap().unwrap().history, Some(Vec::new()));
+        assert!(store.get("carol", "a").unwrap().is_none());
+        assert!(store.list("carol").unwrap().is_empty());
+
+        assert!(!store.remove("carol", "a").unwrap());
+        assert!(store.remove("bob", "a").unwrap());
+        assert!(store.get("bob", "a").unwrap().is_none());
+        assert_eq!(store.get("alice", "a").unwrap().unwrap().history.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn renaming_keeps_messages() {
+        let store = temp_store("rename");
+        let history = vec![message(0, Role::User, "hi"), message(1, Role::Robot, "hello")];
+        store.save("alice", &session("a", "first", Some(history.clone()))).unwrap();
+        store.save("alice", &session("a", "renamed", None)).unwrap();
+
+        let got = store.get("alice", "a").unwrap().unwrap();
+        assert_eq!(got.name, "renamed");
+        assert_eq!(got.history, Some(history));
+        assert_eq!(store.list("alice").unwrap().len(), 1);
+    }
+}
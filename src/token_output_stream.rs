@@ -7,6 +7,8 @@ pub struct TokenOutputStream {
     tokens: Vec<u32>,
     prev_index: usize,
     current_index: usize,
+    stop: Vec<String>,
+    held: String,
 }
 
 impl TokenOutputStream {
@@ -16,6 +18,8 @@ impl TokenOutputStream {
             tokens: Vec::new(),
             prev_index: 0,
             current_index: 0,
+            stop: Vec::new(),
+            held: String::new(),
         }
     }
 
@@ -53,6 +57,57 @@ impl TokenOutputStream {
         }
     }
 
+    pub fn set_stop_sequences(&mut self, stop: &[String]) {
+        self.stop = stop.iter().filter(|s| !s.is_empty()).cloned().collect();
+    }
+
+    /// Like `next_token`, but checks the decoded text for the stop sequences.
+    /// Text that could be the start of a stop sequence is held back until the
+    /// following tokens decide it. Returns the text to emit and whether a stop
+    /// sequence was hit, in which case it is dropped with everything after it.
+    pub fn next_token_until_stop(&mut self, token: u32) -> Result<(Option<String>, bool)> {
+        match self.next_token(token)? {
+            Some(text) => Ok(self.release(text.as_str())),
+            None => Ok((None, false)),
+        }
+    }
+
+    /// The held back and not yet decoded text at the end of a generation.
+    pub fn finish(&mut self) -> Result<(Option<String>, bool)> {
+        let rest = self.decode_rest()?.unwrap_or_default();
+        let (text, stopped) = self.release(rest.as_str());
+        let held = std::mem::take(&mut self.held);
+        let text = match (text, held.is_empty()) {
+            (text, true) => text,
+            (Some(text), false) => Some(text + held.as_str()),
+            (None, false) => Some(held),
+        };
+        Ok((text, stopped))
+    }
+
+    fn release(&mut self, text: &str) -> (Option<String>, bool) {
+        self.held.push_str(text);
+        let found = self
+            .stop
+            .iter()
+            .filter_map(|stop| self.held.find(stop.as_str()))
+            .min();
+        if let Some(pos) = found {
+            let text = self.held[..pos].to_string();
+            self.held.clear();
+            return (Some(text).filter(|t| !t.is_empty()), true);
+        }
+        let keep = self
+            .stop
+            .iter()
+            .map(|stop| partial_match(self.held.as_str(), stop.as_str()))
+            .max()
+            .unwrap_or(0);
+        let rest = self.held.split_off(self.held.len() - keep);
+        let text = std::mem::replace(&mut self.held, rest);
+        (Some(text).filter(|t| !t.is_empty()), false)
+    }
+
     pub fn decode_rest(&self) -> Result<Option<String>> {
         let prev_text = if self.tokens.is_empty() {
             String::new()
@@ -101,5 +156,98 @@ impl TokenOutputStream {
         self.tokens.clear();
         self.prev_index = 0;
         self.current_index = 0;
+        self.held.clear();
+    }
+}
+
+/// Length of the longest end of `text` that is the beginning of `stop`.
+fn partial_match(text: &str, stop: &str) -> usize {
+    (1..stop.len().min(text.len() + 1))
+        .rev()
+        .find(|&n| {
+            stop.is_char_boundary(n)
+                && text.is_char_boundary(text.len() - n)
+                && text.ends_with(&stop[..n])
+        })
+        .unwrap_or(0)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const VOCAB: [&str; 7] = ["Hello", " world", "ST", "OP", "é", "日本", "x"];
+
+    /// A tokenizer whose tokens decode to exactly their text.
+    fn output_stream(stop: &[&str]) -> TokenOutputStream {
+        let vocab: serde_json::Map<String, serde_json::Value> = VOCAB
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id.into()))
+            .collect();
+        let json = serde_json::json!({
+            "version": "1.0",
+            "decoder": {"type": "Fuse"},
+            "model": {"type": "WordLevel", "vocab": vocab, "unk_token": "x"}
+        });
+        let tokenizer = tokenizers::Tokenizer::from_str(&json.to_string()).unwrap();
+        let mut stream = TokenOutputStream::new(tokenizer);
+        let stop: Vec<String> = stop.iter().map(|s| s.to_string()).collect();
+        stream.set_stop_sequences(&stop);
+        stream
+    }
+
+    /// Feeds the tokens, returning the emitted text and whether it stopped.
+    fn run(stream: &mut TokenOutputStream, tokens: &[&str]) -> (String, bool) {
+        let mut output = String::new();
+        for token in tokens {
+            let id = VOCAB.iter().position(|t| t == token).unwrap() as u32;
+            let (text, stopped) = stream.next_token_until_stop(id).unwrap();
+            output.push_str(text.as_deref().unwrap_or_default());
+            if stopped {
+                return (output, true);
+            }
+        }
+        let (text, stopped) = stream.finish().unwrap();
+        output.push_str(text.as_deref().unwrap_or_default());
+        (output, stopped)
+    }
+
+    #[test]
+    fn stop_split_across_tokens() {
+        let mut stream = output_stream(&["STOP"]);
+        assert_eq!(run(&mut stream, &["Hello", "ST", "OP", " world"]), ("Hello".to_string(), true));
+    }
+
+    #[test]
+    fn held_back_text_is_released_when_the_stop_does_not_complete() {
+        let mut stream = output_stream(&["STOP"]);
+        let id = VOCAB.iter().position(|t| *t == "ST").unwrap() as u32;
+        stream.next_token_until_stop(0).unwrap();
+        assert_eq!(stream.next_token_until_stop(id).unwrap(), (None, false));
+        assert_eq!(run(&mut stream, &[" world"]), ("ST world".to_string(), false));
+    }
+
+    #[test]
+    fn stop_at_the_first_token() {
+        let mut stream = output_stream(&["Hello"]);
+        assert_eq!(run(&mut stream, &["Hello", " world"]), (String::new(), true));
+    }
+
+    #[test]
+    fn stop_next_to_multibyte_text() {
+        let mut stream = output_stream(&["本ST"]);
+        assert_eq!(run(&mut stream, &["Hello", "é", "日本", "ST"]), ("Helloé日".to_string(), true));
+        let mut stream = output_stream(&["本ST"]);
+        assert_eq!(run(&mut stream, &["é", "日本", "x"]), ("é日本x".to_string(), false));
+    }
+
+    #[test]
+    fn partial_match_respects_char_boundaries() {
+        assert_eq!(partial_match("abST", "STOP"), 2);
+        assert_eq!(partial_match("abc", "STOP"), 0);
+        assert_eq!(partial_match("aé", "éb"), "é".len());
+        assert_eq!(partial_match("日", "本ST"), 0);
+        assert_eq!(partial_match("xSTO", "STOP"), 3);
     }
-}
\ No newline at end of file
+}
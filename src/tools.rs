@@ -0,0 +1,385 @@
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::{FinishReason, FunctionCall, Message, Role, TokenLogprob, Tool, ToolCall, Usage};
+use crate::ipc::OutputStream;
+
+const CALL_START: &str = "<tool_call>";
+const CALL_END: &str = "</tool_call>";
+
+/// For templates that know nothing about tools: describes the tools in the
+/// system prompt and writes the calls and their results into the messages,
+/// in the `<tool_call>` format the parser reads back.
+pub fn inline_tools(msg_list: &[Message], system_prompt: &str, tools: &[Tool]) -> (Vec<Message>, String) {
+    let mut system_prompt = system_prompt.to_string();
+    if !tools.is_empty() {
+        if !system_prompt.is_empty() {
+            system_prompt.push_str("\n\n");
+        }
+        system_prompt.push_str(
+            "# Tools\n\nYou may call one or more functions to assist with the user query.\n\n\
+             You are provided with function signatures within <tools></tools> XML tags:\n<tools>\n",
+        );
+        for tool in tools {
+            system_prompt.push_str(&serde_json::json!(tool).to_string());
+            system_prompt.push('\n');
+        }
+        system_prompt.push_str(
+            "</tools>\n\nFor each function call, return a json object with function name and \
+             arguments within <tool_call></tool_call> XML tags:\n<tool_call>\n\
+             {\"name\": <function-name>, \"arguments\": <args-json-object>}\n</tool_call>",
+        );
+    }
+    let messages = msg_list
+        .iter()
+        .map(|msg| {
+            let mut msg = msg.clone();
+            if msg.role == Role::Tool {
+                msg.role = Role::User;
+                msg.content = format!("<tool_response>\n{}\n</tool_response>", msg.content);
+            }
+            for call in msg.tool_calls.drain(..) {
+                let arguments: Value = serde_json::from_str(&call.function.arguments)
+                    .unwrap_or(Value::String(call.function.arguments));
+                let call = serde_json::json!({"name": call.function.name, "arguments": arguments});
+                msg.content.push_str(&format!("\n{}\n{}\n{}", CALL_START, call, CALL_END));
+            }
+            msg
+        })
+        .collect();
+    (messages, system_prompt)
+}
+
+/// The calls in `json`: an object with a `name` and its `arguments` (or
+/// `parameters`), or a list of them.
+fn parse_calls(json: &str) -> Option<Vec<ToolCall>> {
+    let value: Value = serde_json::from_str(json.trim()).ok()?;
+    let calls = match value {
+        Value::Array(calls) => calls,
+        call => vec![call],
+    };
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    calls
+        .into_iter()
+        .enumerate()
+        .map(|(i, call)| {
+            let name = call.get("name")?.as_str()?.to_string();
+            let arguments = match call.get("arguments").or_else(|| call.get("parameters")) {
+                Some(Value::String(arguments)) => arguments.clone(),
+                Some(arguments) => arguments.to_string(),
+                None => "{}".to_string(),
+            };
+            Some(ToolCall {
+                id: format!("call_{:x}{}", nanos, i),
+                kind: "function".to_string(),
+                function: FunctionCall { name, arguments },
+            })
+        })
+        .collect()
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Text,
+    /// inside `<tool_call>`.
+    Call,
+    /// the reply started as JSON, which is a call when it parses as one.
+    Json,
+}
+
+/// Splits the tool calls out of a streamed reply. Text is passed on as it
+/// comes, except what could be the start of a call.
+pub struct ToolCallParser {
+    mode: Mode,
+    buffer: String,
+    started: bool,
+    calls: usize,
+}
+
+impl ToolCallParser {
+    pub fn new() -> Self {
+        ToolCallParser {
+            mode: Mode::Text,
+            buffer: String::new(),
+            started: false,
+            calls: 0,
+        }
+    }
+
+    fn call(&mut self, output: &dyn OutputStream, json: &str) -> Result<bool> {
+        match parse_calls(json) {
+            Some(calls) if !calls.is_empty() => {
+                for call in calls {
+                    output.tool_call(&call)?;
+                    self.calls += 1;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub fn push(&mut self, output: &dyn OutputStream, text: &str) -> Result<()> {
+        self.buffer.push_str(text);
+        loop {
+            match self.mode {
+                Mode::Json => return Ok(()),
+                Mode::Call => {
+                    let end = match self.buffer.find(CALL_END) {
+                        Some(end) => end,
+                        None => return Ok(()),
+                    };
+                    let json = self.buffer[..end].to_string();
+                    if !self.call(output, &json)? {
+                        output.write(format!("{}{}{}", CALL_START, json, CALL_END))?;
+                    }
+                    self.buffer.drain(..end + CALL_END.len());
+                    self.mode = Mode::Text;
+                }
+                Mode::Text => {
+                    if !self.started {
+                        let trimmed = self.buffer.trim_start();
+                        if trimmed.is_empty() {
+                            return Ok(());
+                        }
+                        self.started = true;
+                        if trimmed.starts_with('{') || trimmed.starts_with('[') {
+                            self.mode = Mode::Json;
+                            continue;
+                        }
+                    }
+                    if let Some(start) = self.buffer.find(CALL_START) {
+                        if start > 0 {
+                            output.write(self.buffer[..start].to_string())?;
+                        }
+                        self.buffer.drain(..start + CALL_START.len());
+                        self.mode = Mode::Call;
+                        continue;
+                    }
+                    // hold back a tail that may grow into `<tool_call>`.
+                    let keep = (1..CALL_START.len())
+                        .rev()
+                        .find(|n| self.buffer.ends_with(&CALL_START[..*n]))
+                        .unwrap_or(0);
+                    let split = self.buffer.len() - keep;
+                    if split > 0 {
+                        let text: String = self.buffer.drain(..split).collect();
+                        output.write(text)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Writes what is still held back. A reply that ended by calling tools
+    /// gets `FinishReason::ToolCalls`.
+    pub fn finish(&mut self, output: &dyn OutputStream, usage: &mut Usage) -> Result<()> {
+        let rest = std::mem::take(&mut self.buffer);
+        let mode = std::mem::replace(&mut self.mode, Mode::Text);
+        let rest = match mode {
+            Mode::Text => rest,
+            _ if self.call(output, &rest)? => String::new(),
+            Mode::Call => format!("{}{}", CALL_START, rest),
+            Mode::Json => rest,
+        };
+        if !rest.is_empty() {
+            output.write(rest)?;
+        }
+        if self.calls > 0 && usage.finish_reason == FinishReason::Eos {
+            usage.finish_reason = FinishReason::ToolCalls;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ToolCallParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Passes a reply through a `ToolCallParser` on its way to `output`.
+pub struct ToolCallOutput<'a> {
+    parser: &'a RefCell<ToolCallParser>,
+    output: &'a dyn OutputStream,
+}
+
+impl<'a> ToolCallOutput<'a> {
+    pub fn new(parser: &'a RefCell<ToolCallParser>, output: &'a dyn OutputStream) -> Self {
+        ToolCallOutput { parser, output }
+    }
+}
+
+impl<'a> OutputStream for ToolCallOutput<'a> {
+    fn write(&self, text: String) -> Result<(), Error> {
+        self.parser.borrow_mut().push(self.output, text.as_str())
+    }
+
+    fn end(&self) -> Result<(), Error> {
+        self.output.end()
+    }
+
+    fn artifact(&self, content_type: &str, alt: &str, data: &[u8]) -> Result<(), Error> {
+        self.output.artifact(content_type, alt, data)
+    }
+
+    fn usage(&self, usage: &Usage) -> Result<(), Error> {
+        self.output.usage(usage)
+    }
+
+    fn tool_call(&self, call: &ToolCall) -> Result<(), Error> {
+        self.output.tool_call(call)
+    }
+
+    fn logprob(&self, logprob: &TokenLogprob) -> Result<(), Error> {
+        self.output.logprob(logprob)
+    }
+
+    fn error(&self, message: String) -> Result<(), Error> {
+        self.output.error(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps what the parser passes on.
+    #[derive(Default)]
+    struct Recorder {
+        text: RefCell<String>,
+        calls: RefCell<Vec<ToolCall>>,
+    }
+
+    impl OutputStream for Recorder {
+        fn write(&self, text: String) -> Result<(), Error> {
+            self.text.borrow_mut().push_str(&text);
+            Ok(())
+        }
+
+        fn end(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn artifact(&self, _: &str, _: &str, _: &[u8]) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn usage(&self, _: &Usage) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn tool_call(&self, call: &ToolCall) -> Result<(), Error> {
+            self.calls.borrow_mut().push(call.clone());
+            Ok(())
+        }
+
+        fn logprob(&self, _: &TokenLogprob) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn error(&self, _: String) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    /// Pushes the chunks through a parser, returning the text, the calls and
+    /// the finish reason of a reply that ended with `reason`.
+    fn run(chunks: &[&str], reason: FinishReason) -> (String, Vec<ToolCall>, FinishReason) {
+        let recorder = Recorder::default();
+        let mut parser = ToolCallParser::new();
+        for chunk in chunks {
+            parser.push(&recorder, chunk).unwrap();
+        }
+        let mut usage = Usage {
+            prompt_tokens: 1,
+            completion_tokens: chunks.len(),
+            time_to_first_token: 0.,
+            tokens_per_second: 0.,
+            finish_reason: reason,
+        };
+        parser.finish(&recorder, &mut usage).unwrap();
+        (recorder.text.take(), recorder.calls.take(), usage.finish_reason)
+    }
+
+    fn names(calls: &[ToolCall]) -> Vec<&str> {
+        calls.iter().map(|call| call.function.name.as_str()).collect()
+    }
+
+    #[test]
+    fn call_tags_split_across_pushes() {
+        let (text, calls, reason) = run(
+            &["Let me check. <tool", "_call>\n{\"name\": \"weather\", \"argu", "ments\": {\"city\": \"Paris\"}}\n</tool", "_call>"],
+            FinishReason::Eos,
+        );
+        assert_eq!(text, "Let me check. ");
+        assert_eq!(names(&calls), ["weather"]);
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(reason, FinishReason::ToolCalls);
+    }
+
+    #[test]
+    fn held_back_text_that_is_no_tag_is_written() {
+        let (text, calls, reason) = run(&["a <to", "y> and <tool"], FinishReason::Length);
+        assert_eq!(text, "a <toy> and <tool");
+        assert!(calls.is_empty());
+        assert_eq!(reason, FinishReason::Length);
+    }
+
+    #[test]
+    fn call_that_does_not_parse_is_written_as_text() {
+        let (text, calls, reason) = run(&["<tool_call>not json</tool_call> ok"], FinishReason::Eos);
+        assert_eq!(text, "<tool_call>not json</tool_call> ok");
+        assert!(calls.is_empty());
+        assert_eq!(reason, FinishReason::Eos);
+    }
+
+    #[test]
+    fn json_reply_that_is_a_call() {
+        let (text, calls, reason) = run(&["  {\"name\": \"weather\",", " \"parameters\": {\"city\": \"Oslo\"}}"], FinishReason::Eos);
+        assert_eq!(text, "");
+        assert_eq!(names(&calls), ["weather"]);
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Oslo"}"#);
+        assert_eq!(reason, FinishReason::ToolCalls);
+    }
+
+    #[test]
+    fn json_reply_that_is_no_call() {
+        let (text, calls, reason) = run(&["{\"answer\":", " 42}"], FinishReason::Eos);
+        assert_eq!(text, "{\"answer\": 42}");
+        assert!(calls.is_empty());
+        assert_eq!(reason, FinishReason::Eos);
+    }
+
+    #[test]
+    fn unterminated_call_at_finish() {
+        let (_, calls, reason) = run(&["<tool_call>{\"name\": \"weather\", \"arguments\": {}}"], FinishReason::Eos);
+        assert_eq!(names(&calls), ["weather"]);
+        assert_eq!(reason, FinishReason::ToolCalls);
+
+        let (text, calls, reason) = run(&["<tool_call>{\"name\": \"wea"], FinishReason::Length);
+        assert_eq!(text, "<tool_call>{\"name\": \"wea");
+        assert!(calls.is_empty());
+        assert_eq!(reason, FinishReason::Length);
+    }
+
+    #[test]
+    fn call_list() {
+        let (text, calls, reason) = run(
+            &["<tool_call>[{\"name\": \"a\", \"arguments\": \"{}\"}, {\"name\": \"b\"}]</tool_call>"],
+            FinishReason::Eos,
+        );
+        assert_eq!(text, "");
+        assert_eq!(names(&calls), ["a", "b"]);
+        assert_eq!(calls[0].function.arguments, "{}");
+        assert_eq!(calls[1].function.arguments, "{}");
+        assert_ne!(calls[0].id, calls[1].id);
+        assert_eq!(reason, FinishReason::ToolCalls);
+    }
+}
@@ -0,0 +1,994 @@
+//! The llama family of decoders (llama, mistral, qwen2, gemma, phi3 and their
+//! gguf files). Unlike the candle models, the weights and the kv cache are
+//! apart: a sequence owns its `KvCache`, feeds several tokens in one forward
+//! and cuts its cache back when tokens are dropped.
+
+use anyhow::{Error, Result};
+use candle_core::quantized::{gguf_file, QMatMul, QTensor};
+use candle_core::{DType, Device, Module, Tensor, D};
+use candle_nn::{Activation, Embedding, RmsNorm, VarBuilder};
+use std::sync::Arc;
+
+use crate::generation::Decoder;
+
+/// The rotary embeddings of a model.
+#[derive(Debug, Clone)]
+pub struct Rope {
+    /// a frequency per pair of rotated dims, a head rotates twice as many dims.
+    pub inv_freq: Vec<f32>,
+    /// the frequencies used from a position on, as phi3's longrope does.
+    pub long: Option<(usize, Vec<f32>)>,
+    /// gguf llama weights rotate neighbouring dims rather than the two halves.
+    pub interleaved: bool,
+}
+
+impl Rope {
+    pub fn new(dim: usize, theta: f64) -> Self {
+        Rope {
+            inv_freq: (0..dim)
+                .step_by(2)
+                .map(|i| 1f32 / theta.powf(i as f64 / dim as f64) as f32)
+                .collect(),
+            long: None,
+            interleaved: false,
+        }
+    }
+
+    /// The cos and sin of each position, a row per position.
+    fn tables(&self, positions: &[usize], device: &Device, dtype: DType) -> Result<(Tensor, Tensor)> {
+        let half = self.inv_freq.len();
+        let mut freqs = Vec::with_capacity(positions.len() * half);
+        for pos in positions {
+            let inv_freq = match &self.long {
+                Some((from, long)) if pos >= from => long,
+                _ => &self.inv_freq,
+            };
+            freqs.extend(inv_freq.iter().map(|freq| *pos as f32 * freq));
+        }
+        let freqs = Tensor::from_vec(freqs, (positions.len(), half), device)?;
+        Ok((freqs.cos()?.to_dtype(dtype)?, freqs.sin()?.to_dtype(dtype)?))
+    }
+
+    /// Rotates the leading dims of the heads of `xs`, the others pass through.
+    fn apply(&self, xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let rotate = |xs: &Tensor| match self.interleaved {
+            true => candle_nn::rotary_emb::rope_i(xs, cos, sin),
+            false => candle_nn::rotary_emb::rope(xs, cos, sin),
+        };
+        let head_dim = xs.dim(D::Minus1)?;
+        let dim = 2 * self.inv_freq.len();
+        if dim == head_dim {
+            return Ok(rotate(&xs.contiguous()?)?);
+        }
+        let rotated = rotate(&xs.narrow(D::Minus1, 0, dim)?.contiguous()?)?;
+        let passed = xs.narrow(D::Minus1, dim, head_dim - dim)?;
+        Ok(Tensor::cat(&[&rotated, &passed], D::Minus1)?.contiguous()?)
+    }
+}
+
+/// How the weights of a checkpoint are laid out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Layout {
+    Llama,
+    /// scales the embeddings and weighs its norms by `1 + weight`.
+    Gemma,
+    /// keeps q, k and v in one projection, and the gate with up.
+    Phi3,
+}
+
+/// The shape of a model, which each architecture reads from its config.json.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub layout: Layout,
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub head_dim: usize,
+    pub rms_norm_eps: f64,
+    pub hidden_act: Activation,
+    pub rope: Rope,
+    /// tokens further back are not attended to.
+    pub sliding_window: Option<usize>,
+    /// q, k and v have biases.
+    pub attention_bias: bool,
+    pub tie_word_embeddings: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Linear {
+    Dense(candle_nn::Linear),
+    Quantized { weight: QMatMul, bias: Option<Tensor> },
+}
+
+impl Linear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Linear::Dense(linear) => Ok(linear.forward(xs)?),
+            Linear::Quantized { weight, bias: None } => Ok(weight.forward(xs)?),
+            Linear::Quantized {
+                weight,
+                bias: Some(bias),
+            } => Ok(weight.forward(xs)?.broadcast_add(bias)?),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Qkv {
+    Split { q: Linear, k: Linear, v: Linear },
+    Fused(Linear),
+}
+
+#[derive(Debug, Clone)]
+enum Mlp {
+    Gated { gate: Linear, up: Linear, down: Linear },
+    Fused { gate_up: Linear, down: Linear, size: usize },
+    /// mixtral, each token goes through the `used` experts the router rates
+    /// best.
+    Experts { router: Linear, experts: Vec<Mlp>, used: usize },
+}
+
+impl Mlp {
+    fn forward(&self, xs: &Tensor, act: Activation) -> Result<Tensor> {
+        match self {
+            Mlp::Gated { gate, up, down } => {
+                down.forward(&(gate.forward(xs)?.apply(&act)? * up.forward(xs)?)?)
+            }
+            Mlp::Fused { gate_up, down, size } => {
+                let gate_up = gate_up.forward(xs)?;
+                let gate = gate_up.narrow(D::Minus1, 0, *size)?.apply(&act)?;
+                let up = gate_up.narrow(D::Minus1, *size, *size)?;
+                down.forward(&(up * gate)?)
+            }
+            Mlp::Experts { router, experts, used } => {
+                let (b, t, hidden) = xs.dims3()?;
+                let xs = xs.reshape(((), hidden))?;
+                let weights = candle_nn::ops::softmax_last_dim(&router.forward(&xs)?)?
+                    .to_dtype(DType::F32)?
+                    .to_vec2::<f32>()?;
+                // the rows each expert gets, with their weights.
+                let mut rows = vec![vec![]; experts.len()];
+                let mut row_weights = vec![vec![]; experts.len()];
+                for (row, weights) in weights.iter().enumerate() {
+                    let mut best: Vec<usize> = (0..weights.len()).collect();
+                    best.sort_by(|a, b| weights[*b].total_cmp(&weights[*a]));
+                    best.truncate(*used);
+                    let sum: f32 = best.iter().map(|expert| weights[*expert]).sum();
+                    for expert in best {
+                        rows[expert].push(row as u32);
+                        row_weights[expert].push(weights[expert] / sum);
+                    }
+                }
+                let mut ys = xs.zeros_like()?;
+                for (expert, mlp) in experts.iter().enumerate() {
+                    if rows[expert].is_empty() {
+                        continue;
+                    }
+                    let rows_of = Tensor::new(rows[expert].as_slice(), xs.device())?;
+                    let weights = Tensor::new(row_weights[expert].as_slice(), xs.device())?
+                        .reshape(((), 1))?
+                        .to_dtype(xs.dtype())?;
+                    let expert_ys = mlp
+                        .forward(&xs.index_select(&rows_of, 0)?, act)?
+                        .broadcast_mul(&weights)?;
+                    ys = ys.index_add(&rows_of, &expert_ys, 0)?;
+                }
+                Ok(ys.reshape((b, t, hidden))?)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Layer {
+    attn_norm: RmsNorm,
+    qkv: Qkv,
+    o_proj: Linear,
+    mlp_norm: RmsNorm,
+    mlp: Mlp,
+}
+
+/// The keys and values of the tokens a sequence has fed, per layer.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    layers: Vec<Option<(Tensor, Tensor)>>,
+    len: usize,
+}
+
+impl KvCache {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Forgets the tokens past the first `len`.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.len {
+            return Ok(());
+        }
+        for kv in self.layers.iter_mut() {
+            *kv = match kv.take() {
+                Some((k, v)) if len > 0 => Some((k.narrow(2, 0, len)?, v.narrow(2, 0, len)?)),
+                _ => None,
+            };
+        }
+        self.len = len;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Transformer {
+    embed: Embedding,
+    /// gemma multiplies the embeddings by the square root of the hidden size.
+    embed_scale: Option<f64>,
+    layers: Vec<Layer>,
+    norm: RmsNorm,
+    lm_head: Linear,
+    act: Activation,
+    rope: Rope,
+    num_heads: usize,
+    num_kv_heads: usize,
+    head_dim: usize,
+    sliding_window: Option<usize>,
+}
+
+impl Transformer {
+    /// Loads a checkpoint in the layout of the transformers library.
+    pub fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        let vb_m = vb.pp("model");
+        let norm = |vb: VarBuilder| -> Result<RmsNorm> {
+            let weight = vb.get(cfg.hidden_size, "weight")?;
+            let weight = match cfg.layout {
+                Layout::Gemma => (weight + 1.0)?,
+                _ => weight,
+            };
+            Ok(RmsNorm::new(weight, cfg.rms_norm_eps))
+        };
+        let linear = |in_dim: usize, out_dim: usize, bias: bool, vb: VarBuilder| -> Result<Linear> {
+            Ok(Linear::Dense(candle_nn::linear_b(in_dim, out_dim, bias, vb)?))
+        };
+        let embed = candle_nn::embedding(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
+        let q_size = cfg.num_attention_heads * cfg.head_dim;
+        let kv_size = cfg.num_key_value_heads * cfg.head_dim;
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for i in 0..cfg.num_hidden_layers {
+            let vb_l = vb_m.pp(format!("layers.{i}"));
+            let vb_a = vb_l.pp("self_attn");
+            let vb_mlp = vb_l.pp("mlp");
+            let (qkv, mlp) = match cfg.layout {
+                Layout::Phi3 => (
+                    Qkv::Fused(linear(cfg.hidden_size, q_size + 2 * kv_size, false, vb_a.pp("qkv_proj"))?),
+                    Mlp::Fused {
+                        gate_up: linear(cfg.hidden_size, 2 * cfg.intermediate_size, false, vb_mlp.pp("gate_up_proj"))?,
+                        down: linear(cfg.intermediate_size, cfg.hidden_size, false, vb_mlp.pp("down_proj"))?,
+                        size: cfg.intermediate_size,
+                    },
+                ),
+                Layout::Llama | Layout::Gemma => (
+                    Qkv::Split {
+                        q: linear(cfg.hidden_size, q_size, cfg.attention_bias, vb_a.pp("q_proj"))?,
+                        k: linear(cfg.hidden_size, kv_size, cfg.attention_bias, vb_a.pp("k_proj"))?,
+                        v: linear(cfg.hidden_size, kv_size, cfg.attention_bias, vb_a.pp("v_proj"))?,
+                    },
+                    Mlp::Gated {
+                        gate: linear(cfg.hidden_size, cfg.intermediate_size, false, vb_mlp.pp("gate_proj"))?,
+                        up: linear(cfg.hidden_size, cfg.intermediate_size, false, vb_mlp.pp("up_proj"))?,
+                        down: linear(cfg.intermediate_size, cfg.hidden_size, false, vb_mlp.pp("down_proj"))?,
+                    },
+                ),
+            };
+            layers.push(Layer {
+                attn_norm: norm(vb_l.pp("input_layernorm"))?,
+                qkv,
+                // gemma's attention bias also goes to the output.
+                o_proj: linear(q_size, cfg.hidden_size, cfg.layout == Layout::Gemma && cfg.attention_bias, vb_a.pp("o_proj"))?,
+                mlp_norm: norm(vb_l.pp("post_attention_layernorm"))?,
+                mlp,
+            });
+        }
+        let lm_head = if cfg.tie_word_embeddings || !vb.contains_tensor("lm_head.weight") {
+            Linear::Dense(candle_nn::Linear::new(embed.embeddings().clone(), None))
+        } else {
+            linear(cfg.hidden_size, cfg.vocab_size, false, vb.pp("lm_head"))?
+        };
+        Ok(Transformer {
+            embed,
+            embed_scale: (cfg.layout == Layout::Gemma).then(|| (cfg.hidden_size as f64).sqrt()),
+            layers,
+            norm: norm(vb_m.pp("norm"))?,
+            lm_head,
+            act: cfg.hidden_act,
+            rope: cfg.rope.clone(),
+            num_heads: cfg.num_attention_heads,
+            num_kv_heads: cfg.num_key_value_heads,
+            head_dim: cfg.head_dim,
+            sliding_window: cfg.sliding_window,
+        })
+    }
+
+    /// Loads a gguf file of the llama, qwen2 or phi3 architecture.
+    pub fn load_gguf<R: std::io::Read + std::io::Seek>(
+        content: &gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut gguf = Gguf {
+            content,
+            reader,
+            device,
+        };
+        let arch = gguf.string("general.architecture")?;
+        let num_heads = gguf.usize(&format!("{arch}.attention.head_count"))?;
+        let num_kv_heads = gguf.usize(&format!("{arch}.attention.head_count_kv"))?;
+        let num_layers = gguf.usize(&format!("{arch}.block_count"))?;
+        let hidden_size = gguf.usize(&format!("{arch}.embedding_length"))?;
+        let head_dim = hidden_size / num_heads;
+        let eps = gguf.f32(&format!("{arch}.attention.layer_norm_rms_epsilon"))? as f64;
+        let theta = gguf.f32(&format!("{arch}.rope.freq_base")).unwrap_or(10000.) as f64;
+        let rope_dim = gguf.usize(&format!("{arch}.rope.dimension_count")).unwrap_or(head_dim);
+        let experts = gguf.usize(&format!("{arch}.expert_count")).unwrap_or(0);
+        let experts_used = gguf.usize(&format!("{arch}.expert_used_count")).unwrap_or(0);
+
+        let mut rope = Rope::new(rope_dim, theta);
+        rope.interleaved = arch == "llama";
+        // llama 3.1 files keep the factors of its frequency scaling.
+        if gguf.has("rope_freqs.weight") {
+            let factors = gguf.tensor("rope_freqs.weight")?.dequantize(device)?.to_vec1::<f32>()?;
+            for (freq, factor) in rope.inv_freq.iter_mut().zip(factors) {
+                *freq /= factor;
+            }
+        }
+
+        let embeddings = gguf.tensor("token_embd.weight")?;
+        let lm_head = match gguf.has("output.weight") {
+            true => gguf.linear("output")?,
+            false => Linear::Quantized {
+                weight: QMatMul::from_qtensor(gguf.tensor("token_embd.weight")?)?,
+                bias: None,
+            },
+        };
+        let mut layers = Vec::with_capacity(num_layers);
+        for i in 0..num_layers {
+            let prefix = format!("blk.{i}");
+            let qkv = match gguf.has(&format!("{prefix}.attn_qkv.weight")) {
+                true => Qkv::Fused(gguf.linear(&format!("{prefix}.attn_qkv"))?),
+                false => Qkv::Split {
+                    q: gguf.linear(&format!("{prefix}.attn_q"))?,
+                    k: gguf.linear(&format!("{prefix}.attn_k"))?,
+                    v: gguf.linear(&format!("{prefix}.attn_v"))?,
+                },
+            };
+            let mlp = if experts > 1 {
+                let mut mlps = Vec::with_capacity(experts);
+                for e in 0..experts {
+                    mlps.push(Mlp::Gated {
+                        gate: gguf.linear(&format!("{prefix}.ffn_gate.{e}"))?,
+                        up: gguf.linear(&format!("{prefix}.ffn_up.{e}"))?,
+                        down: gguf.linear(&format!("{prefix}.ffn_down.{e}"))?,
+                    });
+                }
+                Mlp::Experts {
+                    router: gguf.linear(&format!("{prefix}.ffn_gate_inp"))?,
+                    experts: mlps,
+                    used: experts_used,
+                }
+            } else if gguf.has(&format!("{prefix}.ffn_gate.weight")) {
+                Mlp::Gated {
+                    gate: gguf.linear(&format!("{prefix}.ffn_gate"))?,
+                    up: gguf.linear(&format!("{prefix}.ffn_up"))?,
+                    down: gguf.linear(&format!("{prefix}.ffn_down"))?,
+                }
+            } else {
+                Mlp::Fused {
+                    gate_up: gguf.linear(&format!("{prefix}.ffn_up"))?,
+                    down: gguf.linear(&format!("{prefix}.ffn_down"))?,
+                    size: gguf.usize(&format!("{arch}.feed_forward_length"))?,
+                }
+            };
+            layers.push(Layer {
+                attn_norm: gguf.norm(&format!("{prefix}.attn_norm"), eps)?,
+                qkv,
+                o_proj: gguf.linear(&format!("{prefix}.attn_output"))?,
+                mlp_norm: gguf.norm(&format!("{prefix}.ffn_norm"), eps)?,
+                mlp,
+            });
+        }
+        Ok(Transformer {
+            embed: Embedding::new(embeddings.dequantize(device)?, hidden_size),
+            embed_scale: None,
+            layers,
+            norm: gguf.norm("output_norm", eps)?,
+            lm_head,
+            act: Activation::Silu,
+            rope,
+            num_heads,
+            num_kv_heads,
+            head_dim,
+            sliding_window: None,
+        })
+    }
+
+    fn device(&self) -> &Device {
+        self.embed.embeddings().device()
+    }
+
+    fn embed(&self, tokens: &Tensor) -> Result<Tensor> {
+        let xs = self.embed.forward(tokens)?;
+        match self.embed_scale {
+            Some(scale) => Ok((xs * scale)?),
+            None => Ok(xs),
+        }
+    }
+
+    /// The queries, keys and values of `xs`, rotated, a head per row.
+    fn project(&self, layer: &Layer, xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+        let (b, t, _) = xs.dims3()?;
+        let (q, k, v) = match &layer.qkv {
+            Qkv::Split { q, k, v } => (q.forward(xs)?, k.forward(xs)?, v.forward(xs)?),
+            Qkv::Fused(qkv) => {
+                let qkv = qkv.forward(xs)?;
+                let q_size = self.num_heads * self.head_dim;
+                let kv_size = self.num_kv_heads * self.head_dim;
+                (
+                    qkv.narrow(D::Minus1, 0, q_size)?,
+                    qkv.narrow(D::Minus1, q_size, kv_size)?,
+                    qkv.narrow(D::Minus1, q_size + kv_size, kv_size)?,
+                )
+            }
+        };
+        let heads = |xs: Tensor, n: usize| -> Result<Tensor> {
+            Ok(xs.reshape((b, t, n, self.head_dim))?.transpose(1, 2)?.contiguous()?)
+        };
+        let q = self.rope.apply(&heads(q, self.num_heads)?, cos, sin)?;
+        let k = self.rope.apply(&heads(k, self.num_kv_heads)?, cos, sin)?;
+        Ok((q, k, heads(v, self.num_kv_heads)?))
+    }
+
+    /// Softmax attention of `q` over `k` and `v`, `mask` holding -inf where a
+    /// query does not see a key.
+    fn attention(&self, q: &Tensor, k: &Tensor, v: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let groups = self.num_heads / self.num_kv_heads;
+        let k = candle_transformers::utils::repeat_kv(k.clone(), groups)?.contiguous()?;
+        let v = candle_transformers::utils::repeat_kv(v.clone(), groups)?.contiguous()?;
+        let scale = 1. / (self.head_dim as f64).sqrt();
+        let att = (q.matmul(&k.t()?)? * scale)?.to_dtype(DType::F32)?;
+        let att = match mask {
+            Some(mask) => att.broadcast_add(mask)?,
+            None => att,
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?.to_dtype(v.dtype())?;
+        Ok(att.matmul(&v)?)
+    }
+
+    /// Whether the query at `pos` does not see the key at `key`.
+    fn masked(&self, pos: usize, key: usize) -> bool {
+        key > pos || self.sliding_window.is_some_and(|window| key + window < pos)
+    }
+
+    /// Runs the layers over `xs`, `attend` giving the attention output of
+    /// each layer from its queries, keys and values.
+    fn layers(
+        &self,
+        mut xs: Tensor,
+        cos: &Tensor,
+        sin: &Tensor,
+        mut attend: impl FnMut(usize, &Tensor, Tensor, Tensor) -> Result<Tensor>,
+    ) -> Result<Tensor> {
+        let (b, t, _) = xs.dims3()?;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let (q, k, v) = self.project(layer, &layer.attn_norm.forward(&xs)?, cos, sin)?;
+            let ys = attend(i, &q, k, v)?
+                .transpose(1, 2)?
+                .reshape((b, t, self.num_heads * self.head_dim))?;
+            xs = (layer.o_proj.forward(&ys)? + xs)?;
+            let ys = layer.mlp.forward(&layer.mlp_norm.forward(&xs)?, self.act)?;
+            xs = (ys + xs)?;
+        }
+        Ok(xs)
+    }
+
+    /// Feeds `tokens` after what `cache` holds. Returns the logits of every
+    /// token, a row each, when `all` is set, else those of the last one.
+    pub fn forward(&self, tokens: &[u32], cache: &mut KvCache, all: bool) -> Result<Tensor> {
+        let (pos, t) = (cache.len, tokens.len());
+        if t == 0 {
+            anyhow::bail!("no tokens to feed");
+        }
+        let device = self.device();
+        let xs = self.embed(&Tensor::new(tokens, device)?.unsqueeze(0)?)?;
+        let positions: Vec<usize> = (pos..pos + t).collect();
+        let (cos, sin) = self.rope.tables(&positions, device, xs.dtype())?;
+        let mask: Vec<f32> = positions
+            .iter()
+            .flat_map(|p| (0..pos + t).map(move |key| (*p, key)))
+            .map(|(p, key)| if self.masked(p, key) { f32::NEG_INFINITY } else { 0. })
+            .collect();
+        let mask = match mask.iter().any(|value| value.is_infinite()) {
+            true => Some(Tensor::from_vec(mask, (t, pos + t), device)?),
+            false => None,
+        };
+        cache.layers.resize(self.layers.len(), None);
+        let xs = self.layers(xs, &cos, &sin, |i, q, k, v| {
+            let (k, v) = match &cache.layers[i] {
+                Some((cached_k, cached_v)) => (Tensor::cat(&[cached_k, &k], 2)?, Tensor::cat(&[cached_v, &v], 2)?),
+                None => (k, v),
+            };
+            let ys = self.attention(q, &k, &v, mask.as_ref())?;
+            cache.layers[i] = Some((k, v));
+            Ok(ys)
+        })?;
+        cache.len += t;
+        let xs = match all {
+            true => xs,
+            false => xs.narrow(1, t - 1, 1)?,
+        };
+        let logits = self.lm_head.forward(&self.norm.forward(&xs)?)?.squeeze(0)?;
+        let logits = match all {
+            true => logits,
+            false => logits.squeeze(0)?,
+        };
+        Ok(logits.to_dtype(DType::F32)?)
+    }
+
+    /// Feeds a token to each of several sequences in one `[batch, 1]`
+    /// forward, each after what its own cache holds. The cached keys are
+    /// padded to the longest sequence and the padding masked. Returns the
+    /// logits a row per sequence.
+    pub fn forward_batch(&self, tokens: &[u32], caches: &mut [&mut KvCache]) -> Result<Tensor> {
+        let b = tokens.len();
+        if b == 0 || b != caches.len() {
+            anyhow::bail!("{} tokens for {} kv caches", b, caches.len());
+        }
+        let device = self.device();
+        let xs = self.embed(&Tensor::new(tokens, device)?.unsqueeze(1)?)?;
+        let positions: Vec<usize> = caches.iter().map(|cache| cache.len).collect();
+        let (cos, sin) = self.rope.tables(&positions, device, xs.dtype())?;
+        let (cos, sin) = (cos.unsqueeze(1)?, sin.unsqueeze(1)?);
+        let keys = positions.iter().max().map_or(1, |pos| pos + 1);
+        let mask: Vec<f32> = positions
+            .iter()
+            .flat_map(|p| (0..keys).map(move |key| (*p, key)))
+            .map(|(p, key)| if self.masked(p, key) { f32::NEG_INFINITY } else { 0. })
+            .collect();
+        let mask = match mask.iter().any(|value| value.is_infinite()) {
+            true => Some(Tensor::from_vec(mask, (b, 1, 1, keys), device)?),
+            false => None,
+        };
+        for cache in caches.iter_mut() {
+            cache.layers.resize(self.layers.len(), None);
+        }
+        let xs = self.layers(xs, &cos, &sin, |i, q, k, v| {
+            let mut padded_k = Vec::with_capacity(b);
+            let mut padded_v = Vec::with_capacity(b);
+            for (row, cache) in caches.iter_mut().enumerate() {
+                let (k, v) = (k.narrow(0, row, 1)?, v.narrow(0, row, 1)?);
+                let (k, v) = match &cache.layers[i] {
+                    Some((cached_k, cached_v)) => (Tensor::cat(&[cached_k, &k], 2)?, Tensor::cat(&[cached_v, &v], 2)?),
+                    None => (k, v),
+                };
+                let len = k.dim(2)?;
+                if len < keys {
+                    let (_, kv_heads, _, head_dim) = k.dims4()?;
+                    let zeros = Tensor::zeros((1, kv_heads, keys - len, head_dim), k.dtype(), device)?;
+                    padded_k.push(Tensor::cat(&[&k, &zeros], 2)?);
+                    padded_v.push(Tensor::cat(&[&v, &zeros], 2)?);
+                } else {
+                    padded_k.push(k.clone());
+                    padded_v.push(v.clone());
+                }
+                cache.layers[i] = Some((k, v));
+            }
+            let k = Tensor::cat(&padded_k, 0)?;
+            let v = Tensor::cat(&padded_v, 0)?;
+            self.attention(q, &k, &v, mask.as_ref())
+        })?;
+        for cache in caches.iter_mut() {
+            cache.len += 1;
+        }
+        let logits = self.lm_head.forward(&self.norm.forward(&xs)?)?.squeeze(1)?;
+        Ok(logits.to_dtype(DType::F32)?)
+    }
+}
+
+/// Reads the tensors of a gguf file.
+struct Gguf<'a, R> {
+    content: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: &'a Device,
+}
+
+impl<R: std::io::Read + std::io::Seek> Gguf<'_, R> {
+    fn value(&self, key: &str) -> Result<&gguf_file::Value> {
+        self.content
+            .metadata
+            .get(key)
+            .ok_or_else(|| anyhow::anyhow!("no {key} in the gguf metadata"))
+    }
+
+    fn string(&self, key: &str) -> Result<String> {
+        Ok(self.value(key)?.to_string()?.clone())
+    }
+
+    fn usize(&self, key: &str) -> Result<usize> {
+        Ok(self.value(key)?.to_u32()? as usize)
+    }
+
+    fn f32(&self, key: &str) -> Result<f32> {
+        Ok(self.value(key)?.to_f32()?)
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.content.tensor_infos.contains_key(name)
+    }
+
+    fn tensor(&mut self, name: &str) -> Result<QTensor> {
+        Ok(self.content.tensor(self.reader, name, self.device)?)
+    }
+
+    /// `{name}.weight`, with `{name}.bias` when there is one.
+    fn linear(&mut self, name: &str) -> Result<Linear> {
+        let weight = QMatMul::from_qtensor(self.tensor(&format!("{name}.weight"))?)?;
+        let bias = match self.has(&format!("{name}.bias")) {
+            true => Some(self.tensor(&format!("{name}.bias"))?.dequantize(self.device)?),
+            false => None,
+        };
+        Ok(Linear::Quantized { weight, bias })
+    }
+
+    fn norm(&mut self, name: &str, eps: f64) -> Result<RmsNorm> {
+        let weight = self.tensor(&format!("{name}.weight"))?.dequantize(self.device)?;
+        Ok(RmsNorm::new(weight, eps))
+    }
+}
+
+/// A sequence's view of a model: the shared weights and its own kv cache.
+#[derive(Clone)]
+pub struct TransformerDecoder {
+    model: Arc<Transformer>,
+    cache: KvCache,
+}
+
+impl TransformerDecoder {
+    pub fn new(model: Transformer) -> Self {
+        TransformerDecoder {
+            model: Arc::new(model),
+            cache: KvCache::default(),
+        }
+    }
+
+    /// Cuts the cache back to `pos`, where the next tokens go.
+    fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos > self.cache.len() {
+            return Err(Error::msg(format!(
+                "the kv cache holds {} tokens, can not feed at {}",
+                self.cache.len(),
+                pos
+            )));
+        }
+        self.cache.truncate(pos)
+    }
+}
+
+impl Decoder for TransformerDecoder {
+    fn forward(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+        self.seek(pos)?;
+        self.model.forward(tokens, &mut self.cache, false)
+    }
+
+    fn forward_all(&mut self, tokens: &[u32], pos: usize) -> Result<Tensor> {
+        self.seek(pos)?;
+        self.model.forward(tokens, &mut self.cache, true)
+    }
+
+    fn forward_batch(decoders: &mut [&mut Self], tokens: &[u32], positions: &[usize]) -> Result<Tensor> {
+        let model = match decoders.first() {
+            Some(decoder) => decoder.model.clone(),
+            None => anyhow::bail!("no sequences to feed"),
+        };
+        let mut caches = Vec::with_capacity(decoders.len());
+        for (decoder, pos) in decoders.iter_mut().zip(positions) {
+            if !Arc::ptr_eq(&decoder.model, &model) {
+                anyhow::bail!("only sequences of one model decode together");
+            }
+            decoder.seek(*pos)?;
+            caches.push(&mut decoder.cache);
+        }
+        model.forward_batch(tokens, &mut caches)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.cache.truncate(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::GgmlDType;
+    use candle_nn::VarMap;
+    use candle_transformers::models;
+    use serde_json::json;
+
+    const PROMPT: [u32; 5] = [1, 5, 9, 2, 7];
+    const REPLY: [u32; 3] = [3, 8, 4];
+
+    /// Gives every weight of `varmap` random values, norms included.
+    fn randomize(varmap: &VarMap) {
+        for var in varmap.all_vars() {
+            var.set(&Tensor::randn(0f32, 0.5, var.shape(), var.device()).unwrap())
+                .unwrap();
+        }
+    }
+
+    fn assert_close(a: &Tensor, b: &Tensor) {
+        let diff = (a - b).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(diff < 1e-3, "logits differ by {diff}");
+    }
+
+    /// Feeds the prompt and then the reply a token at a time to `model` and
+    /// to the candle implementation of its architecture.
+    fn check(model: &Transformer, mut reference: impl FnMut(&[u32], usize) -> Tensor) {
+        let mut cache = KvCache::default();
+        let logits = model.forward(&PROMPT, &mut cache, false).unwrap();
+        assert_close(&logits, &reference(&PROMPT, 0));
+        for (i, token) in REPLY.iter().enumerate() {
+            let logits = model.forward(&[*token], &mut cache, false).unwrap();
+            assert_close(&logits, &reference(&[*token], PROMPT.len() + i));
+        }
+        assert_eq!(cache.len(), PROMPT.len() + REPLY.len());
+    }
+
+    fn input(tokens: &[u32]) -> Tensor {
+        Tensor::new(tokens, &Device::Cpu).unwrap().unsqueeze(0).unwrap()
+    }
+
+    fn llama() -> (Transformer, models::llama::Llama, models::llama::Cache) {
+        let config: models::llama::LlamaConfig = serde_json::from_value(json!({
+            "hidden_size": 16, "intermediate_size": 32, "vocab_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 1,
+            "rms_norm_eps": 1e-5, "rope_theta": 10000.0, "max_position_embeddings": 64,
+            "rope_scaling": {
+                "factor": 8.0, "low_freq_factor": 1.0, "high_freq_factor": 4.0,
+                "original_max_position_embeddings": 64, "rope_type": "llama3"
+            }
+        }))
+        .unwrap();
+        let config = config.into_config(false);
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let reference = models::llama::Llama::load(vb.clone(), &config).unwrap();
+        let cache = models::llama::Cache::new(true, DType::F32, &config, &Device::Cpu).unwrap();
+        randomize(&varmap);
+        let model = Transformer::load(vb, &crate::llama::transformer_config(&config)).unwrap();
+        (model, reference, cache)
+    }
+
+    #[test]
+    fn llama_matches_candle() {
+        let (model, reference, mut cache) = llama();
+        check(&model, |tokens, pos| {
+            reference.forward(&input(tokens), pos, &mut cache).unwrap().squeeze(0).unwrap()
+        });
+    }
+
+    #[test]
+    fn mistral_matches_candle() {
+        let config: models::mistral::Config = serde_json::from_value(json!({
+            "vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "max_position_embeddings": 64, "rms_norm_eps": 1e-5, "rope_theta": 10000.0,
+            "sliding_window": 4096
+        }))
+        .unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut reference = models::mistral::Model::new(&config, vb.clone()).unwrap();
+        randomize(&varmap);
+        let model = Transformer::load(vb, &crate::mistral::transformer_config(&config)).unwrap();
+        check(&model, |tokens, pos| {
+            reference.forward(&input(tokens), pos).unwrap().flatten_all().unwrap()
+        });
+    }
+
+    #[test]
+    fn qwen2_matches_candle() {
+        let config: models::qwen2::Config = serde_json::from_value(json!({
+            "vocab_size": 32, "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "num_key_value_heads": 2,
+            "max_position_embeddings": 64, "sliding_window": 64, "max_window_layers": 2,
+            "tie_word_embeddings": true, "rope_theta": 1000000.0, "rms_norm_eps": 1e-6,
+            "use_sliding_window": false, "hidden_act": "silu"
+        }))
+        .unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut reference = models::qwen2::ModelForCausalLM::new(&config, vb.clone()).unwrap();
+        randomize(&varmap);
+        let model = Transformer::load(vb, &crate::qwen2::transformer_config(&config)).unwrap();
+        check(&model, |tokens, pos| {
+            reference.forward(&input(tokens), pos).unwrap().flatten_all().unwrap()
+        });
+    }
+
+    #[test]
+    fn gemma_matches_candle() {
+        let config: models::gemma::Config = serde_json::from_value(json!({
+            "attention_bias": false, "head_dim": 8, "hidden_activation": "gelu_pytorch_tanh",
+            "hidden_size": 16, "intermediate_size": 32, "num_attention_heads": 2,
+            "num_hidden_layers": 2, "num_key_value_heads": 1, "rms_norm_eps": 1e-6,
+            "rope_theta": 10000.0, "vocab_size": 32, "max_position_embeddings": 64
+        }))
+        .unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut reference = models::gemma::Model::new(false, &config, vb.clone()).unwrap();
+        randomize(&varmap);
+        let model = Transformer::load(vb, &crate::gemma::transformer_config(&config).unwrap()).unwrap();
+        check(&model, |tokens, pos| {
+            reference.forward(&input(tokens), pos).unwrap().flatten_all().unwrap()
+        });
+    }
+
+    #[test]
+    fn phi3_matches_candle() {
+        let config: models::phi3::Config = serde_json::from_value(json!({
+            "vocab_size": 32, "hidden_act": "silu", "hidden_size": 16, "intermediate_size": 32,
+            "num_hidden_layers": 2, "num_attention_heads": 2, "num_key_value_heads": 2,
+            "rms_norm_eps": 1e-5, "rope_theta": 10000.0, "max_position_embeddings": 64,
+            "original_max_position_embeddings": 6, "partial_rotary_factor": 0.5,
+            "rope_scaling": { "type": "longrope", "short_factor": [1.0, 1.5], "long_factor": [2.0, 3.0] }
+        }))
+        .unwrap();
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let mut reference = models::phi3::Model::new(&config, vb.clone()).unwrap();
+        randomize(&varmap);
+        let model = Transformer::load(vb, &crate::phi3::transformer_config(&config)).unwrap();
+        check(&model, |tokens, pos| {
+            reference.forward(&input(tokens), pos).unwrap().flatten_all().unwrap()
+        });
+    }
+
+    /// A gguf file of random weights for `arch`, qwen2 with attention biases.
+    fn gguf(arch: &str) -> Vec<u8> {
+        let qtensor = |dims: &[usize]| {
+            let tensor = Tensor::randn(0f32, 0.5, dims, &Device::Cpu).unwrap();
+            QTensor::quantize(&tensor, GgmlDType::F32).unwrap()
+        };
+        let (hidden, ffn, vocab, heads, kv_heads) = (16usize, 32usize, 32usize, 4usize, 2usize);
+        let kv_size = hidden / heads * kv_heads;
+        let mut tensors = vec![
+            ("token_embd.weight".to_string(), qtensor(&[vocab, hidden])),
+            ("output_norm.weight".to_string(), qtensor(&[hidden])),
+            ("output.weight".to_string(), qtensor(&[vocab, hidden])),
+        ];
+        for i in 0..2 {
+            let mut layer = vec![
+                ("attn_q.weight", qtensor(&[hidden, hidden])),
+                ("attn_k.weight", qtensor(&[kv_size, hidden])),
+                ("attn_v.weight", qtensor(&[kv_size, hidden])),
+                ("attn_output.weight", qtensor(&[hidden, hidden])),
+                ("ffn_gate.weight", qtensor(&[ffn, hidden])),
+                ("ffn_up.weight", qtensor(&[ffn, hidden])),
+                ("ffn_down.weight", qtensor(&[hidden, ffn])),
+                ("attn_norm.weight", qtensor(&[hidden])),
+                ("ffn_norm.weight", qtensor(&[hidden])),
+            ];
+            if arch == "qwen2" {
+                layer.push(("attn_q.bias", qtensor(&[hidden])));
+                layer.push(("attn_k.bias", qtensor(&[kv_size])));
+                layer.push(("attn_v.bias", qtensor(&[kv_size])));
+            }
+            tensors.extend(layer.into_iter().map(|(name, tensor)| (format!("blk.{i}.{name}"), tensor)));
+        }
+        let u32_value = |value: usize| gguf_file::Value::U32(value as u32);
+        let metadata = [
+            ("general.architecture".to_string(), gguf_file::Value::String(arch.to_string())),
+            (format!("{arch}.attention.head_count"), u32_value(heads)),
+            (format!("{arch}.attention.head_count_kv"), u32_value(kv_heads)),
+            (format!("{arch}.block_count"), u32_value(2)),
+            (format!("{arch}.embedding_length"), u32_value(hidden)),
+            (format!("{arch}.context_length"), u32_value(64)),
+            (format!("{arch}.rope.dimension_count"), u32_value(hidden / heads)),
+            (format!("{arch}.attention.layer_norm_rms_epsilon"), gguf_file::Value::F32(1e-5)),
+        ];
+        let mut file = std::io::Cursor::new(Vec::new());
+        gguf_file::write(
+            &mut file,
+            &metadata.iter().map(|(key, value)| (key.as_str(), value)).collect::<Vec<_>>(),
+            &tensors.iter().map(|(name, tensor)| (name.as_str(), tensor)).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        file.into_inner()
+    }
+
+    fn load_gguf(file: &[u8]) -> Transformer {
+        let mut reader = std::io::Cursor::new(file);
+        let content = gguf_file::Content::read(&mut reader).unwrap();
+        Transformer::load_gguf(&content, &mut reader, &Device::Cpu).unwrap()
+    }
+
+    #[test]
+    fn gguf_llama_matches_candle() {
+        let file = gguf("llama");
+        let mut reader = std::io::Cursor::new(&file);
+        let content = gguf_file::Content::read(&mut reader).unwrap();
+        let mut reference =
+            models::quantized_llama::ModelWeights::from_gguf(content, &mut reader, &Device::Cpu).unwrap();
+        check(&load_gguf(&file), |tokens, pos| {
+            reference.forward(&input(tokens), pos).unwrap().squeeze(0).unwrap()
+        });
+    }
+
+    #[test]
+    fn gguf_qwen2_matches_candle() {
+        let file = gguf("qwen2");
+        let mut reader = std::io::Cursor::new(&file);
+        let content = gguf_file::Content::read(&mut reader).unwrap();
+        let mut reference =
+            models::quantized_qwen2::ModelWeights::from_gguf(content, &mut reader, &Device::Cpu).unwrap();
+        check(&load_gguf(&file), |tokens, pos| {
+            reference.forward(&input(tokens), pos).unwrap().squeeze(0).unwrap()
+        });
+    }
+
+    #[test]
+    fn forward_all_matches_one_token_at_a_time() {
+        let (model, _, _) = llama();
+        let mut cache = KvCache::default();
+        model.forward(&PROMPT, &mut cache, false).unwrap();
+        let mut stepped = cache.clone();
+        let all = model.forward(&REPLY, &mut cache, true).unwrap();
+        assert_eq!(all.dims(), &[REPLY.len(), 32]);
+        for (i, token) in REPLY.iter().enumerate() {
+            let logits = model.forward(&[*token], &mut stepped, false).unwrap();
+            assert_close(&all.get(i).unwrap(), &logits);
+        }
+    }
+
+    #[test]
+    fn truncated_cache_feeds_as_a_fresh_one() {
+        let (model, _, _) = llama();
+        let mut cache = KvCache::default();
+        model.forward(&PROMPT, &mut cache, false).unwrap();
+        model.forward(&REPLY, &mut cache, false).unwrap();
+        cache.truncate(3).unwrap();
+        assert_eq!(cache.len(), 3);
+        let logits = model.forward(&[4, 6], &mut cache, false).unwrap();
+        let mut fresh = KvCache::default();
+        let expected = model.forward(&[PROMPT[0], PROMPT[1], PROMPT[2], 4, 6], &mut fresh, false).unwrap();
+        assert_close(&logits, &expected);
+    }
+
+    #[test]
+    fn forward_batch_matches_one_sequence_at_a_time() {
+        let (model, _, _) = llama();
+        let decoder = TransformerDecoder::new(model);
+        let prompts: [&[u32]; 3] = [&PROMPT, &[6], &[2, 4, 6, 8, 10, 12, 14]];
+        let mut alone: Vec<TransformerDecoder> = Vec::new();
+        for prompt in prompts {
+            let mut decoder = decoder.clone();
+            decoder.forward(prompt, 0).unwrap();
+            alone.push(decoder);
+        }
+        let mut batched = alone.clone();
+        for step in 0..2 {
+            let tokens: Vec<u32> = (0..prompts.len()).map(|i| REPLY[(i + step) % REPLY.len()]).collect();
+            let positions: Vec<usize> = prompts.iter().map(|prompt| prompt.len() + step).collect();
+            let mut decoders: Vec<&mut TransformerDecoder> = batched.iter_mut().collect();
+            let logits = TransformerDecoder::forward_batch(&mut decoders, &tokens, &positions).unwrap();
+            assert_eq!(logits.dims(), &[prompts.len(), 32]);
+            for (row, decoder) in alone.iter_mut().enumerate() {
+                let expected = decoder.forward(&[tokens[row]], positions[row]).unwrap();
+                assert_close(&logits.get(row).unwrap(), &expected);
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use crate::artifacts::artifact_store;
+use crate::data::{Message, UploadResponse};
+use crate::master_server::valid_token;
+use axum::{extract::Multipart, http::StatusCode, Json};
+use axum_auth::AuthBearer;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+/// Stores the first file of a multipart form in the artifact store, so chat
+/// messages can reference it by id instead of carrying it inline.
+pub async fn upload_file(
+    AuthBearer(token): AuthBearer,
+    mut multipart: Multipart,
+) -> Result<Json<UploadResponse>, (StatusCode, String)> {
+    if !valid_token(token.as_str()) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            String::from("Authentication failed. Please sign in first."),
+        ));
+    }
+    let bad_request = |e: axum::extract::multipart::MultipartError| {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    };
+    while let Some(field) = multipart.next_field().await.map_err(bad_request)? {
+        if field.file_name().is_none() {
+            continue;
+        }
+        let content_type = field
+            .content_type()
+            .unwrap_or("application/octet-stream")
+            .to_string();
+        let data = field.bytes().await.map_err(bad_request)?;
+        let id = artifact_store()
+            .store_upload(content_type.as_str(), &data)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        return Ok(Json::from(UploadResponse {
+            id,
+            content_type,
+            size: data.len(),
+        }));
+    }
+    Err((
+        StatusCode::BAD_REQUEST,
+        String::from("No file in the upload form."),
+    ))
+}
+
+/// Workers only understand inline images, so replace attachment ids with the
+/// base64 data url of the uploaded file.
+pub(crate) fn resolve_attachments(msg_list: Vec<Message>) -> Result<Vec<Message>, String> {
+    msg_list
+        .into_iter()
+        .map(|mut msg| {
+            if let (None, Some(id)) = (&msg.img, &msg.attachment) {
+                match artifact_store().load(id.as_str()) {
+                    Some((data, content_type)) => {
+                        msg.img = Some(format!(
+                            "data:{};base64,{}",
+                            content_type,
+                            STANDARD.encode(data)
+                        ));
+                    }
+                    None => return Err(format!("The attachment {} is missing, please upload it again.", id)),
+                }
+            }
+            Ok(msg)
+        })
+        .collect()
+}
@@ -1,8 +1,8 @@
 #![allow(non_snake_case, unused)]
 extern crate image_base64_wasm;
 
-use crate::data::{Message, Role, SelectOption, WebUser};
-use crate::web_state::{Session, Store, TempSession};
+use crate::data::{Capability, FinishReason, Message, ModelInfo, Role, SelectOption, Session, ToolCall, UploadResponse, Usage, WebUser};
+use crate::web_state::{delete_remote_session, push_session, sync_sessions, Store, TempSession};
 use crate::authorization::{LoginBox,get_user,show_login};
 use dioxus::prelude::*;
 use dioxus_logger::tracing::{info, Level};
@@ -33,6 +33,11 @@ fn Pulse() -> Element {
     )
 }
 
+// empty inputs leave the parameter to the model defaults.
+fn optional_value<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
 #[component]
 fn ModelConfig(
     model_id: Signal<String>,
@@ -40,6 +45,7 @@ fn ModelConfig(
     mut system_prompt: Signal<String>,
 ) -> Element {
     let mut session = use_context::<Signal<Session>>();
+    let params = session.read().params.clone();
     rsx!(
         div {
             "aria-hidden": "true",
@@ -122,6 +128,120 @@ fn ModelConfig(
                                     
                                 }
                             }
+                            div { class: "col-span-1",
+                                label {
+                                    r#for: "temperature",
+                                    class: "block mb-2 text-sm font-medium text-gray-900 dark:text-white",
+                                    "Temperature"
+                                }
+                                input {
+                                    r#type: "number",
+                                    step: "0.1",
+                                    min: "0",
+                                    placeholder: "default",
+                                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-primary-500 focus:border-primary-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                    id: "temperature",
+                                    value: optional_value(params.temperature),
+                                    onchange: move |evt| {
+                                        session.write().params.temperature = evt.value().trim().parse().ok();
+                                    }
+                                }
+                            }
+                            div { class: "col-span-1",
+                                label {
+                                    r#for: "top_p",
+                                    class: "block mb-2 text-sm font-medium text-gray-900 dark:text-white",
+                                    "Top P"
+                                }
+                                input {
+                                    r#type: "number",
+                                    step: "0.05",
+                                    min: "0",
+                                    placeholder: "default",
+                                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-primary-500 focus:border-primary-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                    id: "top_p",
+                                    value: optional_value(params.top_p),
+                                    onchange: move |evt| {
+                                        session.write().params.top_p = evt.value().trim().parse().ok();
+                                    }
+                                }
+                            }
+                            div { class: "col-span-1",
+                                label {
+                                    r#for: "top_k",
+                                    class: "block mb-2 text-sm font-medium text-gray-900 dark:text-white",
+                                    "Top K"
+                                }
+                                input {
+                                    r#type: "number",
+                                    step: "1",
+                                    min: "0",
+                                    placeholder: "default",
+                                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-primary-500 focus:border-primary-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                    id: "top_k",
+                                    value: optional_value(params.top_k),
+                                    onchange: move |evt| {
+                                        session.write().params.top_k = evt.value().trim().parse().ok();
+                                    }
+                                }
+                            }
+                            div { class: "col-span-1",
+                                label {
+                                    r#for: "max_tokens",
+                                    class: "block mb-2 text-sm font-medium text-gray-900 dark:text-white",
+                                    "Max tokens"
+                                }
+                                input {
+                                    r#type: "number",
+                                    step: "1",
+                                    min: "0",
+                                    placeholder: "default",
+                                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-primary-500 focus:border-primary-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                    id: "max_tokens",
+                                    value: optional_value(params.max_tokens),
+                                    onchange: move |evt| {
+                                        session.write().params.max_tokens = evt.value().trim().parse().ok();
+                                    }
+                                }
+                            }
+                            div { class: "col-span-1",
+                                label {
+                                    r#for: "seed",
+                                    class: "block mb-2 text-sm font-medium text-gray-900 dark:text-white",
+                                    "Seed"
+                                }
+                                input {
+                                    r#type: "number",
+                                    step: "1",
+                                    min: "0",
+                                    placeholder: "default",
+                                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-primary-500 focus:border-primary-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                    id: "seed",
+                                    value: optional_value(params.seed),
+                                    onchange: move |evt| {
+                                        session.write().params.seed = evt.value().trim().parse().ok();
+                                    }
+                                }
+                            }
+                            div { class: "col-span-1",
+                                label {
+                                    r#for: "repeat_penalty",
+                                    class: "block mb-2 text-sm font-medium text-gray-900 dark:text-white",
+                                    "Repeat penalty"
+                                }
+                                input {
+                                    r#type: "number",
+                                    step: "0.1",
+                                    min: "0",
+                                    placeholder: "default",
+                                    class: "bg-gray-50 border border-gray-300 text-gray-900 text-sm rounded-lg focus:ring-primary-500 focus:border-primary-500 block w-full p-2.5 dark:bg-gray-600 dark:border-gray-500 dark:placeholder-gray-400 dark:text-white",
+                                    id: "repeat_penalty",
+                                    value: optional_value(params.repeat_penalty),
+                                    onchange: move |evt| {
+                                        session.write().params.repeat_penalty = evt.value().trim().parse().ok();
+                                    }
+                                }
+                            }
                         }
                         button {
                             "data-modal-toggle": "model-config",
@@ -136,6 +256,20 @@ fn ModelConfig(
     )
 }
 
+// images generated by the workers are served by the authenticated /api/files,
+// which the browser can only reach with the token in the url.
+fn authorize_files(html: String) -> String {
+    let token = get_token();
+    let mut parts = html.split("/api/files/");
+    let mut result = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        let id_len = part.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(part.len());
+        let (id, rest) = part.split_at(id_len);
+        result.push_str(format!("/api/files/{}?token={}{}", id, token, rest).as_str());
+    }
+    result
+}
+
 #[component]
 fn ShowMessage(msg: Message) -> Element {
     use comrak::{markdown_to_html, ExtensionOptions, Options};
@@ -143,13 +277,15 @@ fn ShowMessage(msg: Message) -> Element {
     options.extension.table = true;
     options.extension.math_code = true;
     options.extension.multiline_block_quotes = true;
-    let html = markdown_to_html(msg.content.as_str(), &options);
+    let html = authorize_files(markdown_to_html(msg.content.as_str(), &options));
     rsx!(if msg.role == Role::User {
            div { class: "flex justify-end mb-4",
                 div { class: "bg-blue-500 text-white p-3 rounded-l-lg rounded-br-lg",
                   p { dangerous_inner_html: "{html}" }
                   if let Some(img) = msg.img.clone() {
                      img { class:"rounded-lg", src:"{img}"}
+                  } else if let Some(id) = msg.attachment.clone() {
+                     img { class:"rounded-lg", src:"/api/files/{id}?token={get_token()}"}
                   }
                  }
             }
@@ -161,6 +297,9 @@ fn ShowMessage(msg: Message) -> Element {
                        Pulse {}
                     } else {
                        p { dangerous_inner_html: "{html}" }
+                       if let Some(usage) = msg.usage.clone() {
+                          p { class: "mt-2 text-xs text-gray-500", "{usage_text(&usage)}" }
+                       }
                     }
                 }
             }
@@ -179,24 +318,101 @@ fn ShowMessage(msg: Message) -> Element {
     )
 }
 
+fn usage_text(usage: &Usage) -> String {
+    let finish = match usage.finish_reason {
+        FinishReason::Eos => "completed",
+        FinishReason::Length => "length limit",
+        FinishReason::Stop => "stop sequence",
+        FinishReason::ToolCalls => "tool calls",
+        FinishReason::Cancelled => "cancelled",
+    };
+    format!(
+        "{} prompt + {} completion tokens · {:.1} tokens/s · first token {:.0} ms · {}",
+        usage.prompt_tokens,
+        usage.completion_tokens,
+        usage.tokens_per_second,
+        usage.time_to_first_token,
+        finish
+    )
+}
+
+fn get_token() -> String {
+    match get_user() {
+        Some(user) => match user.auth_key {
+            Some(key) => key,
+            None => "".to_string(),
+        },
+        None => "".to_string(),
+    }
+}
+
+async fn upload_image(url: String, file_name: String, data: Vec<u8>) -> Option<UploadResponse> {
+    use reqwest::multipart::{Form, Part};
+    let ext = file_name.rsplit('.').next().unwrap_or_default().to_lowercase();
+    let content_type = match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        _ => "application/octet-stream",
+    };
+    let part = Part::bytes(data)
+        .file_name(file_name)
+        .mime_str(content_type)
+        .ok()?;
+    let response = reqwest::Client::new()
+        .post(format!("{}uploads", url))
+        .bearer_auth(get_token())
+        .multipart(Form::new().part("file", part))
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    response.json::<UploadResponse>().await.ok()
+}
+
+async fn refresh_models(
+    url: String,
+    model_id: String,
+    mut modelOptions: Signal<Vec<SelectOption>>,
+    mut models: Signal<Vec<ModelInfo>>,
+) {
+    use reqwest::Client;
+    let response = Client::new()
+        .get(format!("{}models", url))
+        .send()
+        .await
+        .unwrap()
+        .json::<Vec<ModelInfo>>()
+        .await
+        .unwrap();
+    let mut options: Vec<SelectOption> = response
+        .iter()
+        .map(|model| SelectOption {
+            text: model.model_id.clone(),
+            value: model.model_id.clone(),
+            selected: model_id == model.model_id,
+        })
+        .collect();
+    modelOptions.write().clear();
+    modelOptions.write().append(&mut options);
+    models.set(response);
+}
+
 fn sendMsg(
     msg: String,
     model_id: String,
     url: String,
     system_prompt: String,
     mut modelOptions: Signal<Vec<SelectOption>>,
+    models: Signal<Vec<ModelInfo>>,
     mut send_disabled: Signal<bool>,
 ) {
     
     if msg != "" {
         use reqwest::Client;
-        let token = match get_user() {
-            Some(user)=> match user.auth_key {
-                Some(key) => key,
-                None => "".to_string(),
-            },
-            None => "".to_string()
-        };
+        let token = get_token();
         let mut history = use_context::<Signal<Vec<Message>>>();
         let id = history().len();
         history.write().push(Message {
@@ -204,7 +420,11 @@ fn sendMsg(
             role: Role::User,
             content: msg.clone(),
             img: None,
+            attachment: None,
             loading: false,
+            usage: None,
+            tool_calls: Vec::new(),
+            tool_call_id: None,
         });
 
         let id = history().len();
@@ -214,7 +434,11 @@ fn sendMsg(
                 role: Role::Administrator,
                 content: String::new(),
                 img: None,
+                attachment: None,
                 loading: true,
+                usage: None,
+                tool_calls: Vec::new(),
+                tool_call_id: None,
             });
             let history_clone = history.read()[..id].to_owned();
             spawn(async move {
@@ -232,25 +456,8 @@ fn sendMsg(
                 let mut message = &mut history.write()[id];
                 message.content.push_str(text.as_str());
                 message.loading = false;
-                
-                let response = Client::new()
-                    .get(format!("{}models", url))
-                    .send()
-                    .await
-                    .unwrap()
-                    .json::<Vec<String>>()
-                    .await
-                    .unwrap();
-                let mut options: Vec<SelectOption> = response
-                    .iter()
-                    .map(|model| SelectOption {
-                        text: model.clone(),
-                        value: model.clone(),
-                        selected: model_id == model.clone(),
-                    })
-                    .collect();
-                modelOptions.write().clear();
-                modelOptions.write().append(&mut options);
+
+                refresh_models(url, model_id, modelOptions, models).await;
                 send_disabled.set(false);
             });
         } else {
@@ -259,9 +466,16 @@ fn sendMsg(
                 role: Role::Robot,
                 content: String::new(),
                 img: None,
+                attachment: None,
                 loading: true,
+                usage: None,
+                tool_calls: Vec::new(),
+                tool_call_id: None,
             });
             let history_clone = history.read()[..id].to_owned();
+            let session = use_context::<Signal<Session>>();
+            let params = session.read().params.clone();
+            let session_id = session.read().id.clone();
 
             spawn(async move {
                 use crate::data::Request;
@@ -274,6 +488,9 @@ fn sendMsg(
                         cmd: model_id.clone(),
                         system_prompt: system_prompt,
                         msg_list: history_clone,
+                        params,
+                        session_id: Some(session_id),
+                        ..Default::default()
                     })
                     .send()
                     .await
@@ -298,6 +515,19 @@ fn sendMsg(
 
                                 break;
                             }
+                            if event.event == "usage" {
+                                message.usage = serde_json::from_str::<Usage>(event.data.as_str()).ok();
+                                continue;
+                            }
+                            if event.event == "logprobs" {
+                                continue;
+                            }
+                            if event.event == "tool_call" {
+                                if let Ok(call) = serde_json::from_str::<ToolCall>(event.data.as_str()) {
+                                    message.tool_calls.push(call);
+                                }
+                                continue;
+                            }
                             message.content.push_str(event.data.as_str());
                         }
                         Err(_) => {
@@ -335,6 +565,7 @@ pub fn switch_session(id: &str, mut model_id: Signal<String>,mut system_prompt:
                 name: new_session.name,
                 mode_id: new_session.mode_id.clone(),
                 system_prompt: new_session.system_prompt.clone(),
+                params: new_session.params.clone(),
                 history: if let Some(ref history) = new_session.history {
                     Some(history.clone())
                 } else {
@@ -350,6 +581,7 @@ pub fn switch_session(id: &str, mut model_id: Signal<String>,mut system_prompt:
                 name: temp_session.read().name.clone(),
                 mode_id: temp_session.read().mode_id.clone(),
                 system_prompt: temp_session.read().system_prompt.clone(),
+                params: temp_session.read().params.clone(),
                 history:  Some(Vec::<Message>::new()),
             };
             messages.set(Vec::<Message>::new());
@@ -363,8 +595,10 @@ pub fn switch_session(id: &str, mut model_id: Signal<String>,mut system_prompt:
 }
 
 #[component]
-pub fn Conversations(mut model_id: Signal<String>, mut system_prompt: Signal<String>, send_disabled: Signal<bool>) -> Element {
+pub fn Conversations(mut model_id: Signal<String>, mut system_prompt: Signal<String>, send_disabled: Signal<bool>, endpoint: Signal<String>, synced: Signal<bool>) -> Element {
     let mut do_delete_conv = use_signal(|| false);
+    // re-render once the sessions stored on the server are merged.
+    let _ = synced();
     let session = use_context::<Signal<Session>>();
     let session_value = session();
     let mut store = Store::new().unwrap();
@@ -381,6 +615,7 @@ pub fn Conversations(mut model_id: Signal<String>, mut system_prompt: Signal<Str
                 name: temp_session.read().name.clone(),
                 mode_id: temp_session.read().mode_id.clone(),
                 system_prompt: temp_session.read().system_prompt.clone(), 
+                params: temp_session.read().params.clone(),
                 history: Some(Vec::<Message>::new()),
             })
         }
@@ -432,6 +667,10 @@ pub fn Conversations(mut model_id: Signal<String>, mut system_prompt: Signal<Str
                                     let mut store = Store::new().unwrap();
                                     if !send_disabled() {
                                         store.remove_session(id_for_delete.clone().as_str());
+                                        let id = id_for_delete.clone();
+                                        spawn(async move {
+                                            let _ = delete_remote_session(endpoint().as_str(), get_token().as_str(), id.as_str()).await;
+                                        });
                                         do_delete_conv.set(true);
                                     }
                                 },
@@ -503,6 +742,17 @@ pub fn app() -> Element {
     let mut new_msg = use_signal(String::new);
     let mut send_disabled = use_signal(|| false);
     let mut modelOptions = use_signal(Vec::<SelectOption>::new);
+    let mut models = use_signal(Vec::<ModelInfo>::new);
+    use_future(move || async move {
+        refresh_models(endpoint(), model_id(), modelOptions, models).await;
+    });
+    let mut synced = use_signal(|| false);
+
+    use_future(move || async move {
+        if sync_sessions(endpoint().as_str(), get_token().as_str()).await.is_ok() {
+            synced.set(true);
+        }
+    });
 
     let mut store = Store::new().unwrap();
     use_context_provider(|| Signal::new(store.new_session()));
@@ -524,6 +774,10 @@ pub fn app() -> Element {
                             sess.history = Some(messages().clone());
                             sess.system_prompt = system_prompt();
                             store.save_session(&sess);
+                            let session = sess.clone();
+                            spawn(async move {
+                                let _ = push_session(endpoint().as_str(), get_token().as_str(), &session).await;
+                            });
                         }
                     }
                 }
@@ -540,6 +794,10 @@ pub fn app() -> Element {
         }
     });
 
+    let supports_vision = models()
+        .iter()
+        .any(|model| model.model_id == model_id() && model.capabilities.contains(&Capability::Vision));
+
     let mut messages = use_context::<Signal<Vec<Message>>>();
     let mut send = move || {
         info!("try send message");
@@ -552,6 +810,7 @@ pub fn app() -> Element {
                 endpoint(),
                 system_prompt(),
                 modelOptions,
+                models,
                 send_disabled,
             );
             new_msg.set(String::new());
@@ -560,7 +819,7 @@ pub fn app() -> Element {
 
     rsx!(
 
-        Conversations { model_id, system_prompt, send_disabled }
+        Conversations { model_id, system_prompt, send_disabled, endpoint, synced }
 
         div { class: "w-4/5 bg-white shadow-lg rounded-lg overflow-hidden flex flex-col",
               style: "height:98%;",
@@ -609,12 +868,20 @@ pub fn app() -> Element {
                             for file_name in files {
                                 if let Some(file) = file_engine.read_file(&file_name).await{
                                     let id = messages().len();
+                                    let (img, attachment) = match upload_image(endpoint(), file_name.clone(), file.clone()).await {
+                                        Some(upload) => (None, Some(upload.id)),
+                                        None => (Some(image_base64_wasm::vec_to_base64(file)), None),
+                                    };
                                     messages.write().push(Message {
                                         id: id,
                                         role: Role::User,
                                         content: String::new(),
-                                        img: Some(image_base64_wasm::vec_to_base64(file)),
+                                        img,
+                                        attachment,
                                         loading: false,
+                                        usage: None,
+                                        tool_calls: Vec::new(),
+                                        tool_call_id: None,
                                     });
                                 }
                             }
@@ -626,11 +893,7 @@ pub fn app() -> Element {
                     class: "cursor-pointer bg-gray-300 p-2 mr-4 rounded-lg hover:bg-gray-400",
                     onclick:move |_| {
                         async move {
-                            use reqwest::Client;
-                            let response = Client::new().get(format!("{}models",endpoint())).send().await.unwrap().json::<Vec<String>>().await.unwrap();
-                            let mut options: Vec<SelectOption> = response.iter().map(|model| SelectOption {text:model.clone(),value:model.clone(),selected:model_id()==model.clone()}).collect();
-                            modelOptions.write().clear();
-                            modelOptions.write().append(&mut options);
+                            refresh_models(endpoint(), model_id(), modelOptions, models).await;
                         }
                     },
                     "data-modal-target":"model-config",
@@ -684,23 +947,25 @@ pub fn app() -> Element {
                     oninput: move |event| new_msg.set(event.value()),
                     onkeyup: move |event| if event.key() == Key::Enter {send();}
                 }
-                label {
-                    r#for: "image-input",
-                    class: "cursor-pointer bg-gray-300 p-2 mr-4 rounded-lg hover:bg-gray-400",
-                    svg {
-                        "aria-hidden": "true",
-                        "fill": "none",
-                        width: "24",
-                        "viewBox": "0 0 24 24",
-                        height: "24",
-                        "xmlns": "http://www.w3.org/2000/svg",
-                        class: "w-6 h-6 text-gray-800 dark:text-white",
-                        path {
-                            "stroke-linejoin": "round",
-                            "stroke": "currentColor",
-                            "d": "m3 16 5-7 6 6.5m6.5 2.5L16 13l-4.286 6M14 10h.01M4 19h16a1 1 0 0 0 1-1V6a1 1 0 0 0-1-1H4a1 1 0 0 0-1 1v12a1 1 0 0 0 1 1Z",
-                            "stroke-width": "2",
-                            "stroke-linecap": "round"
+                if supports_vision {
+                    label {
+                        r#for: "image-input",
+                        class: "cursor-pointer bg-gray-300 p-2 mr-4 rounded-lg hover:bg-gray-400",
+                        svg {
+                            "aria-hidden": "true",
+                            "fill": "none",
+                            width: "24",
+                            "viewBox": "0 0 24 24",
+                            height: "24",
+                            "xmlns": "http://www.w3.org/2000/svg",
+                            class: "w-6 h-6 text-gray-800 dark:text-white",
+                            path {
+                                "stroke-linejoin": "round",
+                                "stroke": "currentColor",
+                                "d": "m3 16 5-7 6 6.5m6.5 2.5L16 13l-4.286 6M14 10h.01M4 19h16a1 1 0 0 0 1-1V6a1 1 0 0 0-1-1H4a1 1 0 0 0-1 1v12a1 1 0 0 0 1 1Z",
+                                "stroke-width": "2",
+                                "stroke-linecap": "round"
+                            }
                         }
                     }
                 }
@@ -1,23 +1,16 @@
-use crate::data::Message;
+use crate::data::{SamplingParams, Session};
 use dioxus::prelude::*;
 use serde::{Deserialize, Serialize};
 use js_sys::Date;
+use reqwest::Client;
 use web_sys::{window, Storage};
 
-#[derive(Props, Clone, PartialEq, Debug, Serialize, Deserialize)]
-pub struct Session {
-    pub id: String,
-    pub name: String,
-    pub mode_id: String,
-    pub system_prompt: String,
-    pub history: Option<Vec<Message>>,
-}
-
 pub struct TempSession {
     pub id: String,
     pub name: String,
     pub mode_id: String,
     pub system_prompt: String,
+    pub params: SamplingParams,
 }
 
 impl TempSession {
@@ -27,6 +20,7 @@ impl TempSession {
             name: session.name.clone(),
             mode_id: session.mode_id.clone(),
             system_prompt: session.system_prompt.clone(),
+            params: session.params.clone(),
         }
     }
 }
@@ -70,6 +64,7 @@ impl Store {
             name: format!("{}/{}/{} {}:{}", time.get_full_year()-2000,time.get_month()+1,time.get_date(),time.get_hours(),time.get_minutes()),
             system_prompt: "".to_string(),
             mode_id: "meta-llama/Meta-Llama-3-8B-Instruct".to_string(),
+            params: SamplingParams::default(),
             history: None,
         };
         session
@@ -142,3 +137,56 @@ impl Store {
         }
     }
 }
+
+/// Upload a session to the server so it can be restored on another machine.
+pub async fn push_session(endpoint: &str, token: &str, session: &Session) -> Result<(), reqwest::Error> {
+    Client::new()
+        .put(format!("{}sessions/{}", endpoint, session.id))
+        .bearer_auth(token)
+        .json(session)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+pub async fn delete_remote_session(endpoint: &str, token: &str, id: &str) -> Result<(), reqwest::Error> {
+    Client::new()
+        .delete(format!("{}sessions/{}", endpoint, id))
+        .bearer_auth(token)
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Merge the sessions stored on the server into local storage, and upload the
+/// local sessions the server does not know about yet.
+pub async fn sync_sessions(endpoint: &str, token: &str) -> Result<(), reqwest::Error> {
+    let client = Client::new();
+    let remote_list = client
+        .get(format!("{}sessions", endpoint))
+        .bearer_auth(token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<Session>>()
+        .await?;
+    let mut store = Store::new().unwrap();
+    for remote in remote_list.iter() {
+        let session = client
+            .get(format!("{}sessions/{}", endpoint, remote.id))
+            .bearer_auth(token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Session>()
+            .await?;
+        store.save_session(&session);
+    }
+    for session in store.fetch_all_session() {
+        if !remote_list.iter().any(|remote| remote.id == session.id) {
+            push_session(endpoint, token, &session).await?;
+        }
+    }
+    Ok(())
+}
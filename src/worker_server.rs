@@ -1,27 +1,229 @@
-
 use crate::data::{Request,Role,Message};
-use crate::model::load;
-use crate::ipc::accept;
+use crate::model::{fit_context, load_embed, Sequence, TextEmbedModel, TextGenModel};
+use crate::registry::{self, LoadOptions};
+use crate::ipc::{accept, ready, IpcMessage, OutputStream, TaggedOutput};
+use crate::tools::{ToolCallOutput, ToolCallParser};
+use ipc_channel::ipc::IpcSender;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::process;
+use std::sync::mpsc;
+
+/// Most sequences decoded at once, later requests wait for a free slot.
+const MAX_BATCH: usize = 8;
+/// Most replies to one request.
+const MAX_CHOICES: usize = MAX_BATCH;
+
+enum Pipeline {
+    Generation(Box<dyn TextGenModel>),
+    Embedding(Box<dyn TextEmbedModel>),
+}
+
+struct Active {
+    id: u64,
+    /// the index of the reply when the request asked for several.
+    choice: Option<usize>,
+    sequence: Box<dyn Sequence>,
+    /// set when the request offers tools.
+    tool_calls: Option<RefCell<ToolCallParser>>,
+}
+
+/// Starts the replies to `req`. Requests answered right away (embeddings,
+/// errors) start none.
+fn admit(pipeline: &mut Pipeline, model_id: &str, sender: &IpcSender<String>, req: Request) -> Vec<Active> {
+    let output = TaggedOutput::new(sender, req.id);
+    match (pipeline, req.cmd.as_str()) {
+        (Pipeline::Embedding(model), "embed") => {
+            match model.embed(&req.input) {
+                Ok(embeddings) => output.write(serde_json::json!(embeddings).to_string()).unwrap(),
+                Err(e) => output.error(format!("Failed to embed: {}", e)).unwrap(),
+            }
+            output.end().unwrap();
+            Vec::new()
+        }
+        (Pipeline::Generation(pipeline), "chat") => {
+            let msg_list: Vec<Message> = req.msg_list.into_iter().filter(|msg|msg.role!=Role::Administrator).collect();
+            let truncation = req.truncation.unwrap_or_default();
+            let n = req.params.n.unwrap_or(1);
+            let sequences = if n == 0 || n > MAX_CHOICES {
+                Err(anyhow::anyhow!("n must be between 1 and {}", MAX_CHOICES))
+            } else {
+                fit_context(
+                    pipeline.as_ref(),
+                    &msg_list,
+                    req.system_prompt.as_str(),
+                    &req.tools,
+                    &req.params,
+                    &truncation,
+                )
+                .and_then(|history| {
+                    pipeline.start_n(history.as_str(), &req.params, req.session_id.as_deref(), n)
+                })
+            };
+            match sequences {
+                Ok(sequences) => sequences
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, sequence)| Active {
+                        id: req.id,
+                        choice: (n > 1).then_some(index),
+                        sequence,
+                        tool_calls: (!req.tools.is_empty()).then(|| RefCell::new(ToolCallParser::new())),
+                    })
+                    .collect(),
+                Err(e) => {
+                    output.error(format!("Failed to generate: {}", e)).unwrap();
+                    output.end().unwrap();
+                    Vec::new()
+                }
+            }
+        }
+        (_, cmd) => {
+            output.error(format!("{} does not support the {} command", model_id, cmd)).unwrap();
+            output.end().unwrap();
+            Vec::new()
+        }
+    }
+}
+
+pub async fn worker_server(ipc_name:String, model_id: String, options: LoadOptions) {
 
-pub async fn worker_server(ipc_name:String, model_id: String, temp: f64, top_p: f64) {
-    
     let (receiver, sender) = accept(ipc_name);
-    
-    let mut pipeline = load(&model_id, temp, top_p).expect("Failed to load model!");
+
+    let pipeline = registry::load(&model_id, &options).and_then(|model| match model {
+        Some(model) => Ok(Some(Pipeline::Generation(model))),
+        None => load_embed(&model_id, &options).map(|model| model.map(Pipeline::Embedding)),
+    });
+    let mut pipeline = match pipeline {
+        Ok(Some(pipeline)) => pipeline,
+        Ok(None) => {
+            println!("{} is not a model this server can load", model_id);
+            process::exit(1);
+        }
+        Err(e) => {
+            println!("Failed to load {}: {}", model_id, e);
+            process::exit(1);
+        }
+    };
+    ready(&sender, &model_id).unwrap();
     println!("model {} server start!", model_id);
+
+    // requests keep arriving while the batch is decoded.
+    let (request_tx, request_rx) = mpsc::channel::<IpcMessage>();
+    std::thread::spawn(move || {
+        while let Ok(message) = receiver.recv() {
+            let message = match IpcMessage::decode(message.as_str()) {
+                Ok(message) => message,
+                Err(e) => {
+                    println!("dropped an unreadable message: {}", e);
+                    continue;
+                }
+            };
+            if request_tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut waiting: VecDeque<Request> = VecDeque::new();
+    let mut active: Vec<Active> = Vec::new();
     loop {
-        let request: String = receiver.recv().expect("Failed to recv!");
-        if let Ok(req) = serde_json::from_str::<Request>(request.as_str()) {
-            if req.cmd.eq("QUIT") {
+        let mut incoming: Vec<IpcMessage> = Vec::new();
+        if active.is_empty() && waiting.is_empty() {
+            incoming.push(request_rx.recv().expect("Failed to recv!"));
+        }
+        incoming.extend(request_rx.try_iter());
+        for message in incoming {
+            match message {
+                IpcMessage::Request(req) if req.cmd.eq("QUIT") => process::exit(0),
+                IpcMessage::Request(req) => waiting.push_back(*req),
+                IpcMessage::Cancel { id } => {
+                    let started = active.iter().any(|seq| seq.id == id);
+                    let queued = waiting.iter().any(|req| req.id == id);
+                    active.retain(|seq| seq.id != id);
+                    waiting.retain(|req| req.id != id);
+                    if started || queued {
+                        println!("request {} cancelled", id);
+                        TaggedOutput::new(&sender, id).end().unwrap();
+                    }
+                }
+                _ => {}
+            }
+        }
+        // a request starts all of its replies at once, so it waits until
+        // there is room for every one of them.
+        while let Some(req) = waiting.front() {
+            let n = req.params.n.unwrap_or(1).clamp(1, MAX_BATCH);
+            if active.len() + n > MAX_BATCH {
                 break;
             }
-            let msg_list: Vec<Message> = req.msg_list.into_iter().filter(|msg|msg.role!=Role::Administrator).collect();
-            let history =
-                pipeline.messages_chat_template(&msg_list, req.system_prompt.as_str());
-            let _ = pipeline.run(&sender,history.as_str(), 1000usize).unwrap();    
+            let req = waiting.pop_front().unwrap();
+            active.extend(admit(&mut pipeline, &model_id, &sender, req));
+        }
+        let model = match &mut pipeline {
+            Pipeline::Generation(model) => model,
+            Pipeline::Embedding(_) => continue,
+        };
+        // the whole batch steps together, so the model can decode it in one forward.
+        let tagged: Vec<TaggedOutput> = active
+            .iter()
+            .map(|seq| TaggedOutput::new(&sender, seq.id).with_choice(seq.choice))
+            .collect();
+        let ids: Vec<u64> = active.iter().map(|seq| seq.id).collect();
+        let mut sequences: Vec<&mut dyn Sequence> = Vec::with_capacity(active.len());
+        let mut parsers: Vec<Option<&RefCell<ToolCallParser>>> = Vec::with_capacity(active.len());
+        for seq in active.iter_mut() {
+            let Active { sequence, tool_calls, .. } = seq;
+            sequences.push(sequence.as_mut());
+            parsers.push(tool_calls.as_ref());
+        }
+        let tool_outputs: Vec<Option<ToolCallOutput>> = parsers
+            .iter()
+            .zip(&tagged)
+            .map(|(parser, output)| parser.map(|parser| ToolCallOutput::new(parser, output)))
+            .collect();
+        let outputs: Vec<&dyn OutputStream> = tool_outputs
+            .iter()
+            .zip(&tagged)
+            .map(|(tool_output, output)| match tool_output {
+                Some(tool_output) => tool_output as &dyn OutputStream,
+                None => output as &dyn OutputStream,
+            })
+            .collect();
+        let steps = model.step_batch(&mut sequences, &outputs);
+        let mut finished: Vec<u64> = Vec::new();
+        let mut keep: Vec<bool> = Vec::with_capacity(steps.len());
+        for (((id, parser), output), step) in ids.into_iter().zip(&parsers).zip(&tagged).zip(steps) {
+            let step = match (step, parser) {
+                (Ok(Some(mut usage)), Some(parser)) => parser
+                    .borrow_mut()
+                    .finish(output, &mut usage)
+                    .map(|_| Some(usage)),
+                (step, _) => step,
+            };
+            match step {
+                Ok(None) => keep.push(true),
+                Ok(Some(usage)) => {
+                    output.usage(&usage).unwrap();
+                    finished.push(id);
+                    keep.push(false);
+                }
+                Err(e) => {
+                    output.error(format!("Failed to generate: {}", e)).unwrap();
+                    finished.push(id);
+                    keep.push(false);
+                }
+            }
+        }
+        let mut keep = keep.into_iter();
+        active.retain(|_| keep.next().unwrap_or(false));
+        // a request ends with the last of its replies.
+        finished.sort();
+        finished.dedup();
+        for id in finished {
+            if !active.iter().any(|seq| seq.id == id) {
+                TaggedOutput::new(&sender, id).end().unwrap();
+            }
         }
     }
-    process::exit(0);
 }
-